@@ -1,7 +1,8 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use kdapp::{
-    episode::{Episode, EpisodeError, PayloadMetadata},
+    episode::{Episode, EpisodeCommand, EpisodeError, EpisodeProjection, PayloadMetadata},
     pki::PubKey,
+    schema::TypeSchema,
 };
 use log::info;
 use std::collections::VecDeque;
@@ -31,7 +32,7 @@ impl std::fmt::Display for TTTError {
 
 impl std::error::Error for TTTError {}
 
-#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, EpisodeCommand, TypeSchema)]
 pub struct TTTMove {
     pub row: usize,
     pub col: usize,
@@ -50,14 +51,14 @@ impl TTTRollback {
     }
 }
 
-#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, TypeSchema)]
 pub struct TTTState {
     pub board: [[Option<PubKey>; 3]; 3],
     pub first_player: PubKey,
     pub status: TTTGameStatus,
 }
 
-#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize, TypeSchema)]
 pub enum TTTGameStatus {
     InProgress(PubKey),
     Winner(PubKey),
@@ -192,8 +193,10 @@ impl Episode for TicTacToe {
     }
 }
 
-impl TicTacToe {
-    pub fn poll(&self) -> TTTState {
+impl EpisodeProjection for TicTacToe {
+    type Public = TTTState;
+
+    fn project(&self) -> TTTState {
         TTTState {
             board: self.board,
             first_player: self.players[0],
@@ -206,7 +209,9 @@ impl TicTacToe {
             },
         }
     }
+}
 
+impl TicTacToe {
     fn check_winner(&self) -> Option<PubKey> {
         let b = &self.board;
         let lines = [
@@ -240,14 +245,26 @@ impl TicTacToe {
 mod tests {
     use super::*;
     use kdapp::{
-        engine::{self, EngineMsg as Msg, EpisodeMessage},
-        pki::{generate_keypair, sign_message, to_message},
+        engine::{self, command_message, EngineMsg as Msg, EpisodeMessage},
+        pki::{generate_keypair, sign_message},
+        schema, shadow,
     };
 
+    #[test]
+    fn test_ttt_schema() {
+        let json = schema::episode_schema::<TicTacToe>();
+        let ttt_move = r#"{"struct":"TTTMove","fields":[{"name":"row","type":"u64"},{"name":"col","type":"u64"}]}"#;
+        assert!(json.starts_with(r#"{"message":{"enum":"EpisodeMessage","variants":[{"name":"NewEpisode","#));
+        assert!(json.contains(&format!(r#"{{"name":"cmd","type":{}}}"#, ttt_move)));
+        assert!(
+            json.contains(r#""public":{"struct":"TTTState","fields":[{"name":"board","type":{"array":{"array":{"option":"pubkey"}"#)
+        );
+    }
+
     #[test]
     fn test_ttt_rollback() {
         let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
-        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let metadata = PayloadMetadata::for_test(0);
         let mut game = TicTacToe::initialize(vec![p1, p2], &metadata);
         let rollback = game.execute(&TTTMove { row: 0, col: 0 }, Some(p1), &metadata).unwrap();
         game.rollback(rollback);
@@ -285,14 +302,14 @@ mod tests {
                 accepting_hash: 1u64.into(),
                 accepting_daa: 0,
                 accepting_time: 0,
-                associated_txs: vec![(2u64.into(), payload)],
+                associated_txs: vec![(2u64.into(), payload, vec![], vec![])],
             })
             .unwrap();
 
         let cmd = TTTMove { row: 0, col: 0 };
-        let msg = to_message(&cmd);
+        let msg = command_message(episode_id, 1, &cmd);
         let sig = sign_message(&s1, &msg);
-        let step = EpisodeMessage::<TicTacToe>::SignedCommand { episode_id, cmd, pubkey: p1, sig };
+        let step = EpisodeMessage::<TicTacToe>::SignedCommand { episode_id, seq: 1, cmd, pubkey: p1, sig };
 
         let payload = borsh::to_vec(&step).unwrap();
         sender
@@ -300,7 +317,7 @@ mod tests {
                 accepting_hash: 3u64.into(),
                 accepting_daa: 1,
                 accepting_time: 1,
-                associated_txs: vec![(4u64.into(), payload)],
+                associated_txs: vec![(4u64.into(), payload, vec![], vec![])],
             })
             .unwrap();
 
@@ -312,11 +329,37 @@ mod tests {
                 accepting_hash: 5u64.into(),
                 accepting_daa: 2,
                 accepting_time: 2,
-                associated_txs: vec![(4u64.into(), payload)],
+                associated_txs: vec![(4u64.into(), payload, vec![], vec![])],
             })
             .unwrap();
 
         sender.send(Msg::Exit).unwrap();
         engine_task.await.unwrap();
     }
+
+    #[test]
+    fn test_ttt_shadow() {
+        let ((s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let episode_id = 11;
+        let new_episode = EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![p1, p2] };
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(episode_id, 1, TTTMove { row: 1, col: 1 }, s1, p1);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for (i, msg) in [&new_episode, &step].into_iter().enumerate() {
+            sender
+                .send(Msg::BlkAccepted {
+                    accepting_hash: (i as u64).into(),
+                    accepting_daa: i as u64,
+                    accepting_time: 0,
+                    associated_txs: vec![((i as u64).into(), borsh::to_vec(msg).unwrap(), vec![], vec![])],
+                })
+                .unwrap();
+        }
+        sender.send(Msg::BlkReverted { accepting_hash: 1u64.into() }).unwrap();
+        sender.send(Msg::Exit).unwrap();
+
+        // An upgrade identical to the current implementation never diverges, through commands and their rollbacks
+        let report = shadow::run_shadow::<TicTacToe, TicTacToe>(receiver, shadow::projection_digest, shadow::projection_digest);
+        assert!(report.is_clean());
+        assert_eq!(report.events_compared, 3);
+    }
 }
@@ -1,11 +1,7 @@
 use clap::Parser;
 use itertools::Itertools;
-use kaspa_addresses::{Address, Prefix, Version};
-use kaspa_consensus_core::{
-    network::{NetworkId, NetworkType},
-    tx::{TransactionOutpoint, UtxoEntry},
-};
-use kaspa_wrpc_client::prelude::*;
+use kaspa_addresses::{Address, Version};
+use kaspa_consensus_core::network::{NetworkId, NetworkType};
 use log::*;
 use rand::Rng;
 use secp256k1::{Keypair, PublicKey, SecretKey};
@@ -19,12 +15,14 @@ use std::{
 };
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
+use kdapp_cli_common::CommonArgs;
+
 use kdapp::{
     engine::{self, EpisodeMessage},
-    episode::{EpisodeEventHandler, EpisodeId},
-    generator::{self, PatternType, PrefixType},
+    episode::{EpisodeEventHandler, EpisodeId, EpisodeProjection},
+    generator::{self, FeePolicy, FeePriority, PatternType, PrefixType, UtxoManager},
     pki::{generate_keypair, PubKey},
-    proxy::{self, connect_client},
+    proxy::{self, connect_client, NodeClient},
 };
 
 use game::{TTTMove, TTTState, TicTacToe};
@@ -34,11 +32,11 @@ pub mod game;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Kaspa schnorr private key
+    /// Kaspa schnorr private key (alternatively use `--keyfile` or `--mnemonic-file`)
     #[arg(short, long)]
     kaspa_private_key: Option<String>,
 
-    /// Game private key
+    /// Game private key (defaults to the identity key derived from `--mnemonic-file`, if specified)
     #[arg(short = 'g', long)]
     game_private_key: Option<String>,
 
@@ -46,40 +44,33 @@ struct Args {
     #[arg(short = 'o', long)]
     game_opponent_key: Option<String>,
 
-    /// Indicates whether to run the interaction over mainnet (default: testnet 10)
+    /// Run the interaction over mainnet. Shorthand for `--network mainnet`, which it overrides
     #[arg(short, long, default_value_t = false)]
     mainnet: bool,
 
-    /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost>. Defaults to the Public Node Network (PNN).
-    #[arg(short, long)]
-    wrpc_url: Option<String>,
-
-    /// Logging level for all subsystems {off, error, warn, info, debug, trace}
-    ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems
-    #[arg(long = "loglevel", default_value = format!("info,{}=trace", env!("CARGO_PKG_NAME")))]
-    log_level: String,
+    #[command(flatten)]
+    common: CommonArgs,
 }
 
 #[tokio::main]
 async fn main() {
     // Get CLI arguments
-    let args = Args::parse();
+    let mut args = kdapp_cli_common::parse_with_config::<Args>(|args| &args.common);
+    if args.mainnet {
+        args.common.network = NetworkId::new(NetworkType::Mainnet);
+    }
 
     // Init logger
-    kaspa_core::log::init_logger(None, &args.log_level);
+    kaspa_core::log::init_logger(None, args.common.log_level_or(&format!("info,{}=trace", env!("CARGO_PKG_NAME"))));
 
     // Select network
-    let (network, prefix) = if args.mainnet {
-        (NetworkId::new(NetworkType::Mainnet), Prefix::Mainnet)
-    } else {
-        (NetworkId::with_suffix(NetworkType::Testnet, 10), Prefix::Testnet)
-    };
+    let (network, prefix) = (args.common.network, args.common.address_prefix());
 
     // Generate or obtain Kaspa private key
     let kaspa_signer = if let Some(private_key_hex) = args.kaspa_private_key {
-        let mut private_key_bytes = [0u8; 32];
-        faster_hex::hex_decode(private_key_hex.as_bytes(), &mut private_key_bytes).unwrap();
-        Keypair::from_seckey_slice(secp256k1::SECP256K1, &private_key_bytes).unwrap()
+        kdapp_cli_common::parse_private_key(&private_key_hex).expect("invalid Kaspa private key")
+    } else if let Some(keypair) = args.common.load_keypair().unwrap() {
+        keypair
     } else {
         let (sk, pk) = &secp256k1::generate_keypair(&mut rand::thread_rng());
         info!(
@@ -98,6 +89,8 @@ async fn main() {
     let (sk, player_pk) = if let Some(game_key_hex) = args.game_private_key {
         let pair = Keypair::from_str(&game_key_hex).unwrap();
         (pair.secret_key(), PubKey(pair.public_key()))
+    } else if let Some(pair) = args.common.load_identity_keypair().unwrap() {
+        (pair.secret_key(), PubKey(pair.public_key()))
     } else {
         let (sk, pk) = generate_keypair();
         info!("Player private key: {}", sk.display_secret());
@@ -109,10 +102,29 @@ async fn main() {
     // ... and opponent pk
     let opponent_pk = args.game_opponent_key.map(|opponent_key_hex| PubKey(PublicKey::from_str(&opponent_key_hex).unwrap()));
 
-    // Connect kaspad clients
-    let kaspad = connect_client(network, args.wrpc_url.clone()).await.unwrap();
-    let player_kaspad = connect_client(network, args.wrpc_url).await.unwrap();
+    // Connect kaspad clients, over gRPC if the node URL selects it
+    #[cfg(feature = "grpc")]
+    if let Some(url) = args.common.grpc_url() {
+        let kaspad = proxy::connect_grpc_client(network, url.to_owned()).await.unwrap();
+        let player_kaspad = proxy::connect_grpc_client(network, url.to_owned()).await.unwrap();
+        run(kaspad, player_kaspad, kaspa_signer, kaspa_addr, sk, player_pk, opponent_pk).await;
+        return;
+    }
+    let kaspad = connect_client(network, args.common.wrpc_url.clone()).await.unwrap();
+    let player_kaspad = connect_client(network, args.common.wrpc_url).await.unwrap();
+    run(kaspad, player_kaspad, kaspa_signer, kaspa_addr, sk, player_pk, opponent_pk).await;
+}
 
+/// Runs the engine and the player over the connected clients until the game is over
+async fn run(
+    kaspad: impl NodeClient,
+    player_kaspad: impl NodeClient + Send + 'static,
+    kaspa_signer: Keypair,
+    kaspa_addr: Address,
+    sk: SecretKey,
+    player_pk: PubKey,
+    opponent_pk: Option<PubKey>,
+) {
     // Define channels and exit flag
     let (sender, receiver) = channel();
     let (response_sender, response_receiver) = tokio::sync::mpsc::unbounded_channel();
@@ -137,10 +149,9 @@ async fn main() {
     player_task.await.unwrap();
 }
 
-// TODO: derive pattern from prefix (using prefix as a random seed for composing the pattern)
-const PATTERN: PatternType = [(7, 0), (32, 1), (45, 0), (99, 1), (113, 0), (126, 1), (189, 0), (200, 1), (211, 0), (250, 1)];
 const PREFIX: PrefixType = 858598618;
-const FEE: u64 = 5000;
+const PATTERN: PatternType = generator::derive_pattern_from_prefix(PREFIX);
+const FALLBACK_FEE: u64 = 5000;
 
 struct TTTHandler {
     sender: UnboundedSender<(EpisodeId, TTTState)>,
@@ -150,7 +161,7 @@ struct TTTHandler {
 impl EpisodeEventHandler<TicTacToe> for TTTHandler {
     fn on_initialize(&self, episode_id: kdapp::episode::EpisodeId, episode: &TicTacToe) {
         if episode.players.contains(&self.player) {
-            let _ = self.sender.send((episode_id, episode.poll()));
+            let _ = self.sender.send((episode_id, episode.project()));
         }
     }
 
@@ -163,7 +174,7 @@ impl EpisodeEventHandler<TicTacToe> for TTTHandler {
         _metadata: &kdapp::episode::PayloadMetadata,
     ) {
         if episode.players.contains(&self.player) {
-            let _ = self.sender.send((episode_id, episode.poll()));
+            let _ = self.sender.send((episode_id, episode.project()));
         }
     }
 
@@ -171,7 +182,7 @@ impl EpisodeEventHandler<TicTacToe> for TTTHandler {
 }
 
 async fn play_ttt(
-    kaspad: KaspaRpcClient,
+    client: impl NodeClient,
     kaspa_signer: Keypair,
     kaspa_addr: Address,
     mut response_receiver: UnboundedReceiver<(EpisodeId, TTTState)>,
@@ -180,13 +191,15 @@ async fn play_ttt(
     player_pk: PubKey,
     opponent_pk: Option<PubKey>,
 ) {
-    let entries = kaspad.get_utxos_by_addresses(vec![kaspa_addr.clone()]).await.unwrap();
-    assert!(!entries.is_empty());
-    // Try to avoid collisions if both players are using the same kaspa address
-    let entry = if opponent_pk.is_some() { entries.first().cloned() } else { entries.last().cloned() };
-    let mut utxo = entry.map(|entry| (TransactionOutpoint::from(entry.outpoint), UtxoEntry::from(entry.utxo_entry))).unwrap();
+    let kaspad = client.api();
+    let utxos = UtxoManager::new(kaspa_addr.clone());
+    utxos.refresh(kaspad).await.unwrap();
+    // Try to avoid collisions if both players are using the same kaspa address. Following txs chain
+    // the outputs of our own previous txs which are always preferred by the manager
+    let mut utxo = if opponent_pk.is_some() { utxos.reserve() } else { utxos.reserve_last() }.expect("no funds in kaspa address");
 
-    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
+    let generator =
+        generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX).with_fee_policy(FeePolicy::Estimated(FeePriority::Normal));
 
     // When opponent pk is passed, we are expected to initiate the game
     if let Some(opponent_pk) = opponent_pk {
@@ -194,10 +207,10 @@ async fn play_ttt(
         // TODO: a complete implementation must handle collisions
         let episode_id = rand::thread_rng().gen();
         let new_episode = EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![player_pk, opponent_pk] };
-        let tx = generator.build_command_transaction(utxo, &kaspa_addr, &new_episode, FEE);
-        info!("Submitting initialize command: {}", tx.id());
-        let _res = kaspad.submit_transaction(tx.as_ref().into(), false).await.unwrap();
-        utxo = generator::get_first_output_utxo(&tx);
+        let fee = generator.command_fee(kaspad, &kaspa_addr, &new_episode).await.unwrap_or(FALLBACK_FEE);
+        let outcome = generator.submit_with_retry(kaspad, &utxos, utxo, &kaspa_addr, &new_episode, fee, Default::default()).await;
+        info!("Submitted initialize command: {}", outcome.tx_id().expect("failed submitting initialize command"));
+        utxo = utxos.reserve().unwrap();
     }
 
     let (episode_id, mut state) = response_receiver.recv().await.unwrap();
@@ -205,6 +218,8 @@ async fn play_ttt(
 
     let mut received_id = episode_id;
     let mut input = String::new();
+    // The sequence number of our signed moves
+    let mut seq = 0;
 
     loop {
         while let game::TTTGameStatus::InProgress(pk) = state.status {
@@ -229,12 +244,13 @@ async fn play_ttt(
         let (row, col) = input.trim().split(',').map(|p| p.trim().parse::<usize>().unwrap()).collect_tuple().unwrap();
 
         let cmd = TTTMove { row, col };
-        let step = EpisodeMessage::<TicTacToe>::new_signed_command(episode_id, cmd, sk, player_pk);
+        seq += 1;
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(episode_id, seq, cmd, sk, player_pk);
 
-        let tx = generator.build_command_transaction(utxo, &kaspa_addr, &step, FEE);
-        info!("Submitting: {}", tx.id());
-        let _res = kaspad.submit_transaction(tx.as_ref().into(), false).await.unwrap();
-        utxo = generator::get_first_output_utxo(&tx);
+        let fee = generator.command_fee(kaspad, &kaspa_addr, &step).await.unwrap_or(FALLBACK_FEE);
+        let outcome = generator.submit_with_retry(kaspad, &utxos, utxo, &kaspa_addr, &step, fee, Default::default()).await;
+        info!("Submitted: {}", outcome.tx_id().expect("failed submitting move"));
+        utxo = utxos.reserve().unwrap();
 
         (received_id, state) = response_receiver.recv().await.unwrap();
 
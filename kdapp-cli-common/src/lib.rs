@@ -0,0 +1,235 @@
+//! Standardized CLI flags and env var conventions shared by kdapp applications.
+//!
+//! Applications flatten [`CommonArgs`] into their own clap parser and call [`parse_with_config`] instead of `Parser::parse`.
+//! Every common flag falls back to a `KDAPP_*` env var, which can in turn be provided by a config file of `KEY=VALUE` lines
+//! passed via `--config` (or `KDAPP_CONFIG`). Explicit flags take precedence over env vars, which take precedence over the config file.
+
+use clap::{Args, Parser};
+use kaspa_addresses::Prefix;
+use kaspa_consensus_core::network::{NetworkId, NetworkType};
+use secp256k1::Keypair;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use wallet::{HdWallet, WalletError};
+
+/// The wallet moved to [`kdapp::wallet`] and is re-exported here for compatibility
+pub use kdapp::wallet;
+pub use kdapp::wallet::parse_private_key;
+
+pub const ENV_NETWORK: &str = "KDAPP_NETWORK";
+pub const ENV_WRPC_URL: &str = "KDAPP_WRPC_URL";
+pub const ENV_KEYFILE: &str = "KDAPP_KEYFILE";
+pub const ENV_MNEMONIC_FILE: &str = "KDAPP_MNEMONIC_FILE";
+pub const ENV_LOGLEVEL: &str = "KDAPP_LOGLEVEL";
+pub const ENV_CONFIG: &str = "KDAPP_CONFIG";
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("failed reading {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("config file {0}, line {1}: expected a KEY=VALUE entry")]
+    InvalidConfigLine(PathBuf, usize),
+
+    #[error("invalid node URL '{0}': expected a host[:port], or a ws://, wss:// or (with the grpc feature) grpc:// URL")]
+    InvalidRpcUrl(String),
+
+    #[error("log level must not be empty")]
+    EmptyLogLevel,
+
+    #[error(transparent)]
+    Wallet(#[from] WalletError),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CommonArgs {
+    /// The network to run on, e.g., `mainnet` or `testnet-10`
+    #[arg(long, env = ENV_NETWORK, default_value = "testnet-10", value_parser = NetworkId::from_str)]
+    pub network: NetworkId,
+
+    /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost> or <localhost:17110> (connected to over ws://).
+    /// Defaults to the Public Node Network (PNN).
+    /// With the `grpc` feature, a gRPC URL (e.g., <grpc://localhost:16110>) connects to the node over gRPC instead
+    #[arg(short, long, env = ENV_WRPC_URL)]
+    pub wrpc_url: Option<String>,
+
+    /// Path to a file holding the hex encoded Kaspa private key
+    #[arg(long, env = ENV_KEYFILE)]
+    pub keyfile: Option<PathBuf>,
+
+    /// Path to a file holding a BIP-39 mnemonic from which both the Kaspa key and the episode identity key are derived.
+    /// The keyfile takes precedence for the Kaspa key if both are specified
+    #[arg(long, env = ENV_MNEMONIC_FILE)]
+    pub mnemonic_file: Option<PathBuf>,
+
+    /// Logging level for all subsystems {off, error, warn, info, debug, trace}
+    ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems.
+    ///  Defaults to a level chosen by the application
+    #[arg(long = "loglevel", env = ENV_LOGLEVEL)]
+    pub log_level: Option<String>,
+
+    /// Path to a config file with `KDAPP_*=<value>` lines providing defaults for the above flags
+    #[arg(long, env = ENV_CONFIG)]
+    pub config: Option<PathBuf>,
+}
+
+impl CommonArgs {
+    /// Validates the flag values which cannot be fully verified by clap itself
+    pub fn validate(&self) -> Result<(), CliError> {
+        if let Some(url) = &self.wrpc_url {
+            // A scheme-less `host[:port]` is completed by the wRPC client to a ws:// URL (at the default port of the network)
+            let valid = url.starts_with("ws://")
+                || url.starts_with("wss://")
+                || !(url.is_empty() || url.contains("://") || url.contains(char::is_whitespace));
+            #[cfg(feature = "grpc")]
+            let valid = valid || kdapp::proxy::is_grpc_url(url);
+            if !valid {
+                return Err(CliError::InvalidRpcUrl(url.clone()));
+            }
+        }
+        if self.log_level.as_ref().is_some_and(|level| level.trim().is_empty()) {
+            return Err(CliError::EmptyLogLevel);
+        }
+        Ok(())
+    }
+
+    /// The node URL if it selects the gRPC client (see [`kdapp::proxy::connect_grpc_client`]), in which case
+    /// [`Self::wrpc_url`] should not be passed to the wRPC client
+    #[cfg(feature = "grpc")]
+    pub fn grpc_url(&self) -> Option<&str> {
+        self.wrpc_url.as_deref().filter(|url| kdapp::proxy::is_grpc_url(url))
+    }
+
+    /// The specified log level, or the application default if none was specified
+    pub fn log_level_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.log_level.as_deref().unwrap_or(default)
+    }
+
+    /// The address prefix matching the selected network
+    pub fn address_prefix(&self) -> Prefix {
+        match self.network.network_type() {
+            NetworkType::Mainnet => Prefix::Mainnet,
+            NetworkType::Testnet => Prefix::Testnet,
+            NetworkType::Devnet => Prefix::Devnet,
+            NetworkType::Simnet => Prefix::Simnet,
+        }
+    }
+
+    /// Loads the Kaspa keypair from the keyfile, or derives it from the mnemonic file, if either was specified
+    pub fn load_keypair(&self) -> Result<Option<Keypair>, CliError> {
+        if let Some(keyfile) = self.keyfile.as_deref() {
+            return read_keyfile(keyfile).map(Some);
+        }
+        Ok(self.load_wallet()?.map(|wallet| wallet.funding_keypair(0, 0)).transpose()?)
+    }
+
+    /// Derives the episode identity keypair from the mnemonic file, if one was specified
+    pub fn load_identity_keypair(&self) -> Result<Option<Keypair>, CliError> {
+        Ok(self.load_wallet()?.map(|wallet| wallet.identity_keypair(0, 0)).transpose()?)
+    }
+
+    /// Restores the wallet from the mnemonic file, if one was specified
+    pub fn load_wallet(&self) -> Result<Option<HdWallet>, CliError> {
+        self.mnemonic_file.as_deref().map(read_mnemonic_file).transpose()
+    }
+}
+
+/// Reads a hex encoded private key from the file at `path`
+pub fn read_keyfile(path: &Path) -> Result<Keypair, CliError> {
+    Ok(wallet::read_keyfile(path)?)
+}
+
+/// Reads a BIP-39 mnemonic phrase from the file at `path` and restores the wallet it backs (without a passphrase)
+pub fn read_mnemonic_file(path: &Path) -> Result<HdWallet, CliError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| CliError::Io(path.to_owned(), err))?;
+    Ok(HdWallet::from_phrase(&contents, "")?)
+}
+
+/// Loads `KEY=VALUE` lines from the config file into the process env. Variables which are already set are left untouched.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn load_config(path: &Path) -> Result<(), CliError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| CliError::Io(path.to_owned(), err))?;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| CliError::InvalidConfigLine(path.to_owned(), index + 1))?;
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() {
+            return Err(CliError::InvalidConfigLine(path.to_owned(), index + 1));
+        }
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Locates the config file path from the command line (`--config <path>` or `--config=<path>`) or from `KDAPP_CONFIG`
+fn find_config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(ENV_CONFIG).map(PathBuf::from)
+}
+
+/// Parses the application args after loading the config file (if any) into the env, and validates the common args.
+/// Exits the process with a usage error on failure, similar to `Parser::parse`.
+pub fn parse_with_config<A: Parser>(common: impl FnOnce(&A) -> &CommonArgs) -> A {
+    if let Some(path) = find_config_path() {
+        if let Err(err) = load_config(&path) {
+            A::command().error(clap::error::ErrorKind::Io, err).exit();
+        }
+    }
+    let args = A::parse();
+    if let Err(err) = common(&args).validate() {
+        A::command().error(clap::error::ErrorKind::ValueValidation, err).exit();
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[derive(Parser, Debug)]
+    struct TestArgs {
+        #[command(flatten)]
+        common: CommonArgs,
+    }
+
+    #[test]
+    fn test_common_args() {
+        TestArgs::command().debug_assert();
+
+        let args = TestArgs::parse_from(["test", "--network", "mainnet", "--wrpc-url", "wss://localhost:17110"]);
+        assert_eq!(args.common.network, NetworkId::new(NetworkType::Mainnet));
+        assert_eq!(args.common.address_prefix(), Prefix::Mainnet);
+        assert_eq!(args.common.log_level_or("info"), "info");
+        assert!(args.common.validate().is_ok());
+
+        let args = TestArgs::parse_from(["test", "--wrpc-url", "localhost:17110"]);
+        assert!(args.common.validate().is_ok());
+        let args = TestArgs::parse_from(["test", "--wrpc-url", "http://localhost:17110"]);
+        assert!(matches!(args.common.validate(), Err(CliError::InvalidRpcUrl(_))));
+
+        // gRPC URLs are accepted only if they can be connected to
+        let args = TestArgs::parse_from(["test", "--wrpc-url", "grpc://localhost:16110"]);
+        #[cfg(feature = "grpc")]
+        {
+            assert!(args.common.validate().is_ok());
+            assert_eq!(args.common.grpc_url(), Some("grpc://localhost:16110"));
+        }
+        #[cfg(not(feature = "grpc"))]
+        assert!(matches!(args.common.validate(), Err(CliError::InvalidRpcUrl(_))));
+    }
+}
@@ -0,0 +1,221 @@
+//! Derive macros for authoring kdapp episodes. These are re-exported by the `kdapp` crate and should be used from there.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, Fields, Lit, Path, Type};
+
+/// Statically checks an episode command type. The derive emits no trait impl, only compile-time checks:
+/// - every field type must implement the Borsh serialization traits;
+/// - a warning is raised if the estimated serialized size of the command (or of any of its variants) exceeds the
+///   payload limit, which is `kdapp::engine::DEFAULT_CHUNK_SIZE` by default. Only fields with a statically known size
+///   are estimated (e.g., `Vec` or `String` fields are not);
+/// - for enums, `#[episode_command(rollback = MyRollback)]` requires the rollback enum to have a variant with the
+///   same name for every command variant.
+///
+/// The payload limit can be overridden with `#[episode_command(max_size = 1000)]`.
+#[proc_macro_derive(EpisodeCommand, attributes(episode_command))]
+pub fn derive_episode_command(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut rollback: Option<Path> = None;
+    let mut max_size: Option<usize> = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("episode_command")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rollback") {
+                rollback = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("max_size") {
+                max_size = Some(meta.value()?.parse::<syn::LitInt>()?.base10_parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `rollback` or `max_size`"))
+            }
+        })?;
+    }
+
+    let ident = &input.ident;
+    // (variant name, fields), where structs are treated as a single unnamed variant
+    let variants: Vec<(Option<&syn::Ident>, &Fields)> = match &input.data {
+        Data::Struct(data) => vec![(None, &data.fields)],
+        Data::Enum(data) => data.variants.iter().map(|v| (Some(&v.ident), &v.fields)).collect(),
+        Data::Union(_) => return Err(syn::Error::new(input.span(), "EpisodeCommand cannot be derived for unions")),
+    };
+    if rollback.is_some() && !matches!(input.data, Data::Enum(_)) {
+        return Err(syn::Error::new(input.span(), "`rollback` can only be checked for command enums"));
+    }
+
+    let field_types: Vec<&Type> = variants.iter().flat_map(|(_, fields)| fields.iter().map(|f| &f.ty)).collect();
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    let borsh_check = quote! {
+        const _: () = {
+            fn assert_borsh<T: ::borsh::BorshSerialize + ::borsh::BorshDeserialize>() {}
+            #[allow(dead_code)]
+            fn check #impl_generics () #where_clause {
+                #( assert_borsh::<#field_types>(); )*
+            }
+        };
+    };
+
+    let mut size_warnings = vec![];
+    for (variant, fields) in variants.iter() {
+        // Enums are prefixed by a single byte variant tag
+        let tag = if variant.is_some() { 1 } else { 0 };
+        let Some(size) = fields.iter().map(|f| fixed_size(&f.ty)).sum::<Option<usize>>().map(|s| s + tag) else {
+            continue;
+        };
+        if max_size.is_some_and(|max_size| size <= max_size) {
+            continue;
+        }
+        // The default limit is defined by kdapp, so it is compared against by the generated code
+        let (limit, limit_note) = match max_size {
+            Some(max_size) => (quote! { #max_size }, format!("the {} bytes payload limit", max_size)),
+            None => (quote! { ::kdapp::engine::DEFAULT_CHUNK_SIZE }, "the default payload limit".to_string()),
+        };
+        let name = variant.map_or_else(|| ident.to_string(), |v| format!("{}::{}", ident, v));
+        let note = format!("serialized size of `{}` is estimated at {} bytes which exceeds {}", name, size, limit_note);
+        // Stable proc macros cannot emit warnings, so a deprecated item is used instead, which is selected only if the
+        // limit is exceeded. The usage is spanned to the offending type or variant since deprecation lints are not
+        // reported for macro generated spans
+        let span = variant.map_or_else(|| ident.span(), |v| v.span());
+        let warning = format_ident!("PAYLOAD_SIZE_EXCEEDED_{}", size_warnings.len(), span = span);
+        let usage = quote_spanned! {span=> PayloadSize::<{ #size > #limit }>::#warning };
+        size_warnings.push(quote! {
+            const _: () = {
+                struct PayloadSize<const EXCEEDED: bool>;
+                #[allow(dead_code)]
+                impl PayloadSize<true> {
+                    #[deprecated(note = #note)]
+                    const #warning: () = ();
+                }
+                #[allow(dead_code)]
+                impl PayloadSize<false> {
+                    const #warning: () = ();
+                }
+                #usage
+            };
+        });
+    }
+
+    let rollback_check = rollback.map(|rollback| {
+        let names: Vec<&syn::Ident> = variants.iter().filter_map(|(variant, _)| *variant).collect();
+        quote! {
+            const _: () = {
+                #[allow(dead_code)]
+                fn check(rollback: &#rollback) {
+                    #( let _ = matches!(rollback, #rollback::#names { .. }); )*
+                }
+            };
+        }
+    });
+
+    Ok(quote! {
+        #borsh_check
+        #( #size_warnings )*
+        #rollback_check
+    })
+}
+
+/// Returns the Borsh serialized size of types with a statically known size, or `None` if it cannot be determined
+fn fixed_size(ty: &Type) -> Option<usize> {
+    match ty {
+        Type::Paren(paren) => fixed_size(&paren.elem),
+        Type::Group(group) => fixed_size(&group.elem),
+        Type::Tuple(tuple) => tuple.elems.iter().map(fixed_size).sum(),
+        Type::Array(array) => {
+            let Expr::Lit(syn::ExprLit { lit: Lit::Int(len), .. }) = &array.len else {
+                return None;
+            };
+            Some(fixed_size(&array.elem)? * len.base10_parse::<usize>().ok()?)
+        }
+        Type::Path(path) if path.qself.is_none() => {
+            let segment = path.path.segments.last()?;
+            match segment.ident.to_string().as_str() {
+                "u8" | "i8" | "bool" => Some(1),
+                "u16" | "i16" => Some(2),
+                "u32" | "i32" | "f32" => Some(4),
+                // Borsh encodes usize and isize as 64 bit integers
+                "u64" | "i64" | "f64" | "usize" | "isize" => Some(8),
+                "u128" | "i128" => Some(16),
+                "PubKey" => Some(33),
+                "Option" => {
+                    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+                        return None;
+                    };
+                    let Some(syn::GenericArgument::Type(inner)) = args.args.first() else {
+                        return None;
+                    };
+                    // The largest encoding, i.e., the `Some` tag followed by the value
+                    Some(1 + fixed_size(inner)?)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Implements `kdapp::schema::TypeSchema`, describing the Borsh layout of the type with its field and variant names.
+/// Every field type must implement `TypeSchema` as well. Recursive types are not supported.
+#[proc_macro_derive(TypeSchema)]
+pub fn derive_type_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_schema(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand_schema(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let name = ident.to_string();
+    let schema = match &input.data {
+        Data::Struct(data) => {
+            let fields = fields_schema(&data.fields);
+            quote! { ::kdapp::schema::Schema::Struct { name: #name.to_string(), fields: #fields } }
+        }
+        Data::Enum(data) => {
+            let names = data.variants.iter().map(|v| v.ident.to_string());
+            let fields = data.variants.iter().map(|v| fields_schema(&v.fields));
+            quote! {
+                ::kdapp::schema::Schema::Enum { name: #name.to_string(), variants: vec![#( (#names.to_string(), #fields) ),*] }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new(input.span(), "TypeSchema cannot be derived for unions")),
+    };
+
+    let field_types: Vec<&Type> = match &input.data {
+        Data::Struct(data) => data.fields.iter().map(|f| &f.ty).collect(),
+        Data::Enum(data) => data.variants.iter().flat_map(|v| v.fields.iter().map(|f| &f.ty)).collect(),
+        Data::Union(_) => unreachable!(),
+    };
+    let mut generics = input.generics.clone();
+    let where_clause = generics.make_where_clause();
+    for ty in field_types {
+        where_clause.predicates.push(syn::parse_quote! { #ty: ::kdapp::schema::TypeSchema });
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kdapp::schema::TypeSchema for #ident #ty_generics #where_clause {
+            fn schema() -> ::kdapp::schema::Schema {
+                #schema
+            }
+        }
+    })
+}
+
+fn fields_schema(fields: &Fields) -> proc_macro2::TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| f.ident.as_ref().unwrap().to_string());
+            let types = named.named.iter().map(|f| &f.ty);
+            quote! {
+                ::kdapp::schema::Fields::Named(vec![#( (#names.to_string(), <#types as ::kdapp::schema::TypeSchema>::schema()) ),*])
+            }
+        }
+        Fields::Unnamed(unnamed) => {
+            let types = unnamed.unnamed.iter().map(|f| &f.ty);
+            quote! { ::kdapp::schema::Fields::Unnamed(vec![#( <#types as ::kdapp::schema::TypeSchema>::schema() ),*]) }
+        }
+        Fields::Unit => quote! { ::kdapp::schema::Fields::Unit },
+    }
+}
@@ -0,0 +1,8 @@
+//! Compile tests of the derive macros. Size warnings are denied by the failing cases so that they are reported as errors
+
+#[test]
+fn test_ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    cases.compile_fail("tests/ui/fail/*.rs");
+}
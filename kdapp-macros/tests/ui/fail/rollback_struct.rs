@@ -0,0 +1,13 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(rollback = MoveRollback)]
+struct Move {
+    row: u8,
+    col: u8,
+}
+
+enum MoveRollback {}
+
+fn main() {}
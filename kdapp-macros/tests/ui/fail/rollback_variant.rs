@@ -0,0 +1,15 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(rollback = MoveRollback)]
+enum Move {
+    Place { row: u8, col: u8 },
+    Resign,
+}
+
+enum MoveRollback {
+    Place { previous: Option<u8> },
+}
+
+fn main() {}
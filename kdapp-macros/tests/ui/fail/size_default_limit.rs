@@ -0,0 +1,12 @@
+#![deny(deprecated)]
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+enum Command {
+    Small(u64),
+    Upload([u8; 25000]),
+}
+
+fn main() {}
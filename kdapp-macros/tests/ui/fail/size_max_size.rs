@@ -0,0 +1,14 @@
+#![deny(deprecated)]
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(max_size = 16)]
+struct Command {
+    from: u64,
+    to: u64,
+    amount: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use kdapp::episode::EpisodeCommand;
+
+#[derive(EpisodeCommand)]
+union Move {
+    row: u8,
+    col: u16,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(limit = 100)]
+struct Move {
+    row: u8,
+    col: u8,
+}
+
+fn main() {}
@@ -0,0 +1,28 @@
+#![deny(deprecated)]
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::episode::EpisodeCommand;
+
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(rollback = MoveRollback)]
+enum Move {
+    Place { row: u8, col: u8 },
+    Resign,
+}
+
+#[allow(dead_code)]
+enum MoveRollback {
+    Place { previous: Option<u8> },
+    Resign,
+}
+
+// Within an overridden limit
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+#[episode_command(max_size = 30000)]
+struct Upload([u8; 25000]);
+
+// Fields of unknown size are not estimated
+#[derive(BorshSerialize, BorshDeserialize, EpisodeCommand)]
+struct Note(Vec<u8>);
+
+fn main() {}
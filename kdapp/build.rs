@@ -0,0 +1,9 @@
+fn main() {
+    // The gRPC service is generated only when enabled, using a vendored protoc so that no system install is required
+    #[cfg(feature = "service")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("vendored protoc"));
+        tonic_build::compile_protos("proto/service.proto").expect("compiling the service protos");
+    }
+    println!("cargo:rerun-if-changed=proto");
+}
@@ -0,0 +1,160 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::prelude::*;
+
+#[derive(Debug, thiserror::Error, BorshSerialize, BorshDeserialize)]
+pub enum CounterError {
+    #[error("unauthorized participant.")]
+    Unauthorized,
+    #[error("counter cannot drop below zero.")]
+    Underflow,
+}
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize, EpisodeCommand)]
+pub enum CounterCommand {
+    Increment(u64),
+    Decrement(u64),
+}
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub struct CounterRollback {
+    pub prev_value: u64,
+}
+
+/// A counter which any of the participants can increment or decrement
+#[derive(Clone, Debug)]
+pub struct Counter {
+    pub participants: Vec<PubKey>,
+    pub value: u64,
+}
+
+impl Episode for Counter {
+    type Command = CounterCommand;
+    type CommandRollback = CounterRollback;
+    type CommandError = CounterError;
+
+    fn initialize(participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+        Self { participants, value: 0 }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: &CounterCommand,
+        authorization: Option<PubKey>,
+        _metadata: &PayloadMetadata,
+    ) -> Result<CounterRollback, EpisodeError<CounterError>> {
+        let Some(player) = authorization else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        if !self.participants.contains(&player) {
+            return Err(EpisodeError::InvalidCommand(CounterError::Unauthorized));
+        }
+        let prev_value = self.value;
+        self.value = match *cmd {
+            CounterCommand::Increment(amount) => self.value.saturating_add(amount),
+            CounterCommand::Decrement(amount) => {
+                self.value.checked_sub(amount).ok_or(EpisodeError::InvalidCommand(CounterError::Underflow))?
+            }
+        };
+        Ok(CounterRollback { prev_value })
+    }
+
+    fn rotate_key(&mut self, old: &PubKey, new: &PubKey) -> bool {
+        match self.participants.iter().position(|pk| pk == old) {
+            Some(index) if !self.participants.contains(new) => {
+                self.participants[index] = *new;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn rollback(&mut self, rollback: CounterRollback) -> bool {
+        self.value = rollback.prev_value;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(block: u64, tx: u64, msg: &EpisodeMessage<Counter>) -> EngineMsg {
+        let associated_txs = vec![(tx.into(), borsh::to_vec(msg).unwrap(), vec![], vec![])];
+        EngineMsg::BlkAccepted { accepting_hash: block.into(), accepting_daa: block, accepting_time: block, associated_txs }
+    }
+
+    #[tokio::test]
+    async fn test_counter_simulation() {
+        let ((sk, pk), (outsider_sk, outsider_pk)) = (generate_keypair(), generate_keypair());
+        let episode_id = 7;
+        let command = |seq, cmd, sk, pk| EpisodeMessage::<Counter>::new_signed_command(episode_id, seq, cmd, sk, pk);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let tracker = EpisodeTracker::<Counter>::new();
+        let engine_tracker = tracker.clone();
+        let engine_task = tokio::task::spawn_blocking(move || Engine::<Counter, _>::new(receiver).start(vec![engine_tracker]));
+
+        sender.send(accepted(1, 1, &EpisodeMessage::NewEpisode { episode_id, participants: vec![pk] })).unwrap();
+        sender.send(accepted(2, 2, &command(1, CounterCommand::Increment(5), sk, pk))).unwrap();
+        // Neither an outsider nor an underflowing decrement affect the counter
+        sender.send(accepted(3, 3, &command(1, CounterCommand::Increment(100), outsider_sk, outsider_pk))).unwrap();
+        sender.send(accepted(4, 4, &command(2, CounterCommand::Decrement(6), sk, pk))).unwrap();
+        sender.send(accepted(5, 5, &command(3, CounterCommand::Decrement(2), sk, pk))).unwrap();
+        assert_eq!(tracker.await_episode(episode_id, |counter| counter.value == 3).await.value, 3);
+
+        // A reorg reverts the decrement
+        sender.send(EngineMsg::BlkReverted { accepting_hash: 5u64.into() }).unwrap();
+        assert_eq!(tracker.await_episode(episode_id, |counter| counter.value == 5).await.value, 5);
+
+        sender.send(EngineMsg::Exit).unwrap();
+        engine_task.await.unwrap();
+    }
+
+    #[test]
+    fn test_counter_reorgs() {
+        let ((sk, pk), (outsider_sk, outsider_pk)) = (generate_keypair(), generate_keypair());
+        let command = |seq, cmd, sk, pk| EpisodeMessage::<Counter>::new_signed_command(7, seq, cmd, sk, pk);
+        let blocks = vec![
+            vec![EpisodeMessage::NewEpisode { episode_id: 7, participants: vec![pk] }],
+            vec![command(1, CounterCommand::Increment(5), sk, pk), command(2, CounterCommand::Decrement(6), sk, pk)],
+            vec![command(1, CounterCommand::Increment(100), outsider_sk, outsider_pk)],
+            vec![command(3, CounterCommand::Decrement(2), sk, pk)],
+        ];
+        kdapp::testing::assert_reorg_consistency(&blocks, 3, |counter| {
+            kdapp::shadow::borsh_digest(&(counter.value, &counter.participants))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_counter_key_rotation() {
+        let ((old_sk, old_pk), (new_sk, new_pk)) = (generate_keypair(), generate_keypair());
+        let episode_id = 7;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let tracker = EpisodeTracker::<Counter>::new();
+        let engine_tracker = tracker.clone();
+        let engine_task = tokio::task::spawn_blocking(move || Engine::<Counter, _>::new(receiver).start(vec![engine_tracker]));
+
+        let rotation = EpisodeMessage::<Counter>::new_key_rotation(episode_id, old_sk, old_pk, new_pk);
+        sender.send(accepted(1, 1, &EpisodeMessage::NewEpisode { episode_id, participants: vec![old_pk] })).unwrap();
+        sender.send(accepted(2, 2, &rotation)).unwrap();
+        // The old key is no longer authorized, while the new one is
+        sender
+            .send(accepted(3, 3, &EpisodeMessage::new_signed_command(episode_id, 1, CounterCommand::Increment(1), old_sk, old_pk)))
+            .unwrap();
+        sender
+            .send(accepted(4, 4, &EpisodeMessage::new_signed_command(episode_id, 1, CounterCommand::Increment(2), new_sk, new_pk)))
+            .unwrap();
+        let counter = tracker.await_episode(episode_id, |counter| counter.value == 2).await;
+        assert_eq!(counter.participants, vec![new_pk]);
+
+        // Reverting the rotation (along with the following command) reinstates the old key
+        sender.send(EngineMsg::BlkReverted { accepting_hash: 4u64.into() }).unwrap();
+        sender.send(EngineMsg::BlkReverted { accepting_hash: 3u64.into() }).unwrap();
+        sender.send(EngineMsg::BlkReverted { accepting_hash: 2u64.into() }).unwrap();
+        let counter = tracker.await_episode(episode_id, |counter| counter.participants == vec![old_pk]).await;
+        assert_eq!(counter.value, 0);
+
+        sender.send(EngineMsg::Exit).unwrap();
+        engine_task.await.unwrap();
+    }
+}
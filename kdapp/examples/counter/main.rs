@@ -0,0 +1,151 @@
+//! A minimal end-to-end kdapp: a counter episode which its participants increment and decrement by submitting signed
+//! commands as Kaspa txs. Serves as a template for new episodes.
+//!
+//! Run with `cargo run -p kdapp --example counter -- --kaspa-private-key <hex>`, optionally passing `--episode-id` and the
+//! identity key of an existing counter in order to join it rather than create a new one.
+
+use clap::Parser;
+use kaspa_addresses::Address;
+use kaspa_wrpc_client::prelude::*;
+use log::*;
+use rand::Rng;
+use secp256k1::Keypair;
+use std::{
+    str::FromStr,
+    sync::{atomic::AtomicBool, mpsc::channel, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use kdapp::{cache::ChainCache, prelude::*, proxy::engine_entry, wallet::keypair_address};
+use kdapp_cli_common::CommonArgs;
+
+use counter::{Counter, CounterCommand};
+
+mod counter;
+
+const PREFIX: PrefixType = 0x636e7472; // "cntr"
+const FALLBACK_FEE: u64 = 5000;
+
+#[derive(Parser, Debug)]
+#[command(about = "Minimal kdapp counter")]
+struct Args {
+    /// Kaspa schnorr private key (alternatively use `--keyfile` or `--mnemonic-file`)
+    #[arg(short, long)]
+    kaspa_private_key: Option<String>,
+
+    /// Identity private key of the counter participant (generated if not specified)
+    #[arg(short = 'i', long)]
+    identity_private_key: Option<String>,
+
+    /// An existing counter episode to join
+    #[arg(short, long)]
+    episode_id: Option<EpisodeId>,
+
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+/// Prints the counter value as it changes
+struct CounterPrinter;
+
+impl EpisodeEventHandler<Counter> for CounterPrinter {
+    fn on_initialize(&self, episode_id: EpisodeId, episode: &Counter) {
+        println!("counter {}: {}", episode_id, episode.value);
+    }
+
+    fn on_command(&self, episode_id: EpisodeId, episode: &Counter, _: &CounterCommand, _: Option<PubKey>, _: &PayloadMetadata) {
+        println!("counter {}: {}", episode_id, episode.value);
+    }
+
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &Counter) {
+        println!("counter {}: {}", episode_id, episode.value);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = kdapp_cli_common::parse_with_config::<Args>(|args| &args.common);
+    env_logger::Builder::new().parse_filters(args.common.log_level_or("info")).init();
+    let (network, prefix) = (args.common.network, args.common.address_prefix());
+
+    let kaspa_signer = match args.kaspa_private_key {
+        Some(private_key_hex) => kdapp_cli_common::parse_private_key(&private_key_hex).expect("invalid Kaspa private key"),
+        None => args.common.load_keypair().unwrap().expect("a Kaspa private key is required"),
+    };
+    let kaspa_addr = keypair_address(&kaspa_signer, prefix);
+    let identity = args.identity_private_key.map(|key_hex| Keypair::from_str(&key_hex).expect("invalid identity private key"));
+    let identity = identity.unwrap_or_else(|| {
+        let pair = Keypair::new(secp256k1::SECP256K1, &mut rand::thread_rng());
+        info!("Identity private key: {}", pair.secret_key().display_secret());
+        pair
+    });
+
+    let kaspad = connect_client(network, args.common.wrpc_url.clone()).await.unwrap();
+    let listener_kaspad = connect_client(network, args.common.wrpc_url).await.unwrap();
+
+    let (sender, receiver) = channel();
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let engine_task = tokio::task::spawn_blocking(move || Engine::<Counter, _>::new(receiver).start(vec![CounterPrinter]));
+    let listener_exit = exit_signal.clone();
+    let listener_task = tokio::spawn(async move {
+        run_listener(listener_kaspad, [engine_entry(PREFIX, sender)].into(), listener_exit).await;
+    });
+
+    run_counter(kaspad, kaspa_signer, kaspa_addr, identity, args.episode_id).await;
+    exit_signal.store(true, std::sync::atomic::Ordering::Relaxed);
+    listener_task.await.unwrap();
+    engine_task.await.unwrap();
+}
+
+/// Submits commands read from stdin (`+N` or `-N`, or an empty line to exit)
+async fn run_counter(
+    kaspad: KaspaRpcClient,
+    kaspa_signer: Keypair,
+    kaspa_addr: Address,
+    identity: Keypair,
+    episode_id: Option<EpisodeId>,
+) {
+    let (sk, pk) = (identity.secret_key(), PubKey(identity.public_key()));
+    let cache = Arc::new(ChainCache::default());
+    let utxos = UtxoManager::new(kaspa_addr.clone()).with_cache(cache.clone());
+    utxos.refresh(&kaspad).await.unwrap();
+    let generator = TransactionGenerator::from_prefix(kaspa_signer, PREFIX).with_cache(cache);
+    let submit = |msg: EpisodeMessage<Counter>| {
+        let (generator, utxos, kaspad, kaspa_addr) = (&generator, &utxos, &kaspad, &kaspa_addr);
+        async move {
+            let fee = generator.command_fee(kaspad, kaspa_addr, &msg).await.unwrap_or(FALLBACK_FEE);
+            let utxo = utxos.reserve().expect("no funds in kaspa address");
+            let outcome = generator.submit_with_retry(kaspad, utxos, utxo, kaspa_addr, &msg, fee, Default::default()).await;
+            info!("Submitted: {:?}", outcome.tx_id());
+        }
+    };
+
+    let episode_id = match episode_id {
+        Some(episode_id) => episode_id,
+        None => {
+            let episode_id = rand::thread_rng().gen();
+            submit(EpisodeMessage::NewEpisode { episode_id, participants: vec![pk] }).await;
+            info!("Created counter episode {}", episode_id);
+            episode_id
+        }
+    };
+
+    let mut input = String::new();
+    loop {
+        input.clear();
+        std::io::stdin().read_line(&mut input).unwrap();
+        let input = input.trim();
+        let cmd = match (input.get(..1), input.get(1..).and_then(|amount| amount.parse().ok())) {
+            (Some("+"), Some(amount)) => CounterCommand::Increment(amount),
+            (Some("-"), Some(amount)) => CounterCommand::Decrement(amount),
+            (None, _) => break,
+            _ => {
+                println!("Expected +N or -N");
+                continue;
+            }
+        };
+        // Sequence numbers may have gaps, so the current time serves as one which also survives restarts
+        let seq = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        submit(EpisodeMessage::new_signed_command(episode_id, seq, cmd, sk, pk)).await;
+    }
+}
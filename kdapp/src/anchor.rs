@@ -0,0 +1,275 @@
+//! State anchoring, allowing participants to audit the episode states reported by an organizer peer. An engine
+//! configured via [`Engine::with_anchoring`](crate::engine::Engine::with_anchoring) periodically commits to the states
+//! of all of its episodes by a Merkle root over their digests, and the organizer publishes each commitment on-chain as
+//! a [`StateAnchor`] tx (see `run_anchor_publisher`). Given the [`MerkleProof`] of an episode, a participant can then
+//! verify that a state reported by the organizer is the one which was anchored, without running the engine itself.
+//!
+//! Anchors are produced as blocks are accepted, so an anchor may commit to a block which is later reverted. Such an
+//! anchor remains verifiable, however it attests to a state which the chain no longer supports.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use kaspa_consensus_core::Hash;
+use sha2::{Digest, Sha256};
+
+use crate::episode::EpisodeId;
+
+/// Domain separation of leaf and inner nodes, so that an inner node can never be presented as a leaf
+const LEAF_DOMAIN: &str = "kdapp/anchor-leaf";
+const NODE_DOMAIN: &str = "kdapp/anchor-node";
+
+/// The payload prefix of anchor txs, distinguishing them from the command txs of any episode
+#[cfg(feature = "rpc")]
+pub const ANCHOR_PREFIX: crate::generator::PrefixType = u32::from_le_bytes(*b"KDAN");
+
+/// A commitment to the states of all episodes run by an engine, as of the accepting block. This is the payload of
+/// anchor txs
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct StateAnchor {
+    pub accepting_hash: Hash,
+    pub accepting_daa: u64,
+    /// The Merkle root over the leaves of the episodes (see [`anchor_leaf`]), ordered by episode id
+    pub root: Hash,
+}
+
+impl StateAnchor {
+    /// Verifies that `digest` is the anchored state digest of the episode
+    pub fn verify(&self, episode_id: EpisodeId, digest: Hash, proof: &MerkleProof) -> bool {
+        proof.root(anchor_leaf(episode_id, digest)) == self.root
+    }
+}
+
+/// An anchor as produced by the engine, along with the episode digests it commits to, from which the organizer
+/// serves proofs to participants
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnchoredStates {
+    pub anchor: StateAnchor,
+    /// The state digest of each episode, ordered by episode id
+    pub digests: Vec<(EpisodeId, Hash)>,
+}
+
+impl AnchoredStates {
+    pub fn new(accepting_hash: Hash, accepting_daa: u64, mut digests: Vec<(EpisodeId, Hash)>) -> Self {
+        digests.sort_unstable_by_key(|&(episode_id, _)| episode_id);
+        let root = merkle_root(&Self::leaves(&digests));
+        Self { anchor: StateAnchor { accepting_hash, accepting_daa, root }, digests }
+    }
+
+    /// The proof of the anchored digest of the episode, if it was anchored
+    pub fn proof(&self, episode_id: EpisodeId) -> Option<MerkleProof> {
+        let index = self.digests.binary_search_by_key(&episode_id, |&(episode_id, _)| episode_id).ok()?;
+        Some(MerkleProof::new(&Self::leaves(&self.digests), index))
+    }
+
+    fn leaves(digests: &[(EpisodeId, Hash)]) -> Vec<Hash> {
+        digests.iter().map(|&(episode_id, digest)| anchor_leaf(episode_id, digest)).collect()
+    }
+}
+
+/// The leaf committing to the state digest of the episode
+pub fn anchor_leaf(episode_id: EpisodeId, digest: Hash) -> Hash {
+    hash(&(LEAF_DOMAIN, episode_id, digest))
+}
+
+fn node(left: Hash, right: Hash) -> Hash {
+    hash(&(NODE_DOMAIN, left, right))
+}
+
+fn hash<T: BorshSerialize>(value: &T) -> Hash {
+    Hash::from_slice(&Sha256::digest(borsh::to_vec(value).unwrap()))
+}
+
+/// The Merkle root of the leaves, which is the default hash if there are none. A node without a sibling is carried up
+/// to the next level as is
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| if let [left, right] = *pair { node(left, right) } else { pair[0] }).collect();
+    }
+    level.first().copied().unwrap_or_default()
+}
+
+/// The siblings along the path from a leaf to the root, each paired with whether it is the left node. Levels at which
+/// the node has no sibling are skipped
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MerkleProof(pub Vec<(Hash, bool)>);
+
+impl MerkleProof {
+    /// The proof of the leaf at `index`
+    pub fn new(leaves: &[Hash], mut index: usize) -> Self {
+        let mut siblings = vec![];
+        let mut level = leaves.to_vec();
+        while level.len() > 1 {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                siblings.push((level[sibling], sibling < index));
+            }
+            level = level.chunks(2).map(|pair| if let [left, right] = *pair { node(left, right) } else { pair[0] }).collect();
+            index /= 2;
+        }
+        Self(siblings)
+    }
+
+    /// The root implied by the proof for the leaf
+    pub fn root(&self, leaf: Hash) -> Hash {
+        self.0.iter().fold(leaf, |acc, &(sibling, is_left)| if is_left { node(sibling, acc) } else { node(acc, sibling) })
+    }
+}
+
+#[cfg(feature = "rpc")]
+mod publisher {
+    use kaspa_consensus_core::tx::TransactionId;
+    use kaspa_rpc_core::{api::rpc::RpcApi, RpcError, RpcResult};
+    use log::{info, warn};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::{AnchoredStates, StateAnchor};
+    use crate::generator::{estimate_compute_mass, TransactionGenerator, UtxoManager, MIN_OUTPUT_AMOUNT};
+
+    /// Submits a tx carrying the anchor, paying back to the managed address. The generator should be created with
+    /// [`ANCHOR_PREFIX`](super::ANCHOR_PREFIX), so that the tx is not mistaken for a command tx
+    pub async fn publish_anchor(
+        kaspad: &impl RpcApi,
+        generator: &TransactionGenerator,
+        utxos: &UtxoManager,
+        anchor: &StateAnchor,
+    ) -> RpcResult<TransactionId> {
+        let utxo = utxos.reserve().ok_or_else(|| RpcError::General(format!("no UTXO of {} to anchor with", utxos.address())))?;
+        let payload = borsh::to_vec(anchor).unwrap();
+        // The mass does not depend on the output value, so it is estimated over a tx paying the whole amount
+        let estimate = generator.build_transaction(std::slice::from_ref(&utxo), utxo.1.amount, 1, utxos.address(), payload.clone());
+        let fee = match generator.fee_policy().fee(kaspad, estimate_compute_mass(&estimate)).await {
+            Ok(fee) if fee.saturating_add(MIN_OUTPUT_AMOUNT) <= utxo.1.amount => fee,
+            Ok(fee) => {
+                utxos.release(utxo);
+                return Err(RpcError::General(format!(
+                    "the UTXO amount cannot cover the anchoring fee of {} and a change output",
+                    fee
+                )));
+            }
+            Err(err) => {
+                utxos.release(utxo);
+                return Err(err);
+            }
+        };
+        let tx = generator.build_transaction(std::slice::from_ref(&utxo), utxo.1.amount - fee, 1, utxos.address(), payload);
+        utxos.submit(kaspad, &tx).await
+    }
+
+    /// Publishes the anchors produced by the engine until it drops the sending side, reporting each published anchor
+    /// along with its tx id to `published` (e.g., for serving proofs to participants). Failed anchors are skipped,
+    /// since the following anchor supersedes them
+    pub async fn run_anchor_publisher(
+        kaspad: &impl RpcApi,
+        generator: &TransactionGenerator,
+        utxos: &UtxoManager,
+        mut anchors: UnboundedReceiver<AnchoredStates>,
+        published: impl Fn(TransactionId, AnchoredStates),
+    ) {
+        while let Some(anchored) = anchors.recv().await {
+            match publish_anchor(kaspad, generator, utxos, &anchored.anchor).await {
+                Ok(tx_id) => {
+                    info!("Anchored {} episodes as of DAA {}: {}", anchored.digests.len(), anchored.anchor.accepting_daa, tx_id);
+                    published(tx_id, anchored);
+                }
+                Err(err) => warn!("Anchoring as of DAA {} failed: {}", anchored.anchor.accepting_daa, err),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+pub use publisher::{publish_anchor, run_anchor_publisher};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, EngineMsg, EpisodeMessage};
+    use crate::episode::{Episode, EpisodeError, PayloadMetadata};
+    use crate::pki::PubKey;
+    use crate::shadow::borsh_digest;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_merkle_proofs() {
+        assert_eq!(merkle_root(&[]), Hash::default());
+        for len in 1..=7u64 {
+            let leaves: Vec<Hash> = (0..len).map(Hash::from).collect();
+            let root = merkle_root(&leaves);
+            for (index, &leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, index);
+                assert_eq!(proof.root(leaf), root);
+                assert_ne!(proof.root(100u64.into()), root);
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Tally(u64);
+
+    impl Episode for Tally {
+        type Command = u64;
+        type CommandRollback = u64;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self(0)
+        }
+
+        fn execute(
+            &mut self,
+            cmd: &u64,
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<u64, EpisodeError<std::fmt::Error>> {
+            self.0 += cmd;
+            Ok(*cmd)
+        }
+
+        fn rollback(&mut self, cmd: u64) -> bool {
+            self.0 -= cmd;
+            true
+        }
+    }
+
+    #[test]
+    fn test_engine_anchoring() {
+        let (sender, receiver) = channel();
+        let (anchor_sender, mut anchors) = tokio::sync::mpsc::unbounded_channel();
+        let digest = |tally: &Tally| borsh_digest(&tally.0);
+        let mut engine = Engine::<Tally>::new(receiver).with_anchoring(10, digest, anchor_sender);
+
+        let accepted = |daa: u64, msgs: &[EpisodeMessage<Tally>]| EngineMsg::BlkAccepted {
+            accepting_hash: daa.into(),
+            accepting_daa: daa,
+            accepting_time: daa,
+            associated_txs: msgs
+                .iter()
+                .enumerate()
+                .map(|(i, msg)| ((daa + i as u64).into(), borsh::to_vec(msg).unwrap(), vec![], vec![]))
+                .collect(),
+        };
+        let new_episode = |episode_id| EpisodeMessage::NewEpisode { episode_id, participants: vec![] };
+        let cmd = |episode_id, cmd| EpisodeMessage::UnsignedCommand { episode_id, cmd };
+        for msg in [
+            accepted(100, &[new_episode(2), new_episode(1)]),
+            accepted(105, &[cmd(1, 3)]),
+            accepted(110, &[cmd(2, 4)]),
+            EngineMsg::Exit,
+        ] {
+            sender.send(msg).unwrap();
+        }
+        engine.start(vec![]);
+
+        let first = anchors.try_recv().unwrap();
+        assert_eq!(first.anchor.accepting_daa, 100);
+        let second = anchors.try_recv().unwrap();
+        assert_eq!((second.anchor.accepting_hash, second.anchor.accepting_daa), (110u64.into(), 110));
+        assert!(anchors.try_recv().is_err());
+
+        assert_eq!(second.digests, vec![(1, borsh_digest(&3u64)), (2, borsh_digest(&4u64))]);
+        let proof = second.proof(2).unwrap();
+        assert!(second.anchor.verify(2, borsh_digest(&4u64), &proof));
+        assert!(!second.anchor.verify(2, borsh_digest(&5u64), &proof));
+        assert!(!first.anchor.verify(2, borsh_digest(&4u64), &first.proof(2).unwrap()));
+    }
+}
@@ -0,0 +1,171 @@
+//! A caching layer for chain data which is queried repeatedly, e.g., the UTXOs of a funding address refreshed before
+//! every command, or fee estimates queried per command. Entries expire after a TTL, and are invalidated early when the
+//! chain advances (see [`ChainCache::invalidate_on`]). A single cache is meant to be shared (via `Arc`) by all users of
+//! a node connection, see [`UtxoManager::with_cache`] and [`TransactionGenerator::with_cache`].
+//!
+//! [`UtxoManager::with_cache`]: crate::generator::UtxoManager::with_cache
+//! [`TransactionGenerator::with_cache`]: crate::generator::TransactionGenerator::with_cache
+
+use kaspa_addresses::Address;
+use kaspa_consensus_core::Hash;
+use kaspa_rpc_core::{api::rpc::RpcApi, GetVirtualChainFromBlockResponse, RpcFeeEstimate, RpcResult, RpcUtxosByAddressesEntry};
+use log::debug;
+use std::collections::HashMap;
+use std::hash::Hash as StdHash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::proxy::ListenerStatus;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    pub utxos_ttl: Duration,
+    pub fee_estimate_ttl: Duration,
+    pub virtual_chain_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            utxos_ttl: Duration::from_secs(2),
+            fee_estimate_ttl: Duration::from_secs(10),
+            virtual_chain_ttl: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A map whose entries expire once their TTL passed
+struct TtlMap<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + StdHash, V: Clone> TtlMap<K, V> {
+    fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: HashMap::new() }
+    }
+
+    fn get(&mut self, key: &K, now: Instant) -> Option<V> {
+        match self.entries.get(key) {
+            Some((inserted, value)) if now.duration_since(*inserted) < self.ttl => Some(value.clone()),
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, now: Instant) {
+        self.entries.insert(key, (now, value));
+    }
+}
+
+pub struct ChainCache {
+    utxos: Mutex<TtlMap<Address, Vec<RpcUtxosByAddressesEntry>>>,
+    fee_estimate: Mutex<TtlMap<(), RpcFeeEstimate>>,
+    virtual_chains: Mutex<TtlMap<Hash, GetVirtualChainFromBlockResponse>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for ChainCache {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl ChainCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            utxos: Mutex::new(TtlMap::new(config.utxos_ttl)),
+            fee_estimate: Mutex::new(TtlMap::new(config.fee_estimate_ttl)),
+            virtual_chains: Mutex::new(TtlMap::new(config.virtual_chain_ttl)),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    /// Returns the cached value of `key` or fetches it. Locks are not held while fetching, so concurrent misses might
+    /// fetch the same entry more than once
+    async fn get_or_fetch<K: Eq + StdHash, V: Clone>(
+        &self,
+        map: &Mutex<TtlMap<K, V>>,
+        key: K,
+        fetch: impl std::future::Future<Output = RpcResult<V>>,
+    ) -> RpcResult<V> {
+        if let Some(value) = map.lock().unwrap().get(&key, Instant::now()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = fetch.await?;
+        map.lock().unwrap().insert(key, value.clone(), Instant::now());
+        Ok(value)
+    }
+
+    pub async fn get_utxos_by_address(&self, kaspad: &impl RpcApi, address: &Address) -> RpcResult<Vec<RpcUtxosByAddressesEntry>> {
+        self.get_or_fetch(&self.utxos, address.clone(), kaspad.get_utxos_by_addresses(vec![address.clone()])).await
+    }
+
+    pub async fn get_fee_estimate(&self, kaspad: &impl RpcApi) -> RpcResult<RpcFeeEstimate> {
+        self.get_or_fetch(&self.fee_estimate, (), kaspad.get_fee_estimate()).await
+    }
+
+    /// The virtual chain from `start_hash`, including accepted tx ids
+    pub async fn get_virtual_chain_from_block(
+        &self,
+        kaspad: &impl RpcApi,
+        start_hash: Hash,
+    ) -> RpcResult<GetVirtualChainFromBlockResponse> {
+        self.get_or_fetch(&self.virtual_chains, start_hash, kaspad.get_virtual_chain_from_block(start_hash, true)).await
+    }
+
+    /// Drops the cached UTXOs of `address`, e.g., after a tx spending from it was submitted
+    pub fn invalidate_address(&self, address: &Address) {
+        self.utxos.lock().unwrap().entries.remove(address);
+    }
+
+    /// Drops all cached chain data which depends on the virtual chain
+    pub fn invalidate_chain(&self) {
+        self.virtual_chains.lock().unwrap().entries.clear();
+        self.utxos.lock().unwrap().entries.clear();
+    }
+
+    /// Invalidates the cached chain data whenever the listener reports chain txs were accepted (which is when the UTXOs
+    /// of episode participants are expected to change) or a reorg. Runs until the listener status sender is dropped.
+    pub async fn invalidate_on(&self, mut status: watch::Receiver<ListenerStatus>) {
+        let mut last = { (status.borrow().last_accepted_block, status.borrow().reorgs_seen) };
+        while status.changed().await.is_ok() {
+            let current = { (status.borrow().last_accepted_block, status.borrow().reorgs_seen) };
+            if current != last {
+                debug!("Chain advanced, invalidating cached chain data");
+                self.invalidate_chain();
+                last = current;
+            }
+        }
+    }
+
+    /// The number of cache hits and misses so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_map() {
+        let mut map = TtlMap::new(Duration::from_secs(2));
+        let now = Instant::now();
+        map.insert(1, "a", now);
+        assert_eq!(map.get(&1, now + Duration::from_secs(1)), Some("a"));
+        assert_eq!(map.get(&1, now + Duration::from_secs(2)), None);
+        assert!(map.entries.is_empty());
+        assert_eq!(map.get(&2, now), None);
+    }
+}
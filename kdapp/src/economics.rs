@@ -0,0 +1,269 @@
+//! Value attached to episode commands. Participants attach value to a command by paying an additional output of the
+//! command tx to the escrow address of the episode (see `TransactionGenerator::build_command_transaction_with_outputs`),
+//! which the engine reports through [`PayloadMetadata::tx_outputs`]. An [`Escrow`], kept as part of the episode state,
+//! credits such deposits to participant accounts and tracks the bonds and balances of each participant.
+//!
+//! The escrow address is controlled by the episode organizer. Withdrawals queue [`Payout`]s, which the organizer turns
+//! into real Kaspa txs by paying them as the outputs of a command tx (see [`payout_outputs`]). The episode settles the
+//! pending payouts once such a tx is accepted, so participants can verify that the organizer paid them out.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use kaspa_addresses::Address;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::episode::PayloadMetadata;
+use crate::pki::PubKey;
+
+/// The funds of a participant held by the escrow, in sompi
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Account {
+    /// Funds available for bonding, transfers and withdrawal
+    pub balance: u64,
+    /// Funds locked as a bond, which can be released back to the balance or slashed
+    pub bonded: u64,
+}
+
+/// An instruction to pay `amount` sompi from the escrow to `address`
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Payout {
+    pub address: Address,
+    pub amount: u64,
+}
+
+/// The outputs paying the payouts, e.g., for building the settlement tx via
+/// `TransactionGenerator::build_command_transaction_with_outputs`
+pub fn payout_outputs(payouts: &[Payout]) -> Vec<(Address, u64)> {
+    payouts.iter().map(|payout| (payout.address.clone(), payout.amount)).collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum EconomicsError {
+    #[error("participant {participant} has {available} available while {required} are required.")]
+    InsufficientBalance { participant: PubKey, available: u64, required: u64 },
+
+    #[error("participant {participant} has {bonded} bonded while {required} are required.")]
+    InsufficientBond { participant: PubKey, bonded: u64, required: u64 },
+
+    #[error("the tx does not pay the pending payouts.")]
+    PayoutsNotPaid,
+
+    #[error("the amount exceeds the funds the escrow can hold.")]
+    Overflow,
+}
+
+/// Restores the escrow state preceding an operation (see [`Escrow::rollback`])
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct EscrowRollback {
+    accounts: Vec<(PubKey, Account)>,
+    pending: Option<Vec<Payout>>,
+}
+
+/// The funds held by an episode on behalf of its participants. All operations either succeed and return the
+/// rollback restoring the previous state, or fail leaving the state unchanged
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Escrow {
+    address: Address,
+    accounts: BTreeMap<PubKey, Account>,
+    pending: Vec<Payout>,
+}
+
+impl Escrow {
+    pub fn new(address: Address) -> Self {
+        Self { address, accounts: BTreeMap::new(), pending: vec![] }
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn account(&self, participant: &PubKey) -> Account {
+        self.accounts.get(participant).copied().unwrap_or_default()
+    }
+
+    /// Payouts awaiting settlement by the organizer, in withdrawal order
+    pub fn pending_payouts(&self) -> &[Payout] {
+        &self.pending
+    }
+
+    /// The overall funds held by the escrow, including pending payouts. Deposits which would take it beyond `u64::MAX`
+    /// are rejected, and all other operations only move funds or pay them out, so no account or payout can overflow
+    pub fn total(&self) -> u64 {
+        let accounts = self.accounts.values().flat_map(|account| [account.balance, account.bonded]);
+        accounts.chain(self.pending.iter().map(|payout| payout.amount)).fold(0, u64::saturating_add)
+    }
+
+    /// The value the tx attaches to its command, i.e., the sum of its outputs paying the escrow address
+    pub fn attached_value(&self, metadata: &PayloadMetadata) -> Result<u64, EconomicsError> {
+        metadata
+            .tx_outputs
+            .iter()
+            .filter(|output| output.address.as_ref() == Some(&self.address))
+            .try_fold(0u64, |sum, output| sum.checked_add(output.amount))
+            .ok_or(EconomicsError::Overflow)
+    }
+
+    /// Credits the value attached to the command to the balance of the participant
+    pub fn deposit(&mut self, participant: PubKey, metadata: &PayloadMetadata) -> Result<EscrowRollback, EconomicsError> {
+        let amount = self.attached_value(metadata)?;
+        self.total().checked_add(amount).ok_or(EconomicsError::Overflow)?;
+        Ok(self.update(&[participant], None, |escrow| escrow.entry(participant).balance += amount))
+    }
+
+    /// Locks `amount` of the balance of the participant as a bond
+    pub fn bond(&mut self, participant: PubKey, amount: u64) -> Result<EscrowRollback, EconomicsError> {
+        self.debit(participant, amount)?;
+        Ok(self.update(&[participant], None, |escrow| {
+            let account = escrow.entry(participant);
+            account.balance -= amount;
+            account.bonded += amount;
+        }))
+    }
+
+    /// Releases `amount` of the bond of the participant back to its balance
+    pub fn release(&mut self, participant: PubKey, amount: u64) -> Result<EscrowRollback, EconomicsError> {
+        self.debit_bond(participant, amount)?;
+        Ok(self.update(&[participant], None, |escrow| {
+            let account = escrow.entry(participant);
+            account.bonded -= amount;
+            account.balance += amount;
+        }))
+    }
+
+    /// Moves `amount` of the bond of the offender to the balance of the beneficiary
+    pub fn slash(&mut self, offender: PubKey, beneficiary: PubKey, amount: u64) -> Result<EscrowRollback, EconomicsError> {
+        self.debit_bond(offender, amount)?;
+        Ok(self.update(&[offender, beneficiary], None, |escrow| {
+            escrow.entry(offender).bonded -= amount;
+            escrow.entry(beneficiary).balance += amount;
+        }))
+    }
+
+    /// Moves `amount` between balances, e.g., paying a pot to the winner of a game
+    pub fn transfer(&mut self, from: PubKey, to: PubKey, amount: u64) -> Result<EscrowRollback, EconomicsError> {
+        self.debit(from, amount)?;
+        Ok(self.update(&[from, to], None, |escrow| {
+            escrow.entry(from).balance -= amount;
+            escrow.entry(to).balance += amount;
+        }))
+    }
+
+    /// Debits `amount` from the balance of the participant and queues its payout to `address`
+    pub fn withdraw(&mut self, participant: PubKey, address: Address, amount: u64) -> Result<EscrowRollback, EconomicsError> {
+        self.debit(participant, amount)?;
+        let mut pending = self.pending.clone();
+        pending.push(Payout { address, amount });
+        Ok(self.update(&[participant], Some(pending), |escrow| escrow.entry(participant).balance -= amount))
+    }
+
+    /// Settles all pending payouts, given that the tx pays each of them as a distinct output (in any order). The
+    /// episode should only accept settlements from the organizer controlling the escrow address
+    pub fn settle(&mut self, metadata: &PayloadMetadata) -> Result<EscrowRollback, EconomicsError> {
+        let mut outputs: Vec<_> = metadata.tx_outputs.iter().map(Some).collect();
+        for payout in self.pending.iter() {
+            let paying = outputs.iter_mut().find(|output| {
+                output.is_some_and(|output| output.amount == payout.amount && output.address.as_ref() == Some(&payout.address))
+            });
+            match paying {
+                Some(output) => *output = None,
+                None => return Err(EconomicsError::PayoutsNotPaid),
+            }
+        }
+        Ok(self.update(&[], Some(vec![]), |_| {}))
+    }
+
+    pub fn rollback(&mut self, rollback: EscrowRollback) {
+        // Restores in reverse order, so that the first recorded state prevails for accounts recorded more than once
+        for (participant, account) in rollback.accounts.into_iter().rev() {
+            self.accounts.insert(participant, account);
+        }
+        if let Some(pending) = rollback.pending {
+            self.pending = pending;
+        }
+    }
+
+    fn debit(&self, participant: PubKey, required: u64) -> Result<(), EconomicsError> {
+        let available = self.account(&participant).balance;
+        match available >= required {
+            true => Ok(()),
+            false => Err(EconomicsError::InsufficientBalance { participant, available, required }),
+        }
+    }
+
+    fn debit_bond(&self, participant: PubKey, required: u64) -> Result<(), EconomicsError> {
+        let bonded = self.account(&participant).bonded;
+        match bonded >= required {
+            true => Ok(()),
+            false => Err(EconomicsError::InsufficientBond { participant, bonded, required }),
+        }
+    }
+
+    fn entry(&mut self, participant: PubKey) -> &mut Account {
+        self.accounts.entry(participant).or_default()
+    }
+
+    /// Applies `f`, which may only modify the accounts of the participants, and replaces the pending payouts if provided
+    fn update(&mut self, participants: &[PubKey], pending: Option<Vec<Payout>>, f: impl FnOnce(&mut Self)) -> EscrowRollback {
+        let prev = participants.iter().map(|participant| (*participant, self.account(participant))).collect();
+        f(self);
+        let pending = pending.map(|pending| std::mem::replace(&mut self.pending, pending));
+        EscrowRollback { accounts: prev, pending }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::episode::TxOutput;
+    use crate::pki::generate_keypair;
+    use kaspa_addresses::{Prefix, Version};
+
+    fn address(id: u8) -> Address {
+        Address::new(Prefix::Testnet, Version::PubKey, &[id; 32])
+    }
+
+    fn metadata(outputs: &[(Address, u64)]) -> PayloadMetadata {
+        let tx_outputs =
+            outputs.iter().map(|(address, amount)| TxOutput { address: Some(address.clone()), amount: *amount }).collect();
+        PayloadMetadata { tx_outputs, ..PayloadMetadata::for_test(0) }
+    }
+
+    #[test]
+    fn test_escrow() {
+        let (_, alice) = generate_keypair();
+        let (_, bob) = generate_keypair();
+        let mut escrow = Escrow::new(address(0));
+
+        // Only the outputs paying the escrow address are credited
+        let mut rollbacks = vec![escrow.deposit(alice, &metadata(&[(address(1), 500), (address(0), 100), (address(0), 50)])).unwrap()];
+        escrow.deposit(bob, &metadata(&[(address(2), 500), (address(0), 100)])).unwrap();
+        assert_eq!(escrow.account(&alice), Account { balance: 150, bonded: 0 });
+        let before = escrow.clone();
+
+        rollbacks.push(escrow.bond(alice, 100).unwrap());
+        assert!(matches!(escrow.bond(alice, 100), Err(EconomicsError::InsufficientBalance { available: 50, .. })));
+        rollbacks.push(escrow.slash(alice, bob, 60).unwrap());
+        rollbacks.push(escrow.release(alice, 40).unwrap());
+        assert_eq!(escrow.account(&alice), Account { balance: 90, bonded: 0 });
+        assert_eq!(escrow.account(&bob), Account { balance: 160, bonded: 0 });
+        rollbacks.push(escrow.transfer(bob, alice, 10).unwrap());
+
+        rollbacks.push(escrow.withdraw(alice, address(1), 100).unwrap());
+        rollbacks.push(escrow.withdraw(bob, address(2), 50).unwrap());
+        assert_eq!(escrow.total(), 250);
+        assert_eq!(escrow.settle(&metadata(&[(address(1), 100)])), Err(EconomicsError::PayoutsNotPaid));
+        rollbacks.push(escrow.settle(&metadata(&[(address(9), 1), (address(2), 50), (address(1), 100)])).unwrap());
+        assert!(escrow.pending_payouts().is_empty());
+        assert_eq!(escrow.total(), 100);
+
+        // Deposits overflowing the escrow funds are rejected
+        assert_eq!(escrow.deposit(bob, &metadata(&[(address(0), u64::MAX), (address(0), 1)])), Err(EconomicsError::Overflow));
+        assert_eq!(escrow.deposit(bob, &metadata(&[(address(0), u64::MAX - 50)])), Err(EconomicsError::Overflow));
+        assert_eq!(escrow.total(), 100);
+
+        rollbacks.drain(1..).rev().for_each(|rollback| escrow.rollback(rollback));
+        assert_eq!(escrow, before);
+        escrow.rollback(rollbacks.pop().unwrap());
+        assert_eq!(escrow.account(&alice), Account::default());
+    }
+}
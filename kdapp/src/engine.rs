@@ -4,26 +4,75 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use kaspa_consensus_core::Hash;
 use log::*;
-use secp256k1::SecretKey;
+use secp256k1::{Message, SecretKey};
+use sha2::{Digest, Sha256};
 
-use crate::episode::{Episode, EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata};
-use crate::pki::{sign_message, to_message, verify_signature, PubKey, Sig};
+use crate::anchor::AnchoredStates;
+use crate::episode::{Episode, EpisodeError, EpisodeEventHandler, EpisodeId, MultisigPolicy, PayloadMetadata, TxOutput};
+use crate::pki::musig::aggregate_keys;
+use crate::pki::{sign_message_with, to_message, verify_signature, MultiSig, PubKey, Sig, SigScheme};
+use crate::schema::TypeSchema;
+use crate::scratch::{ScratchRollback, ScratchStore};
 use std::any::type_name;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::mpsc::Receiver;
+use tokio::sync::mpsc::UnboundedSender;
 
-const EPISODE_LIFETIME: u64 = 2592000; // Three days
-const SAMPLE_REMOVAL_TIME: u64 = 432000; // Half a day
+pub(crate) const EPISODE_LIFETIME: u64 = 2592000; // Three days
+pub(crate) const SAMPLE_REMOVAL_TIME: u64 = 432000; // Half a day
+const CHUNK_ASSEMBLY_TIMEOUT: u64 = 6000; // Ten minutes
+
+/// The maximal number of chunks of a chunked message
+pub const MAX_CHUNKS: u16 = 256;
+
+/// The maximal length of a chunked message, bounding the memory held by the assembly of a single message
+pub const MAX_CHUNKED_MESSAGE_LEN: usize = 4 << 20;
+
+/// The default maximal payload size of a single chunk tx, chosen to keep chunk txs well within standard mass limits.
+/// Also the default payload limit checked by [`EpisodeCommand`](crate::episode::EpisodeCommand)
+pub const DEFAULT_CHUNK_SIZE: usize = 20_000;
+
+/// An episode tx accepted by a block: its id, its payload, its outputs and the ids of the txs whose outputs it spends
+pub type AssociatedTx = (Hash, Vec<u8>, Vec<TxOutput>, Vec<Hash>);
+
+/// The chunks received so far for a chunked message. The chunk txs form a chain, each spending an output of the tx which
+/// carried the previous chunk, so only the author of the first chunk can extend the chain. Chunks of other authors
+/// claiming the same message start chains of their own, which cannot interfere with it
+struct ChunkAssembly {
+    episode_id: EpisodeId,
+    message_id: Hash,
+    total: u16,
+    /// The received parts in order, each along with the tx which carried it and the accepting block of the tx
+    parts: Vec<(Vec<u8>, Hash, Hash)>,
+    first_seen_daa: u64,
+}
+
+/// An entry of the episode rollback stack. A `Command` entry holds the rollback data of an executed command along with
+/// the undo journal of its scratch store mutations, and for signed commands the previous sequence numbers of the signers.
+/// A `DaaTick` entry additionally holds the DAA score of the previous tick
+pub(crate) enum Rollback<G: Episode> {
+    Command(G::CommandRollback, ScratchRollback, Vec<(PubKey, Option<u64>)>),
+    KeyRotation { old: PubKey, new: PubKey },
+    DaaTick { rollback: Option<G::CommandRollback>, scratch_rollback: ScratchRollback, prev_tick_daa: u64 },
+}
 
 pub(crate) struct EpisodeWrapper<G: Episode> {
     pub episode: G,
-    pub rollback_stack: Vec<G::CommandRollback>,
+    pub rollback_stack: Vec<Rollback<G>>,
+    /// Keys which were rotated out of the episode. These can never be rotated back in, which prevents replaying
+    /// previous rotations
+    pub retired_keys: Vec<PubKey>,
+    /// The sequence number of the last signed command executed for each key
+    pub sequences: HashMap<PubKey, u64>,
+    /// The DAA score of the last tick, or of the episode creation if it was not ticked yet
+    pub last_tick_daa: u64,
 }
 
-#[derive(Default)]
+/// An event handler ignoring all events, including the confirmation progress (in DAA depth) of accepted episode txs
+#[derive(Clone, Copy, Default)]
 pub struct DefaultEventHandler;
 
 impl<G: Episode> EpisodeEventHandler<G> for DefaultEventHandler {
@@ -40,6 +89,10 @@ impl<G: Episode> EpisodeEventHandler<G> for DefaultEventHandler {
     }
 
     fn on_rollback(&self, _episode_id: EpisodeId, _episode: &G) {}
+
+    fn on_confirmation(&self, _episode_id: EpisodeId, _tx_id: Hash, _daa_depth: u64) {}
+
+    fn on_finalized(&self, _episode_id: EpisodeId, _episode: &G, _metadata: &PayloadMetadata) {}
 }
 
 /// The main entry point for running episodes of a given Episode type.
@@ -49,23 +102,68 @@ pub struct Engine<G: Episode, P: EpisodeEventHandler<G> = DefaultEventHandler> {
     pub(crate) receiver: Receiver<EngineMsg>,
     pub(crate) next_filtering: u64,
     pub(crate) episode_creation_times: HashMap<EpisodeId, u64>,
+    /// The assemblies of chunked messages, keyed by the tx which carried the last received chunk
+    chunk_assemblies: HashMap<Hash, ChunkAssembly>,
+    /// The episodes ticked by each accepting block, which are rolled back when it is reverted
+    tick_reverts: HashMap<Hash, Vec<EpisodeId>>,
+    anchoring: Option<Anchoring<G>>,
 
     _phantom: PhantomData<P>,
 }
 
-#[derive(Debug, BorshSerialize, BorshDeserialize)]
+/// Periodic anchoring of episode states (see [`Engine::with_anchoring`])
+struct Anchoring<G: Episode> {
+    interval: u64,
+    digest: fn(&G) -> Hash,
+    sender: UnboundedSender<AnchoredStates>,
+    next_daa: u64,
+}
+
+/// Messages carried by tx payloads. A `SignedCommand` signs the episode id and a sequence number along with the command
+/// (see [`command_message`]), where the sequence number must exceed that of the previous command signed by the same
+/// key, so that it can neither be replayed within the episode nor in other episodes. A `Chunk` is a part of a message
+/// too large for a single tx, where `message_id` is the SHA-256 digest of the complete serialized message, and the tx
+/// carrying each chunk but the first must spend an output of the tx carrying the previous one.
+///
+/// A `MultiSignedCommand` is signed by several keys and is authorized by the multisig policy the episode declares for
+/// the command. An `AggregateSignedCommand` is authorized by the same policy, but carries a single MuSig2 signature
+/// (see [`crate::pki::musig`]) verified against the aggregated key of `signers` in their listed order. Both are bound
+/// like a `SignedCommand`, where the sequence number must exceed those of all signers, and sequence numbers are shared
+/// across command kinds.
+///
+/// A `RotateKey` replaces the participant key `old_pubkey` by `new_pubkey` (see [`Episode::rotate_key`]), and is signed
+/// by the old key.
+#[derive(Debug, BorshSerialize, BorshDeserialize, TypeSchema)]
 pub enum EpisodeMessage<G: Episode> {
     NewEpisode { episode_id: EpisodeId, participants: Vec<PubKey> },
-    SignedCommand { episode_id: EpisodeId, cmd: G::Command, pubkey: PubKey, sig: Sig },
+    SignedCommand { episode_id: EpisodeId, seq: u64, cmd: G::Command, pubkey: PubKey, sig: Sig },
     UnsignedCommand { episode_id: EpisodeId, cmd: G::Command },
     Revert { episode_id: EpisodeId },
+    Chunk { episode_id: EpisodeId, message_id: Hash, idx: u16, total: u16, data: Vec<u8> },
+    MultiSignedCommand { episode_id: EpisodeId, seq: u64, cmd: G::Command, sigs: MultiSig },
+    AggregateSignedCommand { episode_id: EpisodeId, seq: u64, cmd: G::Command, signers: Vec<PubKey>, sig: Sig },
+    RotateKey { episode_id: EpisodeId, old_pubkey: PubKey, new_pubkey: PubKey, sig: Sig },
 }
 
 impl<G: Episode> EpisodeMessage<G> {
-    pub fn new_signed_command(episode_id: EpisodeId, cmd: G::Command, sk: SecretKey, pk: PubKey) -> Self {
-        let msg = to_message(&cmd);
-        let sig = sign_message(&sk, &msg);
-        Self::SignedCommand { episode_id, cmd, pubkey: pk, sig }
+    /// Creates a command signed by `pk`, where `seq` must exceed the sequence number of the previous command the key
+    /// signed in this episode. Gaps are allowed, so a participant may, e.g., count the commands it submitted
+    pub fn new_signed_command(episode_id: EpisodeId, seq: u64, cmd: G::Command, sk: SecretKey, pk: PubKey) -> Self {
+        Self::new_signed_command_with(SigScheme::Ecdsa, episode_id, seq, cmd, sk, pk)
+    }
+
+    /// Creates a signed command using the given signature scheme. Schnorr allows using the same keypair which
+    /// controls the kaspa address (see [`PubKey::to_address`])
+    pub fn new_signed_command_with(
+        scheme: SigScheme,
+        episode_id: EpisodeId,
+        seq: u64,
+        cmd: G::Command,
+        sk: SecretKey,
+        pk: PubKey,
+    ) -> Self {
+        let sig = sign_message_with(scheme, &sk, &command_message(episode_id, seq, &cmd));
+        Self::SignedCommand { episode_id, seq, cmd, pubkey: pk, sig }
     }
 
     pub fn episode_id(&self) -> EpisodeId {
@@ -74,48 +172,281 @@ impl<G: Episode> EpisodeMessage<G> {
             EpisodeMessage::SignedCommand { episode_id, .. } => *episode_id,
             EpisodeMessage::UnsignedCommand { episode_id, .. } => *episode_id,
             EpisodeMessage::Revert { episode_id } => *episode_id,
+            EpisodeMessage::Chunk { episode_id, .. } => *episode_id,
+            EpisodeMessage::MultiSignedCommand { episode_id, .. } => *episode_id,
+            EpisodeMessage::AggregateSignedCommand { episode_id, .. } => *episode_id,
+            EpisodeMessage::RotateKey { episode_id, .. } => *episode_id,
         }
     }
+
+    /// Creates a key rotation of the participant key `old_pk` to `new_pk`, signed by the old key
+    pub fn new_key_rotation(episode_id: EpisodeId, old_sk: SecretKey, old_pk: PubKey, new_pk: PubKey) -> Self {
+        let sig = sign_message_with(SigScheme::Ecdsa, &old_sk, &key_rotation_message(episode_id, &new_pk));
+        Self::RotateKey { episode_id, old_pubkey: old_pk, new_pubkey: new_pk, sig }
+    }
+
+    /// Creates a multi-signed command from signatures collected from the signers, each signing
+    /// [`command_message(episode_id, seq, &cmd)`](command_message). `seq` must exceed the sequence numbers of the previous
+    /// commands signed by each of the signers, which are shared with their single-signed commands
+    pub fn new_multisigned_command(episode_id: EpisodeId, seq: u64, cmd: G::Command, sigs: Vec<(PubKey, Sig)>) -> Self {
+        Self::MultiSignedCommand { episode_id, seq, cmd, sigs: MultiSig(sigs) }
+    }
+
+    /// Creates a command signed by the aggregated MuSig2 signature of `signers` over
+    /// [`command_message(episode_id, seq, &cmd)`](command_message), where `seq` is as for multi-signed commands
+    pub fn new_aggregate_signed_command(episode_id: EpisodeId, seq: u64, cmd: G::Command, signers: Vec<PubKey>, sig: Sig) -> Self {
+        Self::AggregateSignedCommand { episode_id, seq, cmd, signers, sig }
+    }
+
+    /// Splits the message into chunk messages each carrying at most `chunk_size` bytes of the serialized message.
+    /// Returns `None` if the message fits within a single chunk, or if it exceeds [`MAX_CHUNKED_MESSAGE_LEN`] or requires
+    /// more than [`MAX_CHUNKS`] chunks, since such messages are not assembled by the engine.
+    pub fn into_chunks(&self, chunk_size: usize) -> Option<Vec<Self>> {
+        let bytes = borsh::to_vec(self).unwrap();
+        if chunk_size == 0 || bytes.len() <= chunk_size || bytes.len() > MAX_CHUNKED_MESSAGE_LEN {
+            return None;
+        }
+        let total: u16 = bytes.len().div_ceil(chunk_size).try_into().ok().filter(|&total| total <= MAX_CHUNKS)?;
+        let message_id = Hash::from_slice(&Sha256::digest(&bytes));
+        let episode_id = self.episode_id();
+        let chunks = bytes
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(idx, data)| Self::Chunk { episode_id, message_id, idx: idx as u16, total, data: data.to_vec() })
+            .collect();
+        Some(chunks)
+    }
 }
 
-#[derive(Debug, BorshSerialize, BorshDeserialize)]
+/// Messages sent from the proxy listener to the engine. `BlkAccepted` reports the episode txs accepted by a block (see
+/// [`AssociatedTx`]). It is also sent without txs at least once per polling round of the listener, which lets
+/// DAA driven logic such as episode ticks progress while no episode txs are accepted. `BlkConfirmed` reports the current
+/// DAA depth of an accepting block which was previously reported via `BlkAccepted`, i.e., the difference between the
+/// virtual DAA score and the DAA score of the block (not a count of blocks on top of it), and `BlkFinalized` indicates
+/// that such a block passed the finality depth and can no longer be reverted.
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub enum EngineMsg {
-    BlkAccepted { accepting_hash: Hash, accepting_daa: u64, accepting_time: u64, associated_txs: Vec<(Hash, Vec<u8>)> },
+    BlkAccepted { accepting_hash: Hash, accepting_daa: u64, accepting_time: u64, associated_txs: Vec<AssociatedTx> },
     BlkReverted { accepting_hash: Hash },
+    BlkConfirmed { accepting_hash: Hash, daa_depth: u64 },
+    BlkFinalized { accepting_hash: Hash },
     Exit,
 }
 
 impl<G: Episode> EpisodeWrapper<G> {
     pub fn initialize(participants: Vec<PubKey>, metadata: &PayloadMetadata) -> Self {
         let episode = G::initialize(participants, metadata);
-        let rollback_stack = vec![];
-        EpisodeWrapper { episode, rollback_stack }
+        EpisodeWrapper {
+            episode,
+            rollback_stack: vec![],
+            retired_keys: vec![],
+            sequences: HashMap::new(),
+            last_tick_daa: metadata.accepting_daa,
+        }
     }
 
+    /// Verifies the signature and that the sequence number exceeds the last one of the signer, and executes the command
+    /// on success. The sequence number is consumed only if the command executes successfully
     pub fn execute_signed(
         &mut self,
+        episode_id: EpisodeId,
+        seq: u64,
         cmd: &G::Command,
         pubkey: PubKey,
         sig: Sig,
         metadata: &PayloadMetadata,
     ) -> Result<(), EpisodeError<G::CommandError>> {
-        if !self::verify_signature(&pubkey, &self::to_message(&cmd), &sig) {
+        if !self::verify_signature(&pubkey, &command_message(episode_id, seq, cmd), &sig) {
             return Err(EpisodeError::InvalidSignature);
         }
-        let rollback = G::execute(&mut self.episode, cmd, Some(pubkey), metadata)?;
-        self.rollback_stack.push(rollback);
-        Ok(())
+        self.check_sequence(&[pubkey], seq)?;
+        self.journaled(&[pubkey], seq, |episode| episode.execute(cmd, Some(pubkey), metadata))
+    }
+
+    /// Verifies the signatures against the multisig policy of the episode and the sequence number against the last
+    /// ones of the signers, and executes the command on success
+    pub fn execute_multisigned(
+        &mut self,
+        episode_id: EpisodeId,
+        seq: u64,
+        cmd: &G::Command,
+        sigs: &MultiSig,
+        metadata: &PayloadMetadata,
+    ) -> Result<(), EpisodeError<G::CommandError>> {
+        let signers = self.authorized_signers(cmd, sigs.0.iter().map(|(pubkey, _)| pubkey))?;
+        let msg = command_message(episode_id, seq, cmd);
+        if !sigs.0.iter().all(|(pubkey, sig)| self::verify_signature(pubkey, &msg, sig)) {
+            return Err(EpisodeError::InvalidSignature);
+        }
+        self.check_sequence(&signers, seq)?;
+        self.journaled(&signers, seq, |episode| episode.execute_multisigned(cmd, &signers, metadata))
+    }
+
+    /// Verifies the aggregated signature against the aggregated key of `signers`, who must satisfy the multisig policy
+    /// of the episode, and the sequence number as for multi-signed commands, and executes the command on success
+    pub fn execute_aggregate_signed(
+        &mut self,
+        episode_id: EpisodeId,
+        seq: u64,
+        cmd: &G::Command,
+        signers: &[PubKey],
+        sig: &Sig,
+        metadata: &PayloadMetadata,
+    ) -> Result<(), EpisodeError<G::CommandError>> {
+        let authorized = self.authorized_signers(cmd, signers.iter())?;
+        // Duplicate keys would alter the aggregated key, so they are not tolerated here
+        if authorized.len() != signers.len() {
+            return Err(EpisodeError::Unauthorized);
+        }
+        let Some(aggregated_key) = aggregate_keys(signers) else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        if !self::verify_signature(&aggregated_key, &command_message(episode_id, seq, cmd), sig) {
+            return Err(EpisodeError::InvalidSignature);
+        }
+        self.check_sequence(&authorized, seq)?;
+        self.journaled(&authorized, seq, |episode| episode.execute_multisigned(cmd, &authorized, metadata))
+    }
+
+    /// Returns the distinct `signers` if all of them belong to the multisig policy of the episode for `cmd` and they
+    /// reach its threshold
+    fn authorized_signers<'a>(
+        &self,
+        cmd: &G::Command,
+        signers: impl Iterator<Item = &'a PubKey>,
+    ) -> Result<Vec<PubKey>, EpisodeError<G::CommandError>> {
+        let Some(MultisigPolicy { threshold, signers: policy_signers }) = self.episode.multisig_policy(cmd) else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        let mut authorized: Vec<PubKey> = vec![];
+        for pubkey in signers {
+            if !policy_signers.contains(pubkey) {
+                return Err(EpisodeError::Unauthorized);
+            }
+            // Duplicate signatures by the same signer are counted once
+            if !authorized.contains(pubkey) {
+                authorized.push(*pubkey);
+            }
+        }
+        if authorized.len() < threshold.max(1) {
+            return Err(EpisodeError::Unauthorized);
+        }
+        Ok(authorized)
+    }
+
+    /// Fails unless `seq` exceeds the sequence number of the last command signed by each of the signers
+    fn check_sequence(&self, signers: &[PubKey], seq: u64) -> Result<(), EpisodeError<G::CommandError>> {
+        match signers.iter().filter_map(|pubkey| self.sequences.get(pubkey)).max() {
+            Some(&last) if seq <= last => Err(EpisodeError::StaleSequence { seq, last }),
+            _ => Ok(()),
+        }
     }
 
     pub fn execute_unsigned(&mut self, cmd: &G::Command, metadata: &PayloadMetadata) -> Result<(), EpisodeError<G::CommandError>> {
-        let rollback = G::execute(&mut self.episode, cmd, None, metadata)?;
-        self.rollback_stack.push(rollback);
+        self.journaled(&[], 0, |episode| episode.execute(cmd, None, metadata))
+    }
+
+    /// Runs an execution while journaling the mutations of the episode scratch store, which are undone if it fails.
+    /// On success, records `seq` as the sequence number of each of the signers (none for unsigned commands)
+    fn journaled(
+        &mut self,
+        signers: &[PubKey],
+        seq: u64,
+        execute: impl FnOnce(&mut G) -> Result<G::CommandRollback, EpisodeError<G::CommandError>>,
+    ) -> Result<(), EpisodeError<G::CommandError>> {
+        if let Some(store) = self.episode.scratch_store() {
+            store.begin();
+        }
+        match execute(&mut self.episode) {
+            Ok(rollback) => {
+                let scratch_rollback = self.episode.scratch_store().map(ScratchStore::commit).unwrap_or_default();
+                let sequences = signers.iter().map(|&pubkey| (pubkey, self.sequences.insert(pubkey, seq))).collect();
+                self.rollback_stack.push(Rollback::Command(rollback, scratch_rollback, sequences));
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(store) = self.episode.scratch_store() {
+                    store.discard();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Verifies the rotation is signed by the old key and applies it to the episode
+    pub fn execute_key_rotation(
+        &mut self,
+        episode_id: EpisodeId,
+        old: PubKey,
+        new: PubKey,
+        sig: &Sig,
+    ) -> Result<(), EpisodeError<G::CommandError>> {
+        if self.retired_keys.contains(&new) {
+            return Err(EpisodeError::Unauthorized);
+        }
+        if !self::verify_signature(&old, &key_rotation_message(episode_id, &new), sig) {
+            return Err(EpisodeError::InvalidSignature);
+        }
+        if !self.episode.rotate_key(&old, &new) {
+            return Err(EpisodeError::Unauthorized);
+        }
+        self.retired_keys.push(old);
+        self.rollback_stack.push(Rollback::KeyRotation { old, new });
         Ok(())
     }
 
+    /// Ticks the episode if its tick interval elapsed by the accepting block of `metadata`. Returns whether it was ticked
+    pub fn daa_tick(&mut self, metadata: &PayloadMetadata) -> bool {
+        let Some(interval) = self.episode.daa_tick_interval() else {
+            return false;
+        };
+        if metadata.accepting_daa < self.last_tick_daa.saturating_add(interval.max(1)) {
+            return false;
+        }
+        if let Some(store) = self.episode.scratch_store() {
+            store.begin();
+        }
+        let rollback = self.episode.on_daa_tick(metadata.accepting_daa, metadata);
+        let scratch_rollback = self.episode.scratch_store().map(ScratchStore::commit).unwrap_or_default();
+        // Ticks leaving the state unchanged are recorded as well, so that reverting them restores the tick schedule
+        self.rollback_stack.push(Rollback::DaaTick { rollback, scratch_rollback, prev_tick_daa: self.last_tick_daa });
+        self.last_tick_daa = metadata.accepting_daa;
+        true
+    }
+
     pub fn rollback(&mut self) -> Result<(), EpisodeError<G::CommandError>> {
         if let Some(rollback) = self.rollback_stack.pop() {
-            let res = self.episode.rollback(rollback);
+            let res = match rollback {
+                Rollback::Command(rollback, scratch_rollback, sequences) => {
+                    // The episode rollback observes the scratch store as it was following the command
+                    let res = self.episode.rollback(rollback);
+                    if let Some(store) = self.episode.scratch_store() {
+                        store.restore(scratch_rollback);
+                    }
+                    for (pubkey, prev) in sequences {
+                        match prev {
+                            Some(prev) => self.sequences.insert(pubkey, prev),
+                            None => self.sequences.remove(&pubkey),
+                        };
+                    }
+                    res
+                }
+                Rollback::KeyRotation { old, new } => {
+                    self.retired_keys.retain(|key| key != &old);
+                    self.episode.rotate_key(&new, &old)
+                }
+                Rollback::DaaTick { rollback, scratch_rollback, prev_tick_daa } => {
+                    self.last_tick_daa = prev_tick_daa;
+                    let res = match rollback {
+                        Some(rollback) => self.episode.rollback(rollback),
+                        None => true,
+                    };
+                    if let Some(store) = self.episode.scratch_store() {
+                        store.restore(scratch_rollback);
+                    }
+                    res
+                }
+            };
             if !res {
                 error!(
                     "Episode rollback for type {} was unsuccessful (indicates a severe bug in episode impl or engine code)",
@@ -130,61 +461,141 @@ impl<G: Episode> EpisodeWrapper<G> {
     }
 }
 
+/// The message signed by the key of a signed command. It binds the episode and the sequence number of the command, so
+/// that the command cannot be replayed in other episodes or within its episode
+pub fn command_message<C: BorshSerialize>(episode_id: EpisodeId, seq: u64, cmd: &C) -> Message {
+    self::to_message(&(episode_id, seq, cmd))
+}
+
+/// The message signed by the old key of a key rotation. It binds the episode so that the rotation cannot be replayed
+/// in other episodes
+fn key_rotation_message(episode_id: EpisodeId, new: &PubKey) -> Message {
+    self::to_message(&(episode_id, new))
+}
+
 impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
     pub fn new(receiver: Receiver<EngineMsg>) -> Self {
         let episodes: HashMap<EpisodeId, EpisodeWrapper<G>> = HashMap::new();
         let episode_creation_times: HashMap<EpisodeId, u64> = HashMap::new();
         let revert_map: HashMap<Hash, Vec<(EpisodeId, PayloadMetadata)>> = HashMap::new();
         let next_filtering: u64 = 0;
-        Self { episodes, revert_map, episode_creation_times, receiver, next_filtering, _phantom: Default::default() }
+        let chunk_assemblies: HashMap<Hash, ChunkAssembly> = HashMap::new();
+        let tick_reverts: HashMap<Hash, Vec<EpisodeId>> = HashMap::new();
+        Self {
+            episodes,
+            revert_map,
+            episode_creation_times,
+            receiver,
+            next_filtering,
+            chunk_assemblies,
+            tick_reverts,
+            anchoring: None,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Commits to the states of all episodes once every `interval` DAA, sending the commitment (see [`crate::anchor`])
+    /// to `sender` for publishing on-chain. `digest` maps an episode state to its digest, e.g., via
+    /// [`crate::shadow::borsh_digest`] for Borsh serializable episodes
+    pub fn with_anchoring(mut self, interval: u64, digest: fn(&G) -> Hash, sender: UnboundedSender<AnchoredStates>) -> Self {
+        self.anchoring = Some(Anchoring { interval: interval.max(1), digest, sender, next_daa: 0 });
+        self
     }
 
     pub fn start(&mut self, handlers: Vec<H>) {
         while let Ok(msg) = self.receiver.recv() {
             match msg {
                 EngineMsg::BlkAccepted { accepting_hash, accepting_daa, accepting_time, associated_txs } => {
-                    self.filter_old_episodes(accepting_daa);
+                    self.filter_old_episodes(accepting_daa, &handlers);
+                    self.chunk_assemblies.retain(|_, assembly| assembly.first_seen_daa + CHUNK_ASSEMBLY_TIMEOUT > accepting_daa);
+                    self.daa_tick(accepting_hash, accepting_daa, accepting_time, &handlers);
                     let mut revert_vec: Vec<(EpisodeId, PayloadMetadata)> = vec![];
-                    for (tx_id, payload) in associated_txs {
+                    for (tx_id, payload, tx_outputs, spent_tx_ids) in associated_txs {
                         let episode_action: EpisodeMessage<G> = match borsh::from_slice(&payload) {
                             Ok(EpisodeMessage::Revert { episode_id }) => {
                                 warn!("Episode: {}. Illegal revert attempted. Ignoring.", episode_id);
                                 continue;
                             }
+                            Ok(chunk @ EpisodeMessage::Chunk { .. }) => {
+                                match self.assemble_chunk(chunk, tx_id, &spent_tx_ids, accepting_hash, accepting_daa) {
+                                    Some(episode_action) => episode_action,
+                                    None => continue,
+                                }
+                            }
                             Ok(episode_action) => episode_action,
                             Err(err) => {
                                 warn!("Payload: {:?} rejected. Parsing error: {}", payload, err);
                                 continue;
                             }
                         };
-                        let metadata = PayloadMetadata { accepting_hash, accepting_daa, accepting_time, tx_id };
+                        let metadata = PayloadMetadata {
+                            accepting_hash,
+                            accepting_daa,
+                            accepting_time,
+                            tx_id,
+                            tx_first_output_address: tx_outputs.first().and_then(|output| output.address.clone()),
+                            tx_outputs,
+                        };
                         if let Some(revert_id) = self.handle_message(episode_action, &metadata, &handlers) {
                             revert_vec.push(revert_id);
                         }
                     }
-                    self.revert_map.insert(accepting_hash, revert_vec);
+                    if !revert_vec.is_empty() {
+                        self.revert_map.insert(accepting_hash, revert_vec);
+                    }
+                    self.anchor(accepting_hash, accepting_daa);
                 }
-                EngineMsg::BlkReverted { accepting_hash } => match self.revert_map.entry(accepting_hash) {
-                    Entry::Occupied(entry) => {
+                EngineMsg::BlkReverted { accepting_hash } => {
+                    self.revert_chunks(accepting_hash);
+                    if let Entry::Occupied(entry) = self.revert_map.entry(accepting_hash) {
                         for reversion in entry.remove().into_iter().rev() {
                             let episode_action: EpisodeMessage<G> = EpisodeMessage::Revert { episode_id: reversion.0 };
-                            let metadata = PayloadMetadata {
-                                accepting_hash,
-                                accepting_daa: reversion.1.accepting_daa,
-                                accepting_time: reversion.1.accepting_time,
-                                tx_id: reversion.1.tx_id,
-                            };
+                            let metadata = PayloadMetadata { accepting_hash, ..reversion.1 };
                             assert_eq!(self.handle_message(episode_action, &metadata, &handlers), None);
                         }
                     }
-                    Entry::Vacant(_) => {}
-                },
+                    // Ticks precede the commands of their accepting block, so they are rolled back last
+                    if let Some(ticked) = self.tick_reverts.remove(&accepting_hash) {
+                        for episode_id in ticked.into_iter().rev() {
+                            if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
+                                debug!("Episode {}: Reverting DAA tick at {}", episode_id, accepting_hash);
+                                let _ = wrapper.rollback();
+                                for handler in handlers.iter() {
+                                    handler.on_rollback(episode_id, &wrapper.episode);
+                                }
+                            }
+                        }
+                    }
+                }
+                EngineMsg::BlkConfirmed { accepting_hash, daa_depth } => {
+                    if let Some(confirmed) = self.revert_map.get(&accepting_hash) {
+                        for (episode_id, metadata) in confirmed.iter() {
+                            for handler in handlers.iter() {
+                                handler.on_confirmation(*episode_id, metadata.tx_id, daa_depth);
+                            }
+                        }
+                    }
+                }
+                EngineMsg::BlkFinalized { accepting_hash } => {
+                    // Finalized blocks can no longer be reverted, so their revert entries can be dropped
+                    self.tick_reverts.remove(&accepting_hash);
+                    if let Some(finalized) = self.revert_map.remove(&accepting_hash) {
+                        for (episode_id, metadata) in finalized {
+                            if let Some(wrapper) = self.episodes.get(&episode_id) {
+                                for handler in handlers.iter() {
+                                    handler.on_finalized(episode_id, &wrapper.episode, &metadata);
+                                }
+                            }
+                        }
+                    }
+                }
                 EngineMsg::Exit => break,
             }
         }
     }
 
-    pub fn filter_old_episodes(&mut self, daa_score: u64) {
+    #[doc(hidden)]
+    pub fn filter_old_episodes(&mut self, daa_score: u64, handlers: &[H]) {
         if daa_score > self.next_filtering + SAMPLE_REMOVAL_TIME {
             let mut remove_ids = vec![];
             for (episode_id, creation_time) in self.episode_creation_times.iter() {
@@ -195,11 +606,162 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
             for episode_id in remove_ids {
                 self.episodes.remove_entry(&episode_id);
                 self.episode_creation_times.remove_entry(&episode_id);
+                for handler in handlers.iter() {
+                    handler.on_expiry(episode_id);
+                }
             }
             self.next_filtering = daa_score;
         }
     }
 
+    /// Anchors the episode states as of the accepting block if the anchoring interval elapsed
+    fn anchor(&mut self, accepting_hash: Hash, accepting_daa: u64) {
+        let Some(anchoring) = self.anchoring.as_mut() else {
+            return;
+        };
+        if accepting_daa < anchoring.next_daa || self.episodes.is_empty() {
+            return;
+        }
+        let digests = self.episodes.iter().map(|(&episode_id, wrapper)| (episode_id, (anchoring.digest)(&wrapper.episode))).collect();
+        if anchoring.sender.send(AnchoredStates::new(accepting_hash, accepting_daa, digests)).is_err() {
+            warn!("Anchor receiver dropped. Disabling anchoring.");
+            self.anchoring = None;
+            return;
+        }
+        anchoring.next_daa = accepting_daa + anchoring.interval;
+    }
+
+    /// Ticks all episodes whose tick interval elapsed by the accepting block, recording them for a possible revert
+    fn daa_tick(&mut self, accepting_hash: Hash, accepting_daa: u64, accepting_time: u64, handlers: &[H]) {
+        let metadata = PayloadMetadata {
+            accepting_hash,
+            accepting_daa,
+            accepting_time,
+            tx_id: Hash::default(),
+            tx_first_output_address: None,
+            tx_outputs: vec![],
+        };
+        let mut ticked = vec![];
+        for (&episode_id, wrapper) in self.episodes.iter_mut() {
+            if wrapper.daa_tick(&metadata) {
+                for handler in handlers.iter() {
+                    handler.on_daa_tick(episode_id, &wrapper.episode, accepting_daa);
+                }
+                ticked.push(episode_id);
+            }
+        }
+        if !ticked.is_empty() {
+            self.tick_reverts.insert(accepting_hash, ticked);
+        }
+    }
+
+    /// Records a chunk carried by the tx `tx_id`, which spends outputs of the txs `spent_tx_ids`, and returns the assembled
+    /// message once all of its chunks were received. A chunk other than the first is recorded only if the tx spends an
+    /// output of the tx which carried the previous chunk.
+    fn assemble_chunk(
+        &mut self,
+        chunk: EpisodeMessage<G>,
+        tx_id: Hash,
+        spent_tx_ids: &[Hash],
+        accepting_hash: Hash,
+        accepting_daa: u64,
+    ) -> Option<EpisodeMessage<G>> {
+        let EpisodeMessage::Chunk { episode_id, message_id, idx, total, data } = chunk else {
+            return None;
+        };
+        let prev = match idx {
+            0 => None,
+            _ => match spent_tx_ids.iter().find(|id| {
+                self.chunk_assemblies
+                    .get(id)
+                    .is_some_and(|assembly| assembly.message_id == message_id && assembly.parts.len() == idx as usize)
+            }) {
+                Some(&prev) => Some(prev),
+                None => {
+                    warn!(
+                        "Chunk {}/{} of message {} does not follow its previous chunk. Ignoring.",
+                        idx as u32 + 1,
+                        total,
+                        message_id
+                    );
+                    return None;
+                }
+            },
+        };
+        let (prev_total, prev_episode_id, prev_len) = match prev.and_then(|prev| self.chunk_assemblies.get(&prev)) {
+            Some(assembly) => (assembly.total, assembly.episode_id, assembly.parts.iter().map(|(data, _, _)| data.len()).sum()),
+            None => (total, episode_id, 0),
+        };
+        if idx >= total
+            || total > MAX_CHUNKS
+            || (total, episode_id) != (prev_total, prev_episode_id)
+            || prev_len + data.len() > MAX_CHUNKED_MESSAGE_LEN
+        {
+            warn!("Chunk {}/{} of message {} is inconsistent. Ignoring.", idx as u32 + 1, total, message_id);
+            return None;
+        }
+        debug!("Chunk {}/{} of message {} received.", idx as u32 + 1, total, message_id);
+        let mut assembly = match prev {
+            Some(prev) => self.chunk_assemblies.remove(&prev).unwrap(),
+            None => ChunkAssembly { episode_id, message_id, total, parts: vec![], first_seen_daa: accepting_daa },
+        };
+        assembly.parts.push((data, tx_id, accepting_hash));
+        let complete = assembly.parts.len() == total as usize;
+        let bytes: Vec<u8> = if complete { assembly.parts.iter().flat_map(|(data, _, _)| data).copied().collect() } else { vec![] };
+        // A complete assembly is kept until it times out, so that its last part can be reverted and re-accepted
+        self.chunk_assemblies.insert(tx_id, assembly);
+        if !complete {
+            return None;
+        }
+        if Hash::from_slice(&Sha256::digest(&bytes)) != message_id {
+            warn!("Chunked message {} does not match its digest. Ignoring.", message_id);
+            return None;
+        }
+        match borsh::from_slice::<EpisodeMessage<G>>(&bytes) {
+            Ok(EpisodeMessage::Revert { .. } | EpisodeMessage::Chunk { .. }) => {
+                warn!("Chunked message {} wraps an illegal message. Ignoring.", message_id);
+                None
+            }
+            Ok(episode_action) if episode_action.episode_id() != episode_id => {
+                warn!("Chunked message {} does not belong to episode {}. Ignoring.", message_id, episode_id);
+                None
+            }
+            Ok(episode_action) => Some(episode_action),
+            Err(err) => {
+                warn!("Chunked message {} rejected. Parsing error: {}", message_id, err);
+                None
+            }
+        }
+    }
+
+    /// Drops the chunks carried by the reverted block along with the chunks following them, whose txs spend theirs and
+    /// were thus accepted by the same block or by blocks which were reverted before. A chunked message executed in the
+    /// block is executed again once its last chunk is re-accepted
+    fn revert_chunks(&mut self, accepting_hash: Hash) {
+        let reverted: Vec<Hash> = self
+            .chunk_assemblies
+            .iter()
+            .filter(|(_, assembly)| assembly.parts.iter().any(|&(_, _, carrier)| carrier == accepting_hash))
+            .map(|(&tip, _)| tip)
+            .collect();
+        for tip in reverted {
+            let mut assembly = self.chunk_assemblies.remove(&tip).unwrap();
+            let first_reverted = assembly.parts.iter().position(|&(_, _, carrier)| carrier == accepting_hash).unwrap();
+            assembly.parts.truncate(first_reverted);
+            if let Some(&(_, tip, _)) = assembly.parts.last() {
+                self.chunk_assemblies.insert(tip, assembly);
+            }
+        }
+    }
+
+    /// Reports the rejection of an episode tx to the handlers, so that it can be surfaced to the submitting participant
+    fn reject(handlers: &[H], episode_id: EpisodeId, metadata: &PayloadMetadata, error: &str) {
+        for handler in handlers.iter() {
+            handler.on_rejection(episode_id, metadata.tx_id, error);
+        }
+    }
+
+    #[doc(hidden)]
     pub fn handle_message(
         &mut self,
         episode_action: EpisodeMessage<G>,
@@ -210,6 +772,7 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
             EpisodeMessage::NewEpisode { episode_id, participants } => {
                 if self.episodes.contains_key(&episode_id) {
                     warn!("Episode with id {} already exists", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode already exists.");
                     return None;
                 }
                 let ew = EpisodeWrapper::<G>::initialize(participants, metadata);
@@ -223,9 +786,9 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                 return Some((episode_id, metadata.clone()));
             }
 
-            EpisodeMessage::SignedCommand { episode_id, cmd, pubkey, sig } => {
+            EpisodeMessage::SignedCommand { episode_id, seq, cmd, pubkey, sig } => {
                 if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
-                    match wrapper.execute_signed(&cmd, pubkey, sig, metadata) {
+                    match wrapper.execute_signed(episode_id, seq, &cmd, pubkey, sig, metadata) {
                         Ok(()) => {
                             for handler in handlers.iter() {
                                 handler.on_command(episode_id, &wrapper.episode, &cmd, Some(pubkey), metadata);
@@ -233,11 +796,13 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                             return Some((episode_id, metadata.clone()));
                         }
                         Err(e) => {
-                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e)
+                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e);
+                            Self::reject(handlers, episode_id, metadata, &e.to_string());
                         }
                     }
                 } else {
                     warn!("Episode {} not found.", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode not found.");
                 }
             }
 
@@ -251,14 +816,80 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                             return Some((episode_id, metadata.clone()));
                         }
                         Err(e) => {
-                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e)
+                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e);
+                            Self::reject(handlers, episode_id, metadata, &e.to_string());
+                        }
+                    }
+                } else {
+                    warn!("Episode {} not found.", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode not found.");
+                }
+            }
+
+            EpisodeMessage::MultiSignedCommand { episode_id, seq, cmd, sigs } => {
+                if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
+                    match wrapper.execute_multisigned(episode_id, seq, &cmd, &sigs, metadata) {
+                        Ok(()) => {
+                            for handler in handlers.iter() {
+                                handler.on_command(episode_id, &wrapper.episode, &cmd, None, metadata);
+                            }
+                            return Some((episode_id, metadata.clone()));
+                        }
+                        Err(e) => {
+                            warn!("Episode {}: Multi-signed command {:?} rejected: {}", episode_id, cmd, e);
+                            Self::reject(handlers, episode_id, metadata, &e.to_string());
                         }
                     }
                 } else {
                     warn!("Episode {} not found.", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode not found.");
                 }
             }
 
+            EpisodeMessage::AggregateSignedCommand { episode_id, seq, cmd, signers, sig } => {
+                if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
+                    match wrapper.execute_aggregate_signed(episode_id, seq, &cmd, &signers, &sig, metadata) {
+                        Ok(()) => {
+                            for handler in handlers.iter() {
+                                handler.on_command(episode_id, &wrapper.episode, &cmd, None, metadata);
+                            }
+                            return Some((episode_id, metadata.clone()));
+                        }
+                        Err(e) => {
+                            warn!("Episode {}: Aggregate-signed command {:?} rejected: {}", episode_id, cmd, e);
+                            Self::reject(handlers, episode_id, metadata, &e.to_string());
+                        }
+                    }
+                } else {
+                    warn!("Episode {} not found.", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode not found.");
+                }
+            }
+
+            EpisodeMessage::RotateKey { episode_id, old_pubkey, new_pubkey, sig } => {
+                if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
+                    match wrapper.execute_key_rotation(episode_id, old_pubkey, new_pubkey, &sig) {
+                        Ok(()) => {
+                            for handler in handlers.iter() {
+                                handler.on_key_rotation(episode_id, &wrapper.episode, old_pubkey, new_pubkey);
+                            }
+                            return Some((episode_id, metadata.clone()));
+                        }
+                        Err(e) => {
+                            warn!("Episode {}: Key rotation of {} rejected: {}", episode_id, old_pubkey, e);
+                            Self::reject(handlers, episode_id, metadata, &e.to_string());
+                        }
+                    }
+                } else {
+                    warn!("Episode {} not found.", episode_id);
+                    Self::reject(handlers, episode_id, metadata, "episode not found.");
+                }
+            }
+
+            EpisodeMessage::Chunk { episode_id, message_id, .. } => {
+                warn!("Episode {}: chunk of message {} cannot be handled prior to assembly. Ignoring.", episode_id, message_id);
+            }
+
             EpisodeMessage::Revert { episode_id } => {
                 if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
                     info!("Episode {}: Reverting command: {:?}", episode_id, metadata.tx_id);
@@ -280,3 +911,441 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pki::{generate_keypair, musig, sign_message};
+    use crate::testing::Simulation;
+    use std::sync::mpsc::{channel, Sender};
+
+    /// An escrow released by 2 of its 3 participants
+    #[derive(Debug)]
+    struct Escrow {
+        participants: Vec<PubKey>,
+        released: bool,
+    }
+
+    impl Episode for Escrow {
+        type Command = ();
+        type CommandRollback = ();
+        type CommandError = std::fmt::Error;
+
+        fn initialize(participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { participants, released: false }
+        }
+
+        fn execute(
+            &mut self,
+            _cmd: &(),
+            _authorization: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<(), EpisodeError<std::fmt::Error>> {
+            Err(EpisodeError::Unauthorized)
+        }
+
+        fn multisig_policy(&self, _cmd: &()) -> Option<MultisigPolicy> {
+            Some(MultisigPolicy::new(2, self.participants.clone()))
+        }
+
+        fn execute_multisigned(
+            &mut self,
+            _cmd: &(),
+            signers: &[PubKey],
+            _metadata: &PayloadMetadata,
+        ) -> Result<(), EpisodeError<std::fmt::Error>> {
+            assert_eq!(signers.len(), 2);
+            self.released = true;
+            Ok(())
+        }
+
+        fn rollback(&mut self, _rollback: ()) -> bool {
+            self.released = false;
+            true
+        }
+    }
+
+    #[test]
+    fn test_multisigned_command() {
+        let keys = [generate_keypair(), generate_keypair(), generate_keypair()];
+        let (_, outsider) = generate_keypair();
+        let participants: Vec<PubKey> = keys.iter().map(|&(_, pk)| pk).collect();
+        let metadata = PayloadMetadata::for_test(0);
+        let mut engine = Engine::<Escrow>::new(channel().1);
+        for episode_id in [1, 2] {
+            let new_episode = EpisodeMessage::NewEpisode { episode_id, participants: participants.clone() };
+            assert!(engine.handle_message(new_episode, &metadata, &[]).is_some());
+        }
+
+        let msg = command_message(1, 1, &());
+        let sigs = |signers: &[usize]| signers.iter().map(|&i| (keys[i].1, sign_message(&keys[i].0, &msg))).collect::<Vec<_>>();
+        let encode = |sigs| borsh::to_vec(&EpisodeMessage::<Escrow>::new_multisigned_command(1, 1, (), sigs)).unwrap();
+        let decode = |payload: Vec<u8>| borsh::from_slice::<EpisodeMessage<Escrow>>(&payload).unwrap();
+
+        // Below threshold (duplicates count once), outsider and forged signatures are all rejected
+        let mut forged = sigs(&[0]);
+        forged.push((outsider, sign_message(&keys[1].0, &msg)));
+        let mut mismatched = sigs(&[0]);
+        mismatched.push((keys[1].1, sign_message(&keys[2].0, &msg)));
+        for rejected in [sigs(&[1]), sigs(&[2, 2]), forged, mismatched] {
+            assert!(engine.handle_message(decode(encode(rejected)), &metadata, &[]).is_none());
+        }
+        assert!(!engine.episodes[&1].episode.released);
+
+        let released = encode(sigs(&[0, 2]));
+        assert!(engine.handle_message(decode(released.clone()), &metadata, &[]).is_some());
+        assert!(engine.episodes[&1].episode.released);
+
+        // Neither a replay within the episode nor in another episode with the same participants is accepted
+        assert!(engine.handle_message(decode(released.clone()), &metadata, &[]).is_none());
+        let EpisodeMessage::MultiSignedCommand { seq, cmd, sigs, .. } = decode(released) else { unreachable!() };
+        let relabeled = EpisodeMessage::<Escrow>::MultiSignedCommand { episode_id: 2, seq, cmd, sigs };
+        assert!(engine.handle_message(relabeled, &metadata, &[]).is_none());
+        assert!(!engine.episodes[&2].episode.released);
+        assert_eq!((engine.episodes[&1].sequences[&keys[0].1], engine.episodes[&1].sequences.get(&keys[1].1)), (1, None));
+    }
+
+    /// Records the rejections reported by the engine
+    #[derive(Default)]
+    struct Rejections(std::cell::RefCell<Vec<(EpisodeId, Hash, String)>>);
+
+    impl EpisodeEventHandler<Escrow> for Rejections {
+        fn on_initialize(&self, _episode_id: EpisodeId, _episode: &Escrow) {}
+
+        fn on_command(
+            &self,
+            _episode_id: EpisodeId,
+            _episode: &Escrow,
+            _cmd: &(),
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) {
+        }
+
+        fn on_rollback(&self, _episode_id: EpisodeId, _episode: &Escrow) {}
+
+        fn on_rejection(&self, episode_id: EpisodeId, tx_id: Hash, error: &str) {
+            self.0.borrow_mut().push((episode_id, tx_id, error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_rejection_events() {
+        let (_, pk) = generate_keypair();
+        let metadata = |tx_id: u64| PayloadMetadata { tx_id: tx_id.into(), ..PayloadMetadata::for_test(0) };
+        let handlers = [Rejections::default()];
+        let mut engine = Engine::<Escrow, Rejections>::new(channel().1);
+        let new_episode = || EpisodeMessage::NewEpisode { episode_id: 1, participants: vec![pk] };
+        assert!(engine.handle_message(new_episode(), &metadata(1), &handlers).is_some());
+        assert!(handlers[0].0.borrow().is_empty());
+
+        assert!(engine.handle_message(new_episode(), &metadata(2), &handlers).is_none());
+        assert!(engine.handle_message(EpisodeMessage::UnsignedCommand { episode_id: 1, cmd: () }, &metadata(3), &handlers).is_none());
+        assert!(engine.handle_message(EpisodeMessage::UnsignedCommand { episode_id: 2, cmd: () }, &metadata(4), &handlers).is_none());
+        let rejections = handlers[0].0.take();
+        let expected = [
+            (1, 2u64, "episode already exists."),
+            (1, 3, "participant is not authorized in this episode."),
+            (2, 4, "episode not found."),
+        ];
+        assert_eq!(rejections.len(), expected.len());
+        for ((episode_id, tx_id, error), (expected_id, expected_tx, expected_error)) in rejections.into_iter().zip(expected) {
+            assert_eq!((episode_id, tx_id, error.as_str()), (expected_id, expected_tx.into(), expected_error));
+        }
+    }
+
+    #[test]
+    fn test_aggregate_signed_command() {
+        let keys = [generate_keypair(), generate_keypair(), generate_keypair()];
+        let participants: Vec<PubKey> = keys.iter().map(|&(_, pk)| pk).collect();
+        let metadata = PayloadMetadata::for_test(0);
+        let mut engine = Engine::<Escrow>::new(channel().1);
+        let new_episode = EpisodeMessage::NewEpisode { episode_id: 1, participants: participants.clone() };
+        assert!(engine.handle_message(new_episode, &metadata, &[]).is_some());
+
+        let msg = command_message(1, 1, &());
+        let aggregate_sign = |indices: &[usize]| {
+            let signers: Vec<PubKey> = indices.iter().map(|&i| keys[i].1).collect();
+            let (sec_nonces, pub_nonces): (Vec<_>, Vec<_>) = indices.iter().map(|_| musig::generate_nonce()).unzip();
+            let agg_nonce = musig::aggregate_nonces(&pub_nonces).unwrap();
+            let partials: Vec<_> = indices
+                .iter()
+                .zip(sec_nonces)
+                .map(|(&i, sec_nonce)| musig::partial_sign(&keys[i].0, sec_nonce, &signers, &agg_nonce, &msg).unwrap())
+                .collect();
+            let sig = musig::aggregate_partial_sigs(&signers, &agg_nonce, &msg, &partials).unwrap();
+            (signers, sig)
+        };
+
+        // Below threshold, and a signature attributed to a different signer set, are rejected
+        let (signers, sig) = aggregate_sign(&[1]);
+        let below_threshold = EpisodeMessage::new_aggregate_signed_command(1, 1, (), signers, sig);
+        let (_, sig) = aggregate_sign(&[0, 1]);
+        let misattributed = EpisodeMessage::new_aggregate_signed_command(1, 1, (), vec![keys[0].1, keys[2].1], sig);
+        for rejected in [below_threshold, misattributed] {
+            assert!(engine.handle_message(rejected, &metadata, &[]).is_none());
+        }
+        assert!(!engine.episodes[&1].episode.released);
+
+        let (signers, sig) = aggregate_sign(&[2, 0]);
+        let payload = borsh::to_vec(&EpisodeMessage::<Escrow>::new_aggregate_signed_command(1, 1, (), signers, sig)).unwrap();
+        assert!(engine.handle_message(borsh::from_slice(&payload).unwrap(), &metadata, &[]).is_some());
+        assert!(engine.episodes[&1].episode.released);
+
+        // A replay is rejected by the sequence numbers of the signers
+        assert!(engine.handle_message(borsh::from_slice(&payload).unwrap(), &metadata, &[]).is_none());
+        assert_eq!(engine.episodes[&1].sequences[&keys[2].1], 1);
+    }
+
+    /// Records the DAA scores at which it was ticked. Rollbacks indicate whether they roll back a tick
+    #[derive(Debug)]
+    struct Clock {
+        ticks: Vec<u64>,
+    }
+
+    impl Episode for Clock {
+        type Command = ();
+        type CommandRollback = bool;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { ticks: vec![] }
+        }
+
+        fn execute(
+            &mut self,
+            _cmd: &(),
+            _authorization: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<bool, EpisodeError<std::fmt::Error>> {
+            Ok(false)
+        }
+
+        fn daa_tick_interval(&self) -> Option<u64> {
+            Some(10)
+        }
+
+        fn on_daa_tick(&mut self, daa: u64, _metadata: &PayloadMetadata) -> Option<bool> {
+            self.ticks.push(daa);
+            Some(true)
+        }
+
+        fn rollback(&mut self, tick: bool) -> bool {
+            !tick || self.ticks.pop().is_some()
+        }
+    }
+
+    #[test]
+    fn test_daa_tick() {
+        let accepted = |block: u64, daa: u64, msgs: &[EpisodeMessage<Clock>]| EngineMsg::BlkAccepted {
+            accepting_hash: block.into(),
+            accepting_daa: daa,
+            accepting_time: daa,
+            associated_txs: msgs.iter().map(|msg| (block.into(), borsh::to_vec(msg).unwrap(), vec![], vec![])).collect(),
+        };
+        let (sender, receiver) = channel();
+        let mut engine = Engine::<Clock>::new(receiver);
+        let mut run = |msgs: Vec<EngineMsg>| {
+            msgs.into_iter().chain([EngineMsg::Exit]).for_each(|msg| sender.send(msg).unwrap());
+            engine.start(vec![]);
+            (engine.episodes[&1].episode.ticks.clone(), engine.episodes[&1].last_tick_daa)
+        };
+
+        let new_episode = EpisodeMessage::NewEpisode { episode_id: 1, participants: vec![] };
+        assert_eq!(
+            run(vec![accepted(1, 0, &[new_episode]), accepted(2, 5, &[]), accepted(3, 12, &[]), accepted(4, 15, &[])]),
+            (vec![12], 12)
+        );
+
+        // Reverting the ticking block restores the tick schedule
+        let reverts =
+            vec![EngineMsg::BlkReverted { accepting_hash: 4u64.into() }, EngineMsg::BlkReverted { accepting_hash: 3u64.into() }];
+        assert_eq!(run(reverts), (vec![], 0));
+
+        // A tick precedes the commands of its block, so these are reverted first
+        let cmd = EpisodeMessage::UnsignedCommand { episode_id: 1, cmd: () };
+        assert_eq!(run(vec![accepted(5, 11, &[cmd]), accepted(6, 20, &[]), accepted(7, 21, &[])]), (vec![11, 21], 21));
+        assert_eq!(run(vec![EngineMsg::BlkReverted { accepting_hash: 7u64.into() }]), (vec![11], 11));
+        assert_eq!(run(vec![EngineMsg::BlkReverted { accepting_hash: 5u64.into() }]), (vec![], 0));
+    }
+
+    #[test]
+    fn test_daa_tick_idle() {
+        // The empty acceptances sent by the listener while no episode txs are accepted
+        let idle = |block: u64| EngineMsg::BlkAccepted {
+            accepting_hash: block.into(),
+            accepting_daa: block,
+            accepting_time: block,
+            associated_txs: vec![],
+        };
+        let (sender, receiver) = channel();
+        let mut engine = Engine::<Clock>::new(receiver);
+        let new_episode = EpisodeMessage::NewEpisode { episode_id: 1, participants: vec![] };
+        let metadata = PayloadMetadata::for_test(0);
+        assert!(engine.handle_message(new_episode, &metadata, &[]).is_some());
+        let mut run = |msgs: Vec<EngineMsg>| {
+            msgs.into_iter().chain([EngineMsg::Exit]).for_each(|msg| sender.send(msg).unwrap());
+            engine.start(vec![]);
+            (engine.episodes[&1].episode.ticks.clone(), engine.tick_reverts.len(), engine.revert_map.len())
+        };
+
+        assert_eq!(run(vec![idle(10), idle(15), idle(20), idle(30)]), (vec![10, 20, 30], 3, 0));
+        let reverts =
+            vec![EngineMsg::BlkReverted { accepting_hash: 30u64.into() }, EngineMsg::BlkReverted { accepting_hash: 20u64.into() }];
+        assert_eq!(run(reverts), (vec![10], 1, 0));
+        assert_eq!(run(vec![EngineMsg::BlkFinalized { accepting_hash: 10u64.into() }, idle(25)]), (vec![10, 25], 1, 0));
+    }
+
+    #[test]
+    fn test_signed_command_replay() {
+        let (sk, pk) = generate_keypair();
+        let metadata = PayloadMetadata::for_test(0);
+        let mut engine = Engine::<Clock>::new(channel().1);
+        for episode_id in [1, 2] {
+            assert!(engine
+                .handle_message(EpisodeMessage::NewEpisode { episode_id, participants: vec![pk] }, &metadata, &[])
+                .is_some());
+        }
+        let signed = |seq| EpisodeMessage::<Clock>::new_signed_command(1, seq, (), sk, pk);
+        let replay = |msg: &EpisodeMessage<Clock>| borsh::from_slice::<EpisodeMessage<Clock>>(&borsh::to_vec(msg).unwrap()).unwrap();
+
+        let first = signed(1);
+        assert!(engine.handle_message(replay(&first), &metadata, &[]).is_some());
+        // Neither a replay within the episode nor in another episode with the same participants is accepted
+        assert!(engine.handle_message(replay(&first), &metadata, &[]).is_none());
+        let EpisodeMessage::SignedCommand { seq, cmd, pubkey, sig, .. } = first else { unreachable!() };
+        let relabeled = EpisodeMessage::<Clock>::SignedCommand { episode_id: 2, seq, cmd, pubkey, sig };
+        assert!(engine.handle_message(relabeled, &metadata, &[]).is_none());
+
+        // Sequence numbers may have gaps but must increase, and are restored on revert
+        assert!(engine.handle_message(signed(5), &metadata, &[]).is_some());
+        assert!(engine.handle_message(signed(3), &metadata, &[]).is_none());
+        assert!(engine.handle_message(EpisodeMessage::Revert { episode_id: 1 }, &metadata, &[]).is_none());
+        assert_eq!(engine.episodes[&1].sequences[&pk], 1);
+        assert!(engine.handle_message(signed(3), &metadata, &[]).is_some());
+    }
+
+    /// Reports the finalized episode txs
+    #[derive(Clone)]
+    struct Finality(Sender<(EpisodeId, Hash)>);
+
+    impl EpisodeEventHandler<Escrow> for Finality {
+        fn on_initialize(&self, _episode_id: EpisodeId, _episode: &Escrow) {}
+
+        fn on_command(
+            &self,
+            _episode_id: EpisodeId,
+            _episode: &Escrow,
+            _cmd: &(),
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) {
+        }
+
+        fn on_rollback(&self, _episode_id: EpisodeId, _episode: &Escrow) {}
+
+        fn on_finalized(&self, episode_id: EpisodeId, _episode: &Escrow, metadata: &PayloadMetadata) {
+            self.0.send((episode_id, metadata.tx_id)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_finality() {
+        let (_, pk) = generate_keypair();
+        let (sender, receiver) = channel();
+        let mut sim = Simulation::with_handlers(vec![Finality(sender)]);
+        let tx_id = sim.submit(&EpisodeMessage::NewEpisode { episode_id: 1, participants: vec![pk] });
+        sim.advance();
+        sim.finalize();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![(1, tx_id)]);
+
+        // Reverting a finalized block has no effect
+        sim.revert(1);
+        assert!(sim.episode(1).is_some());
+        sim.finalize();
+        assert_eq!(receiver.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_chunk_finality() {
+        let (_, pk) = generate_keypair();
+        let new_episode = EpisodeMessage::<Escrow>::NewEpisode { episode_id: 1, participants: vec![pk; 2] };
+        let chunks = new_episode.into_chunks(20).unwrap();
+        assert!(chunks.len() > 2);
+        let (sender, receiver) = channel();
+        let mut sim = Simulation::with_handlers(vec![Finality(sender)]);
+
+        // Each chunk tx spends the previous one, and the last chunk is accepted by a later block
+        let (last, rest) = chunks.split_last().unwrap();
+        let spent = rest.iter().fold(vec![], |spent, chunk| vec![sim.submit_spending(chunk, spent)]);
+        sim.advance();
+        let last_tx_id = sim.submit_spending(last, spent);
+        sim.advance();
+        assert!(sim.episode(1).is_some());
+
+        // The episode is created by the last chunk, so reorging its block recreates the episode, which is finalized once
+        sim.reorg(1);
+        assert!(sim.episode(1).is_some());
+        sim.finalize();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![(1, last_tx_id)]);
+    }
+
+    #[test]
+    fn test_chunk_assembly() {
+        let (sender, receiver) = channel();
+        let mut engine = Engine::<Clock>::new(receiver);
+        let mut run = |msgs: Vec<EngineMsg>| {
+            msgs.into_iter().chain([EngineMsg::Exit]).for_each(|msg| sender.send(msg).unwrap());
+            engine.start(vec![]);
+            let mut episodes: Vec<_> = engine.episodes.keys().copied().collect();
+            episodes.sort();
+            (episodes, engine.chunk_assemblies.len())
+        };
+        // Chunks along with the txs carrying them and the txs these spend
+        let accepted = |block: u64, chunks: Vec<(u64, u64, EpisodeMessage<Clock>)>| EngineMsg::BlkAccepted {
+            accepting_hash: block.into(),
+            accepting_daa: block,
+            accepting_time: block,
+            associated_txs: chunks
+                .into_iter()
+                .map(|(tx, spent, chunk)| (tx.into(), borsh::to_vec(&chunk).unwrap(), vec![], vec![spent.into()]))
+                .collect(),
+        };
+
+        let (_, pk) = generate_keypair();
+        let new_episode = EpisodeMessage::<Clock>::NewEpisode { episode_id: 1, participants: vec![pk; 3] };
+        let chunks = new_episode.into_chunks(40).unwrap();
+        let last = chunks.len() as u64 - 1;
+        let EpisodeMessage::Chunk { message_id, total, .. } = chunks[0] else { unreachable!() };
+        // The chunk at `idx` of the chain of txs `chain * 100 + idx`, each spending the previous one, labeled with `episode_id`
+        let chunk = |chain: u64, episode_id, idx: u64| {
+            let EpisodeMessage::Chunk { data, .. } = &chunks[idx as usize] else { unreachable!() };
+            let chunk = EpisodeMessage::Chunk { episode_id, message_id, idx: idx as u16, total, data: data.clone() };
+            (chain * 100 + idx, chain * 100 + idx - 1, chunk)
+        };
+        let chain = |chain: u64, episode_id, idxs: std::ops::RangeInclusive<u64>| -> Vec<_> {
+            idxs.map(|idx| chunk(chain, episode_id, idx)).collect()
+        };
+        let forged = |idx: u16, total: u16, data: Vec<u8>| EpisodeMessage::Chunk { episode_id: 1, message_id, idx, total, data };
+
+        // Chunks claiming the message which do not follow its chain, exceed the limits or are inconsistent with it are
+        // ignored, and a chain of another author relabeling the message to another episode is not executed
+        let mut first = chain(1, 1, 0..=last - 1);
+        first.push((300, 1, forged(last as u16, total, vec![0; 40])));
+        first.push((400, 0, forged(0, MAX_CHUNKS + 1, vec![])));
+        first.push((500, 0, forged(0, 2, vec![0; MAX_CHUNKED_MESSAGE_LEN + 1])));
+        first.push((100 + last, 99 + last, forged(last as u16, total + 1, vec![])));
+        assert_eq!(run(vec![accepted(1, first), accepted(2, chain(2, 2, 0..=last))]), (vec![], 2));
+
+        // The parts carried by a reverted block are dropped, so the chain cannot be completed
+        assert_eq!(
+            run(vec![EngineMsg::BlkReverted { accepting_hash: 1u64.into() }, accepted(3, chain(1, 1, last..=last))]),
+            (vec![], 1)
+        );
+
+        // Reverting the block of the last chunk reverts the message, which is executed again once it is re-accepted
+        assert_eq!(run(vec![accepted(4, chain(1, 1, 0..=last - 1)), accepted(5, chain(1, 1, last..=last))]), (vec![1], 2));
+        assert_eq!(run(vec![EngineMsg::BlkReverted { accepting_hash: 5u64.into() }]), (vec![], 2));
+        assert_eq!(run(vec![accepted(6, chain(1, 1, last..=last))]), (vec![1], 2));
+    }
+}
@@ -1,12 +1,17 @@
 //! Defines the external injection points an Episode developer would need to implement
 
 use crate::pki::PubKey;
+use crate::scratch::ScratchStore;
 use borsh::{BorshDeserialize, BorshSerialize};
+use kaspa_addresses::Address;
 use kaspa_consensus_core::Hash;
 use std::error::Error;
 use std::fmt::Debug;
 use thiserror::Error;
 
+/// Derive for statically checking episode command types (see [`kdapp_macros::EpisodeCommand`])
+pub use kdapp_macros::EpisodeCommand;
+
 #[derive(Clone, Debug, Error)]
 pub enum EpisodeError<E: Error + 'static> {
     #[error("participant is not authorized in this episode.")]
@@ -15,6 +20,9 @@ pub enum EpisodeError<E: Error + 'static> {
     #[error("signature verification failed.")]
     InvalidSignature,
 
+    #[error("command sequence number {seq} does not exceed the last one of the signer ({last}).")]
+    StaleSequence { seq: u64, last: u64 },
+
     #[error("invalid command: {0}")]
     InvalidCommand(E),
 
@@ -22,16 +30,58 @@ pub enum EpisodeError<E: Error + 'static> {
     DeleteEpisode,
 }
 
+/// An output of an episode tx. The address is `None` for non-standard scripts
+#[derive(Clone, PartialEq, Eq, Debug, BorshSerialize, BorshDeserialize)]
+pub struct TxOutput {
+    pub address: Option<Address>,
+    pub amount: u64,
+}
+
 #[derive(Clone, PartialEq, Debug, BorshSerialize, BorshDeserialize)]
 pub struct PayloadMetadata {
     pub accepting_hash: Hash,
     pub accepting_daa: u64,
     pub accepting_time: u64,
     pub tx_id: Hash,
+    /// The address receiving the first tx output. By the generator convention this output returns the change to the
+    /// funding address, however the tx author is free to choose it, so it does not establish who paid for the tx and
+    /// must not be relied upon for authorization
+    pub tx_first_output_address: Option<Address>,
+    /// The outputs of the tx in order, where the first is usually the change output. Further outputs carry the value
+    /// attached to the command, e.g., a buy-in paid to an escrow address (see [`crate::economics`])
+    pub tx_outputs: Vec<TxOutput>,
+}
+
+impl PayloadMetadata {
+    /// Metadata of a tx without outputs accepted at `accepting_daa`, with all other fields defaulted. Meant for tests,
+    /// which can set further fields via the struct update syntax
+    pub fn for_test(accepting_daa: u64) -> Self {
+        Self {
+            accepting_hash: Hash::default(),
+            accepting_daa,
+            accepting_time: 0,
+            tx_id: Hash::default(),
+            tx_first_output_address: None,
+            tx_outputs: vec![],
+        }
+    }
 }
 
 pub type EpisodeId = u32;
 
+/// An m-of-n authorization policy: at least `threshold` of `signers` must sign a multi-signed command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: usize,
+    pub signers: Vec<PubKey>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: usize, signers: Vec<PubKey>) -> Self {
+        Self { threshold, signers }
+    }
+}
+
 pub trait Episode {
     type Command: BorshSerialize + BorshDeserialize + Debug + Clone;
     type CommandRollback: BorshSerialize + BorshDeserialize;
@@ -50,10 +100,62 @@ pub trait Episode {
         metadata: &PayloadMetadata,
     ) -> Result<Self::CommandRollback, EpisodeError<Self::CommandError>>;
 
+    /// The m-of-n policy under which the command can be authorized when multi-signed (or aggregate-signed), or `None`
+    /// (the default) if the episode does not accept the command as a multi-signed command
+    fn multisig_policy(&self, _cmd: &Self::Command) -> Option<MultisigPolicy> {
+        None
+    }
+
+    /// Execute a multi-signed command which was already verified against the [policy](Self::multisig_policy) of
+    /// the episode. `signers` are the policy signers who signed the command. Rejected by default
+    fn execute_multisigned(
+        &mut self,
+        _cmd: &Self::Command,
+        _signers: &[PubKey],
+        _metadata: &PayloadMetadata,
+    ) -> Result<Self::CommandRollback, EpisodeError<Self::CommandError>> {
+        Err(EpisodeError::Unauthorized)
+    }
+
+    /// The scratch store held by the episode, if any. Mutations of the store made by a command are journaled by the
+    /// engine and undone when the command is rejected or rolled back, so they need not be captured in `CommandRollback`
+    fn scratch_store(&mut self) -> Option<&mut ScratchStore> {
+        None
+    }
+
+    /// Replace the participant key `old` by `new` wherever the episode authorizes it. Called by the engine for key
+    /// rotations signed by `old`, and with the keys swapped when such a rotation is rolled back. Returns `false` if
+    /// the rotation is not valid, e.g., when `old` is not a participant or `new` already is one. Unsupported by default
+    fn rotate_key(&mut self, _old: &PubKey, _new: &PubKey) -> bool {
+        false
+    }
+
+    /// The DAA score interval at which the engine calls [`Self::on_daa_tick`], or `None` (the default) for episodes
+    /// without time-driven state transitions
+    fn daa_tick_interval(&self) -> Option<u64> {
+        None
+    }
+
+    /// Advance time-driven state, e.g., forfeit a participant whose turn timed out. Called by the engine prior to the
+    /// commands of the first accepted block whose DAA score is at least [`Self::daa_tick_interval`] past the previous tick
+    /// (or the episode creation). `metadata` describes the accepting block and has a zero `tx_id`. Returns the rollback
+    /// of the state change, or `None` if the state is unchanged. Ticks are rolled back along with their accepting block
+    fn on_daa_tick(&mut self, _daa: u64, _metadata: &PayloadMetadata) -> Option<Self::CommandRollback> {
+        None
+    }
+
     /// Rollback a previous execute op
     fn rollback(&mut self, rollback: Self::CommandRollback) -> bool;
 }
 
+/// A sanitized read-only view of an episode, e.g., omitting the secrets it holds, which is what is exposed to
+/// peers and UIs (see [`EpisodeTracker::projected`](crate::tracker::EpisodeTracker::projected)) rather than the full state
+pub trait EpisodeProjection: Episode {
+    type Public: BorshSerialize + Clone + Debug;
+
+    fn project(&self) -> Self::Public;
+}
+
 pub trait EpisodeEventHandler<G: Episode> {
     /// Called by the engine on episode initialization
     fn on_initialize(&self, episode_id: EpisodeId, episode: &G);
@@ -70,4 +172,30 @@ pub trait EpisodeEventHandler<G: Episode> {
 
     /// Called by the engine following a command rollback
     fn on_rollback(&self, episode_id: EpisodeId, episode: &G);
+
+    /// Called by the engine following a successful key rotation of a participant (rolled back rotations are reported
+    /// through `on_rollback`)
+    fn on_key_rotation(&self, _episode_id: EpisodeId, _episode: &G, _old: PubKey, _new: PubKey) {}
+
+    /// Called by the engine following a DAA tick of the episode (see [`Episode::on_daa_tick`]). Rolled back ticks are
+    /// reported through `on_rollback`
+    fn on_daa_tick(&self, _episode_id: EpisodeId, _episode: &G, _daa: u64) {}
+
+    /// Called by the engine as the block which accepted the episode tx `tx_id` gains confirmation depth, allowing to report
+    /// progress from pending through confirmed up until final (see `on_finalized`). `daa_depth` is the DAA score elapsed
+    /// since the accepting block (roughly 10 per second on mainnet), not a count of blocks on top of it
+    fn on_confirmation(&self, _episode_id: EpisodeId, _tx_id: Hash, _daa_depth: u64) {}
+
+    /// Called by the engine once the tx identified by `metadata.tx_id` (an episode creation or a command)
+    /// passed the finality depth, i.e., it is guaranteed to never be rolled back
+    fn on_finalized(&self, _episode_id: EpisodeId, _episode: &G, _metadata: &PayloadMetadata) {}
+
+    /// Called by the engine once the episode outlived its lifetime and was removed, after which it receives no further
+    /// events. Handlers keeping per-episode state should drop it here
+    fn on_expiry(&self, _episode_id: EpisodeId) {}
+
+    /// Called by the engine when the episode tx `tx_id` was accepted but had no effect, e.g., a command failing
+    /// execution or an invalid signature, with `error` describing the reason. This allows surfacing the failure to the
+    /// submitting participant
+    fn on_rejection(&self, _episode_id: EpisodeId, _tx_id: Hash, _error: &str) {}
 }
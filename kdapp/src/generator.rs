@@ -4,7 +4,7 @@
 //! need to be obtained from the Kaspa node.
 
 use itertools::Itertools;
-use kaspa_addresses::Address;
+use kaspa_addresses::{Address, Prefix, Version};
 use kaspa_consensus_core::{
     constants::TX_VERSION,
     sign::sign,
@@ -12,15 +12,59 @@ use kaspa_consensus_core::{
     tx::{MutableTransaction, Transaction, TransactionInput, TransactionOutpoint, TransactionOutput, UtxoEntry},
     Hash,
 };
+use kaspa_rpc_core::{api::rpc::RpcApi, RpcResult};
 use kaspa_txscript::pay_to_address_script;
 use log::debug;
 use secp256k1::Keypair;
+use std::sync::Arc;
 
-use crate::{engine::EpisodeMessage, episode::Episode};
+use crate::{
+    cache::ChainCache,
+    engine::{command_message, EpisodeMessage},
+    episode::Episode,
+    pki::verify_signature,
+};
+
+mod fee;
+mod retry;
+mod utxo;
+pub use fee::{estimate_compute_mass, fee_for_mass, FeePolicy, FeePriority, DEFAULT_FEE, MIN_FEERATE, MIN_OUTPUT_AMOUNT};
+pub use retry::{RetryPolicy, SubmitOutcome};
+pub use utxo::{Utxo, UtxoManager, MAX_AGGREGATED_INPUTS};
+
+pub use crate::engine::DEFAULT_CHUNK_SIZE;
 
 pub type PatternType = [(u8, u8); 10];
 pub type PrefixType = u32;
 
+/// Deterministically derives a tx id pattern from the given prefix, using the prefix as a seed for a simple
+/// splitmix64 generator. Sender and listener only need to agree on the prefix, and since the function is
+/// `const` it can be used for defining pattern constants (e.g., `const PATTERN: PatternType = derive_pattern_from_prefix(PREFIX)`).
+pub const fn derive_pattern_from_prefix(prefix: PrefixType) -> PatternType {
+    let mut pattern = [(0u8, 0u8); 10];
+    let mut used = [false; 256];
+    let mut state = prefix as u64;
+    let mut i = 0;
+    while i < pattern.len() {
+        // splitmix64 step
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+
+        let pos = (z & 0xff) as u8;
+        // Bit positions must be unique, otherwise the pattern might be contradictory or weaker than expected
+        if !used[pos as usize] {
+            used[pos as usize] = true;
+            pattern[i] = (pos, ((z >> 8) & 1) as u8);
+            i += 1;
+        }
+    }
+    pattern
+}
+
+#[doc(hidden)]
 pub fn check_pattern(tx_id: Hash, pattern: &PatternType) -> bool {
     let words = tx_id.as_bytes();
     for (pos, val) in pattern.iter().copied() {
@@ -32,6 +76,7 @@ pub fn check_pattern(tx_id: Hash, pattern: &PatternType) -> bool {
     true
 }
 
+#[doc(hidden)]
 pub struct Payload;
 
 impl Payload {
@@ -58,15 +103,46 @@ impl Payload {
     }
 }
 
+/// Generates txs carrying episode commands. The generator signer is the funding identity paying for the txs, which is
+/// independent of the command identity authorizing signed commands. This allows a peer (e.g., an episode organizer)
+/// to sponsor the fees of commands signed by other participants (see [`Self::build_sponsored_command_transaction`]).
 pub struct TransactionGenerator {
     signer: Keypair,
     pattern: PatternType,
     prefix: PrefixType,
+    fee_policy: FeePolicy,
+    cache: Option<Arc<ChainCache>>,
 }
 
 impl TransactionGenerator {
     pub fn new(signer: Keypair, pattern: PatternType, prefix: PrefixType) -> Self {
-        Self { signer, pattern, prefix }
+        Self { signer, pattern, prefix, fee_policy: Default::default(), cache: None }
+    }
+
+    /// Obtains node fee estimates through a shared chain data cache
+    pub fn with_cache(mut self, cache: Arc<ChainCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the policy used by [`Self::command_fee`] (defaults to a fixed fee of [`DEFAULT_FEE`])
+    pub fn with_fee_policy(mut self, fee_policy: FeePolicy) -> Self {
+        self.fee_policy = fee_policy;
+        self
+    }
+
+    pub fn fee_policy(&self) -> FeePolicy {
+        self.fee_policy
+    }
+
+    /// Creates a generator whose pattern is derived from the prefix (see [`derive_pattern_from_prefix`])
+    pub fn from_prefix(signer: Keypair, prefix: PrefixType) -> Self {
+        Self::new(signer, derive_pattern_from_prefix(prefix), prefix)
+    }
+
+    /// The address of the funding identity, i.e., the generator signer
+    pub fn funding_address(&self, prefix: Prefix) -> Address {
+        Address::new(prefix, Version::PubKey, &self.signer.x_only_public_key().0.serialize())
     }
 
     pub fn build_transaction(
@@ -78,14 +154,23 @@ impl TransactionGenerator {
         payload: Vec<u8>,
     ) -> Transaction {
         let script_public_key = pay_to_address_script(recipient);
+        let outputs = (0..num_outs)
+            .map(|_| TransactionOutput { value: send_amount / num_outs, script_public_key: script_public_key.clone() })
+            .collect_vec();
+        self.build_transaction_with_outputs(utxos, outputs, payload)
+    }
+
+    /// Builds a tx spending `utxos` to the given outputs, with the payload nonce mined to match the generator pattern
+    pub fn build_transaction_with_outputs(
+        &self,
+        utxos: &[(TransactionOutpoint, UtxoEntry)],
+        outputs: Vec<TransactionOutput>,
+        payload: Vec<u8>,
+    ) -> Transaction {
         let inputs = utxos
             .iter()
             .map(|(op, _)| TransactionInput { previous_outpoint: *op, signature_script: vec![], sequence: 0, sig_op_count: 1 })
             .collect_vec();
-
-        let outputs = (0..num_outs)
-            .map(|_| TransactionOutput { value: send_amount / num_outs, script_public_key: script_public_key.clone() })
-            .collect_vec();
         let payload = Payload::pack_header(payload, self.prefix);
         let mut nonce = 0u32;
         let mut unsigned_tx = Transaction::new_non_finalized(TX_VERSION, inputs, outputs, 0, SUBNETWORK_ID_NATIVE, 0, payload);
@@ -114,8 +199,262 @@ impl TransactionGenerator {
         let send = utxo.1.amount - fee;
         self.build_transaction(&[utxo], send, 1, recipient, payload)
     }
+
+    /// Builds a command tx aggregating several UTXOs (e.g., as reserved by [`UtxoManager::reserve_amount`]) into a
+    /// single output to the recipient, where `fee` should be calculated for the number of inputs (see
+    /// [`Self::command_fee_for_inputs`]). Each input is signed by the generator signer. Returns `None` if the UTXOs
+    /// cannot cover the fee along with an output of at least [`MIN_OUTPUT_AMOUNT`]
+    pub fn build_command_transaction_from_utxos<G: Episode>(
+        &self,
+        utxos: &[(TransactionOutpoint, UtxoEntry)],
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+    ) -> Option<Transaction> {
+        let payload = borsh::to_vec(&cmd).unwrap();
+        let total = utxos.iter().try_fold(0u64, |total, (_, entry)| total.checked_add(entry.amount))?;
+        let send = total.checked_sub(fee).filter(|&amount| amount >= MIN_OUTPUT_AMOUNT)?;
+        Some(self.build_transaction(utxos, send, 1, recipient, payload))
+    }
+
+    /// Builds the command tx, or a chain of chunk txs (each spending the output of the previous one, which the engine
+    /// requires for assembling them) if the serialized command exceeds `chunk_size` and the chunk limits permit (see
+    /// [`EpisodeMessage::into_chunks`]). The txs must be submitted in order, and `fee` is paid per tx so it should cover
+    /// the mass of a full chunk.
+    pub fn build_chunked_command_transactions<G: Episode>(
+        &self,
+        utxo: (TransactionOutpoint, UtxoEntry),
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+        chunk_size: usize,
+        fee: u64,
+    ) -> Vec<Transaction> {
+        let Some(chunks) = cmd.into_chunks(chunk_size) else {
+            return vec![self.build_command_transaction(utxo, recipient, cmd, fee)];
+        };
+        let mut utxo = utxo;
+        let mut txs = Vec::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let tx = self.build_command_transaction(utxo, recipient, chunk, fee);
+            utxo = first_output_utxo(&tx);
+            txs.push(tx);
+        }
+        txs
+    }
+
+    /// Builds a tx funding a command signed by another identity, with the change returned to `change` (usually the
+    /// [funding address](Self::funding_address)). Returns `None` unless the command is a signed command with a valid
+    /// signature, so that a sponsor never pays for commands which the engine would reject as unauthorized.
+    pub fn build_sponsored_command_transaction<G: Episode>(
+        &self,
+        utxo: (TransactionOutpoint, UtxoEntry),
+        change: &Address,
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+    ) -> Option<Transaction> {
+        match cmd {
+            EpisodeMessage::SignedCommand { episode_id, seq, cmd: inner, pubkey, sig }
+                if verify_signature(pubkey, &command_message(*episode_id, *seq, inner), sig) =>
+            {
+                Some(self.build_command_transaction(utxo, change, cmd, fee))
+            }
+            _ => None,
+        }
+    }
+
+    /// Builds a command tx which pays each of `outputs` (e.g., a buy-in to an escrow address) and sends the remaining
+    /// amount minus the fee to `change`. The change output is always the first output, so the tx can be chained once
+    /// submitted (see [`UtxoManager::chain`]). Returns `None` if the UTXO cannot cover the outputs and the fee along with
+    /// a change of at least [`MIN_OUTPUT_AMOUNT`], so that the caller can pick another UTXO.
+    pub fn build_command_transaction_with_outputs<G: Episode>(
+        &self,
+        utxo: (TransactionOutpoint, UtxoEntry),
+        change: &Address,
+        outputs: &[(Address, u64)],
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+    ) -> Option<Transaction> {
+        let payload = borsh::to_vec(&cmd).unwrap();
+        let spent = outputs.iter().try_fold(fee, |spent, (_, value)| spent.checked_add(*value))?;
+        let change_amount = utxo.1.amount.checked_sub(spent).filter(|&amount| amount >= MIN_OUTPUT_AMOUNT)?;
+        let outputs = std::iter::once(TransactionOutput { value: change_amount, script_public_key: pay_to_address_script(change) })
+            .chain(
+                outputs
+                    .iter()
+                    .map(|(address, value)| TransactionOutput { value: *value, script_public_key: pay_to_address_script(address) }),
+            )
+            .collect_vec();
+        Some(self.build_transaction_with_outputs(&[utxo], outputs, payload))
+    }
+
+    /// Calculates the fee for a command tx (as built by [`Self::build_command_transaction`]) according to the fee policy
+    pub async fn command_fee<G: Episode>(&self, kaspad: &impl RpcApi, recipient: &Address, cmd: &EpisodeMessage<G>) -> RpcResult<u64> {
+        self.estimate_fee(kaspad, 1, recipient, &[], cmd).await
+    }
+
+    /// Calculates the fee for a command tx spending `inputs` UTXOs (as built by [`Self::build_command_transaction_from_utxos`])
+    pub async fn command_fee_for_inputs<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        inputs: usize,
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+    ) -> RpcResult<u64> {
+        self.estimate_fee(kaspad, inputs, recipient, &[], cmd).await
+    }
+
+    /// Calculates the fee for a command tx with additional outputs (as built by [`Self::build_command_transaction_with_outputs`])
+    pub async fn command_fee_with_outputs<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        change: &Address,
+        outputs: &[(Address, u64)],
+        cmd: &EpisodeMessage<G>,
+    ) -> RpcResult<u64> {
+        self.estimate_fee(kaspad, 1, change, outputs, cmd).await
+    }
+
+    async fn estimate_fee<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        inputs: usize,
+        change: &Address,
+        outputs: &[(Address, u64)],
+        cmd: &EpisodeMessage<G>,
+    ) -> RpcResult<u64> {
+        let payload = Payload::pack_header(borsh::to_vec(&cmd).unwrap(), self.prefix);
+        let inputs = (0..inputs as u32)
+            .map(|index| TransactionInput {
+                previous_outpoint: TransactionOutpoint::new(Hash::default(), index),
+                signature_script: vec![],
+                sequence: 0,
+                sig_op_count: 1,
+            })
+            .collect_vec();
+        let outputs = std::iter::once(change)
+            .chain(outputs.iter().map(|(address, _)| address))
+            .map(|address| TransactionOutput { value: 0, script_public_key: pay_to_address_script(address) })
+            .collect_vec();
+        let tx = Transaction::new_non_finalized(TX_VERSION, inputs, outputs, 0, SUBNETWORK_ID_NATIVE, 0, payload);
+        self.fee_policy.fee_with_cache(kaspad, self.cache.as_deref(), estimate_compute_mass(&tx)).await
+    }
 }
 
+#[deprecated(since = "0.0.1", note = "use `UtxoManager::chain` to track the outputs of submitted txs")]
 pub fn get_first_output_utxo(tx: &Transaction) -> (TransactionOutpoint, UtxoEntry) {
+    first_output_utxo(tx)
+}
+
+fn first_output_utxo(tx: &Transaction) -> (TransactionOutpoint, UtxoEntry) {
     (TransactionOutpoint::new(tx.id(), 0), UtxoEntry::new(tx.outputs[0].value, tx.outputs[0].script_public_key.clone(), 0, false))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::episode::{EpisodeError, PayloadMetadata};
+    use crate::pki::{generate_keypair, PubKey};
+
+    #[derive(Debug)]
+    struct Noop;
+
+    impl Episode for Noop {
+        type Command = ();
+        type CommandRollback = ();
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Noop
+        }
+
+        fn execute(
+            &mut self,
+            _cmd: &(),
+            _authorization: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<(), EpisodeError<std::fmt::Error>> {
+            Ok(())
+        }
+
+        fn rollback(&mut self, _rollback: ()) -> bool {
+            true
+        }
+    }
+
+    fn test_generator() -> TransactionGenerator {
+        let (sk, _) = generate_keypair();
+        TransactionGenerator::from_prefix(Keypair::from_secret_key(secp256k1::SECP256K1, &sk), 1)
+    }
+
+    #[test]
+    fn test_derive_pattern_from_prefix() {
+        let pattern = derive_pattern_from_prefix(858598618);
+        assert_eq!(pattern, derive_pattern_from_prefix(858598618));
+        assert_ne!(pattern, derive_pattern_from_prefix(858598619));
+        assert!(pattern.iter().map(|(pos, _)| pos).all_unique());
+        assert!(pattern.iter().all(|&(_, val)| val <= 1));
+    }
+
+    #[test]
+    fn test_command_transaction_with_outputs() {
+        let change = Address::new(Prefix::Testnet, Version::PubKey, &[1u8; 32]);
+        let escrow = Address::new(Prefix::Testnet, Version::PubKey, &[2u8; 32]);
+        let utxo = (TransactionOutpoint::new(Hash::default(), 0), UtxoEntry::new(10_000, pay_to_address_script(&change), 0, false));
+        let generator = test_generator();
+        let cmd = EpisodeMessage::<Noop>::UnsignedCommand { episode_id: 1, cmd: () };
+        let tx =
+            generator.build_command_transaction_with_outputs(utxo.clone(), &change, &[(escrow.clone(), 3_000)], &cmd, 1_000).unwrap();
+
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(first_output_utxo(&tx).1.amount, 6_000);
+        assert_eq!(tx.outputs[1].value, 3_000);
+        assert_eq!(tx.outputs[1].script_public_key, pay_to_address_script(&escrow));
+        assert!(check_pattern(tx.id(), &derive_pattern_from_prefix(1)));
+
+        // The UTXO must also cover a change output which is not dust
+        let build = |escrow_amount| {
+            generator.build_command_transaction_with_outputs(utxo.clone(), &change, &[(escrow.clone(), escrow_amount)], &cmd, 1_000)
+        };
+        assert!(build(9_000 - MIN_OUTPUT_AMOUNT).is_some());
+        assert!(build(9_000 - MIN_OUTPUT_AMOUNT + 1).is_none());
+        assert!(build(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_command_transaction_from_utxos() {
+        let generator = test_generator();
+        let recipient = generator.funding_address(Prefix::Testnet);
+        let utxos = (0..2)
+            .map(|index| {
+                (TransactionOutpoint::new(Hash::default(), index), UtxoEntry::new(5_000, pay_to_address_script(&recipient), 0, false))
+            })
+            .collect_vec();
+        let cmd = EpisodeMessage::<Noop>::UnsignedCommand { episode_id: 1, cmd: () };
+        let tx = generator.build_command_transaction_from_utxos(&utxos, &recipient, &cmd, 1_000).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(first_output_utxo(&tx).1.amount, 9_000);
+
+        // The UTXOs must cover the fee along with an output which is not dust
+        assert!(generator.build_command_transaction_from_utxos(&utxos, &recipient, &cmd, 10_000 - MIN_OUTPUT_AMOUNT).is_some());
+        assert!(generator.build_command_transaction_from_utxos(&utxos, &recipient, &cmd, 10_000 - MIN_OUTPUT_AMOUNT + 1).is_none());
+        assert!(generator.build_command_transaction_from_utxos(&utxos, &recipient, &cmd, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_sponsored_command_transaction() {
+        let generator = test_generator();
+        let change = generator.funding_address(Prefix::Testnet);
+        let utxo = (TransactionOutpoint::new(Hash::default(), 0), UtxoEntry::new(10_000, pay_to_address_script(&change), 0, false));
+        let ((sk, pk), (_, other_pk)) = (generate_keypair(), generate_keypair());
+
+        let signed = EpisodeMessage::<Noop>::new_signed_command(1, 1, (), sk, pk);
+        assert!(generator.build_sponsored_command_transaction(utxo.clone(), &change, &signed, 1_000).is_some());
+
+        // Commands which the engine would reject are not sponsored
+        let EpisodeMessage::SignedCommand { sig, .. } = signed else { unreachable!() };
+        let forged = EpisodeMessage::<Noop>::SignedCommand { episode_id: 1, seq: 1, cmd: (), pubkey: other_pk, sig };
+        assert!(generator.build_sponsored_command_transaction(utxo.clone(), &change, &forged, 1_000).is_none());
+        let unsigned = EpisodeMessage::<Noop>::UnsignedCommand { episode_id: 1, cmd: () };
+        assert!(generator.build_sponsored_command_transaction(utxo, &change, &unsigned, 1_000).is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! Fee policies for command transactions. Fees are derived from the tx compute mass and a feerate (sompi per gram),
+//! which is either fixed or obtained from the node fee estimator.
+
+use kaspa_consensus_core::tx::Transaction;
+use kaspa_rpc_core::{api::rpc::RpcApi, RpcResult};
+
+use crate::cache::ChainCache;
+
+/// The default fixed fee used when no policy is specified
+pub const DEFAULT_FEE: u64 = 5000;
+
+/// The minimum feerate accepted for relay by kaspad (sompi per gram)
+pub const MIN_FEERATE: f64 = 1.0;
+
+/// The minimal amount (in sompi) of a pay-to-pubkey output which kaspad does not consider dust, i.e., three times the
+/// cost of creating and spending the output (200 bytes) at the minimum relay fee
+pub const MIN_OUTPUT_AMOUNT: u64 = 600;
+
+/// Compute mass weights as defined by Kaspa consensus
+const MASS_PER_TX_BYTE: u64 = 1;
+const MASS_PER_SCRIPT_PUB_KEY_BYTE: u64 = 10;
+const MASS_PER_SIG_OP: u64 = 1000;
+
+/// The expected length of a Schnorr signature script (push op, 64 byte signature and sighash type)
+const SCHNORR_SIGNATURE_SCRIPT_LEN: u64 = 66;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FeePriority {
+    Low,
+    #[default]
+    Normal,
+    Priority,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeePolicy {
+    /// A fixed fee in sompi regardless of tx mass
+    Fixed(u64),
+    /// A fixed feerate in sompi per gram of mass
+    Feerate(f64),
+    /// A feerate obtained from the node fee estimator according to the requested priority
+    Estimated(FeePriority),
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self::Fixed(DEFAULT_FEE)
+    }
+}
+
+impl FeePolicy {
+    /// Resolves the feerate for this policy, querying the node if required. Returns `None` for fixed fee policies
+    pub async fn feerate(&self, kaspad: &impl RpcApi) -> RpcResult<Option<f64>> {
+        self.feerate_with_cache(kaspad, None).await
+    }
+
+    /// Same as [`Self::feerate`], where node fee estimates are obtained through `cache` if provided
+    pub async fn feerate_with_cache(&self, kaspad: &impl RpcApi, cache: Option<&ChainCache>) -> RpcResult<Option<f64>> {
+        match *self {
+            FeePolicy::Fixed(_) => Ok(None),
+            FeePolicy::Feerate(feerate) => Ok(Some(feerate.max(MIN_FEERATE))),
+            FeePolicy::Estimated(priority) => {
+                let estimate = match cache {
+                    Some(cache) => cache.get_fee_estimate(kaspad).await?,
+                    None => kaspad.get_fee_estimate().await?,
+                };
+                let bucket = match priority {
+                    FeePriority::Low => estimate.low_buckets.first(),
+                    FeePriority::Normal => estimate.normal_buckets.first(),
+                    FeePriority::Priority => None,
+                };
+                Ok(Some(bucket.unwrap_or(&estimate.priority_bucket).feerate.max(MIN_FEERATE)))
+            }
+        }
+    }
+
+    /// Calculates the fee for a tx of the given mass according to this policy
+    pub async fn fee(&self, kaspad: &impl RpcApi, mass: u64) -> RpcResult<u64> {
+        self.fee_with_cache(kaspad, None, mass).await
+    }
+
+    /// Same as [`Self::fee`], where node fee estimates are obtained through `cache` if provided
+    pub async fn fee_with_cache(&self, kaspad: &impl RpcApi, cache: Option<&ChainCache>, mass: u64) -> RpcResult<u64> {
+        match *self {
+            FeePolicy::Fixed(fee) => Ok(fee),
+            _ => Ok(fee_for_mass(mass, self.feerate_with_cache(kaspad, cache).await?.unwrap_or(MIN_FEERATE))),
+        }
+    }
+}
+
+pub fn fee_for_mass(mass: u64, feerate: f64) -> u64 {
+    (mass as f64 * feerate).ceil() as u64
+}
+
+/// Estimates the compute mass of a tx. Inputs are assumed to be spent with a Schnorr signature, so the estimate
+/// can be calculated prior to signing
+pub fn estimate_compute_mass(tx: &Transaction) -> u64 {
+    // version + inputs length + outputs length + lock time + subnetwork id + gas + payload hash + payload length
+    let mut size = 2 + 8 + 8 + 8 + 20 + 8 + 32 + 8 + tx.payload.len() as u64;
+    // outpoint + signature script length + signature script + sequence
+    size += tx.inputs.len() as u64 * (32 + 4 + 8 + SCHNORR_SIGNATURE_SCRIPT_LEN + 8);
+    let script_public_key_size: u64 = tx.outputs.iter().map(|output| 2 + output.script_public_key.script().len() as u64).sum();
+    // value + script length + script (and version)
+    size += tx.outputs.len() as u64 * (8 + 8) + script_public_key_size;
+    let sig_ops: u64 = tx.inputs.iter().map(|input| input.sig_op_count as u64).sum();
+    size * MASS_PER_TX_BYTE + script_public_key_size * MASS_PER_SCRIPT_PUB_KEY_BYTE + sig_ops * MASS_PER_SIG_OP
+}
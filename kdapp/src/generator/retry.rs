@@ -0,0 +1,157 @@
+//! Resilient submission of command txs. A submission can fail because the spent UTXO was already spent (e.g., by
+//! another process using the same address), because the tx is an orphan whose parent the node does not know yet,
+//! or because the node connection dropped. In all these cases the UTXO set is refreshed and the command tx is
+//! rebuilt over freshly reserved UTXOs, with exponential backoff between attempts.
+
+use kaspa_addresses::Address;
+use kaspa_consensus_core::tx::{Transaction, TransactionId};
+use kaspa_rpc_core::{api::rpc::RpcApi, RpcError};
+use log::{info, warn};
+use std::time::Duration;
+use tokio::time::sleep;
+
+use super::{TransactionGenerator, Utxo, UtxoManager, MIN_OUTPUT_AMOUNT};
+use crate::{engine::EpisodeMessage, episode::Episode};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Overall number of submission attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 5, initial_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(8) }
+    }
+}
+
+#[derive(Debug)]
+pub enum SubmitOutcome {
+    /// The originally built tx was accepted by the node
+    Accepted(Transaction),
+    /// The original tx was rejected, and a tx rebuilt over different UTXOs was accepted on attempt number `attempts`
+    Replaced { tx: Transaction, attempts: u32 },
+    /// All attempts failed. Holds the last error encountered
+    GaveUp { attempts: u32, error: RpcError },
+}
+
+impl SubmitOutcome {
+    /// The id of the accepted tx, if any
+    pub fn tx_id(&self) -> Option<TransactionId> {
+        match self {
+            SubmitOutcome::Accepted(tx) | SubmitOutcome::Replaced { tx, .. } => Some(tx.id()),
+            SubmitOutcome::GaveUp { .. } => None,
+        }
+    }
+}
+
+impl TransactionGenerator {
+    /// Builds the command tx over `utxo` (which should be reserved from `utxos`) and submits it. Failed submissions are
+    /// retried according to `policy`, where each retry refreshes the UTXO set and rebuilds the tx over newly reserved
+    /// UTXOs which can cover `fee` while leaving a change output which is not dust. When the funds are fragmented, the
+    /// tx aggregates several UTXOs and the fee is recalculated for the number of inputs (but never lowered below `fee`).
+    pub async fn submit_with_retry<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        utxos: &UtxoManager,
+        utxo: Utxo,
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+        policy: RetryPolicy,
+    ) -> SubmitOutcome {
+        self.submit_utxos_with_retry(kaspad, utxos, vec![utxo], recipient, cmd, fee, policy).await
+    }
+
+    /// Same as [`Self::submit_with_retry`], but the first attempt spends all of `utxos_to_spend` (e.g., as reserved by
+    /// [`Self::reserve_aggregated`]), where `fee` should be calculated for their number
+    pub(crate) async fn submit_utxos_with_retry<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        utxos: &UtxoManager,
+        utxos_to_spend: Vec<Utxo>,
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+        policy: RetryPolicy,
+    ) -> SubmitOutcome {
+        let mut utxos_to_spend = Some((utxos_to_spend, fee));
+        let mut backoff = policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match utxos_to_spend.take() {
+                Some((utxos_to_spend, fee)) => match self.build_command_transaction_from_utxos(&utxos_to_spend, recipient, cmd, fee) {
+                    // The manager resets its state from the node if the submission fails
+                    Some(tx) => match utxos.submit(kaspad, &tx).await {
+                        Ok(_) if attempts == 1 => return SubmitOutcome::Accepted(tx),
+                        Ok(_) => {
+                            info!("Tx {} replaced a rejected submission on attempt {}", tx.id(), attempts);
+                            return SubmitOutcome::Replaced { tx, attempts };
+                        }
+                        Err(err) => err,
+                    },
+                    None => {
+                        utxos_to_spend.into_iter().for_each(|utxo| utxos.release(utxo));
+                        RpcError::General(format!("the spent UTXOs cannot cover a fee of {} and a change output", fee))
+                    }
+                },
+                None => {
+                    // Nothing spendable was found on the previous round, so the node might be lagging behind or disconnected
+                    match utxos.reset(kaspad).await {
+                        Ok(()) => {
+                            RpcError::General(format!("no UTXO of {} can cover a fee of {} and a change output", utxos.address(), fee))
+                        }
+                        Err(err) => err,
+                    }
+                }
+            };
+            if attempts >= policy.max_attempts {
+                warn!("Giving up submitting command of episode {} after {} attempts: {}", cmd.episode_id(), attempts, error);
+                return SubmitOutcome::GaveUp { attempts, error };
+            }
+            warn!("Submission attempt {} failed: {}. Retrying in {:?}", attempts, error, backoff);
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff);
+            // Prefer a single UTXO, and fall back to aggregating fragmented funds
+            utxos_to_spend = match utxos.reserve_at_least(fee.saturating_add(MIN_OUTPUT_AMOUNT)) {
+                Some(utxo) => Some((vec![utxo], fee)),
+                None => self.reserve_aggregated(kaspad, utxos, recipient, cmd, fee).await,
+            };
+        }
+    }
+
+    /// Reserves several UTXOs which can cover the fee of a command tx spending all of them along with a change output
+    /// which is not dust. Since each input adds to the fee, the fee is recalculated for the number of reserved inputs,
+    /// and the reservation is repeated with the higher fee if these cannot cover it. Each repetition reserves more
+    /// inputs, up to the aggregation limit of the manager. Returns the UTXOs along with the fee to pay
+    pub(crate) async fn reserve_aggregated<G: Episode>(
+        &self,
+        kaspad: &impl RpcApi,
+        utxos: &UtxoManager,
+        recipient: &Address,
+        cmd: &EpisodeMessage<G>,
+        fee: u64,
+    ) -> Option<(Vec<Utxo>, u64)> {
+        let mut required_fee = fee;
+        loop {
+            let reserved = utxos.reserve_amount(required_fee.saturating_add(MIN_OUTPUT_AMOUNT))?;
+            let inputs_fee = match self.command_fee_for_inputs(kaspad, reserved.len(), recipient, cmd).await {
+                Ok(inputs_fee) => inputs_fee.max(fee),
+                Err(err) => {
+                    warn!("Failed calculating the fee of {} inputs: {}", reserved.len(), err);
+                    reserved.into_iter().for_each(|utxo| utxos.release(utxo));
+                    return None;
+                }
+            };
+            let total = reserved.iter().fold(0u64, |total, (_, entry)| total.saturating_add(entry.amount));
+            if total >= inputs_fee.saturating_add(MIN_OUTPUT_AMOUNT) {
+                return Some((reserved, inputs_fee));
+            }
+            reserved.into_iter().for_each(|utxo| utxos.release(utxo));
+            required_fee = inputs_fee;
+        }
+    }
+}
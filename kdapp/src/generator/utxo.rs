@@ -0,0 +1,292 @@
+//! UTXO tracking for a single signer address. Takes care of fetching UTXOs from the node, reserving them for
+//! txs under construction (so that concurrent episodes never pick the same outpoint), chaining the outputs of
+//! submitted txs before they are accepted, and recovering from failed submissions by refetching node state.
+
+use itertools::Itertools;
+use kaspa_addresses::Address;
+use kaspa_consensus_core::{
+    constants::TX_VERSION,
+    sign::sign,
+    subnets::SUBNETWORK_ID_NATIVE,
+    tx::{
+        MutableTransaction, ScriptPublicKey, Transaction, TransactionId, TransactionInput, TransactionOutpoint, TransactionOutput,
+        UtxoEntry,
+    },
+};
+use kaspa_rpc_core::{api::rpc::RpcApi, RpcResult};
+use kaspa_txscript::pay_to_address_script;
+use log::{debug, warn};
+use secp256k1::Keypair;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::cache::ChainCache;
+
+pub type Utxo = (TransactionOutpoint, UtxoEntry);
+
+/// The maximal number of UTXOs aggregated by [`UtxoManager::reserve_amount`], keeping the tx mass well within standard limits
+pub const MAX_AGGREGATED_INPUTS: usize = 50;
+
+#[derive(Default)]
+struct UtxoState {
+    /// Spendable UTXOs in order of preference (chained unconfirmed outputs first)
+    available: VecDeque<Utxo>,
+    /// UTXOs handed out for building txs which were not submitted or released yet
+    reserved: HashSet<TransactionOutpoint>,
+    /// Outpoints spent by submitted txs which the node might still report as unspent
+    spent: HashSet<TransactionOutpoint>,
+    /// Outputs of submitted txs which the node might not report yet
+    unconfirmed: HashMap<TransactionOutpoint, UtxoEntry>,
+}
+
+/// Tracks the UTXOs of a single address. All methods take `&self`, so a manager can be shared (e.g., via `Arc`)
+/// between concurrently running episodes using the same signer.
+pub struct UtxoManager {
+    address: Address,
+    script_public_key: ScriptPublicKey,
+    state: Mutex<UtxoState>,
+    cache: Option<Arc<ChainCache>>,
+}
+
+impl UtxoManager {
+    pub fn new(address: Address) -> Self {
+        let script_public_key = pay_to_address_script(&address);
+        Self { address, script_public_key, state: Default::default(), cache: None }
+    }
+
+    /// Fetches the address UTXOs through a shared chain data cache. Resets always bypass the cache
+    pub fn with_cache(mut self, cache: Arc<ChainCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Refetches the address UTXOs from the node while keeping track of locally known pending spends and outputs
+    pub async fn refresh(&self, kaspad: &impl RpcApi) -> RpcResult<()> {
+        self.refetch(kaspad, false).await
+    }
+
+    /// Refetches the address UTXOs from the node and drops all locally tracked pending state. Should be used when
+    /// a submission fails, since the failure might indicate that the local view diverged from the node
+    pub async fn reset(&self, kaspad: &impl RpcApi) -> RpcResult<()> {
+        self.refetch(kaspad, true).await
+    }
+
+    async fn refetch(&self, kaspad: &impl RpcApi, drop_pending: bool) -> RpcResult<()> {
+        let entries = match &self.cache {
+            Some(cache) => {
+                if drop_pending {
+                    cache.invalidate_address(&self.address);
+                }
+                cache.get_utxos_by_address(kaspad, &self.address).await?
+            }
+            None => kaspad.get_utxos_by_addresses(vec![self.address.clone()]).await?,
+        };
+        let node_utxos: Vec<Utxo> =
+            entries.into_iter().map(|entry| (TransactionOutpoint::from(entry.outpoint), UtxoEntry::from(entry.utxo_entry))).collect();
+        let node_outpoints: HashSet<TransactionOutpoint> = node_utxos.iter().map(|(op, _)| *op).collect();
+
+        let mut state = self.state.lock().unwrap();
+        if drop_pending {
+            state.spent.clear();
+            state.unconfirmed.clear();
+        } else {
+            // Pending spends no longer reported by the node were accepted, and unconfirmed outputs reported by the node are now confirmed
+            state.spent.retain(|op| node_outpoints.contains(op));
+            state.unconfirmed.retain(|op, _| !node_outpoints.contains(op));
+        }
+
+        let state = &mut *state;
+        let unconfirmed = state.unconfirmed.iter().map(|(op, entry)| (*op, entry.clone()));
+        state.available =
+            unconfirmed.chain(node_utxos).filter(|(op, _)| !state.spent.contains(op) && !state.reserved.contains(op)).collect();
+        debug!("Refreshed UTXOs of {}: {} available, {} reserved", self.address, state.available.len(), state.reserved.len());
+        Ok(())
+    }
+
+    /// Reserves the next available UTXO
+    pub fn reserve(&self) -> Option<Utxo> {
+        let mut state = self.state.lock().unwrap();
+        let utxo = state.available.pop_front()?;
+        state.reserved.insert(utxo.0);
+        Some(utxo)
+    }
+
+    /// Reserves the least preferred available UTXO. Useful for lowering the chance of collisions with another
+    /// process spending from the same address, which is expected to use [`Self::reserve`]
+    pub fn reserve_last(&self) -> Option<Utxo> {
+        let mut state = self.state.lock().unwrap();
+        let utxo = state.available.pop_back()?;
+        state.reserved.insert(utxo.0);
+        Some(utxo)
+    }
+
+    /// Reserves the first available UTXO holding at least `amount` sompi
+    pub fn reserve_at_least(&self, amount: u64) -> Option<Utxo> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.available.iter().position(|(_, entry)| entry.amount >= amount)?;
+        let utxo = state.available.remove(index).unwrap();
+        state.reserved.insert(utxo.0);
+        Some(utxo)
+    }
+
+    /// Reserves available UTXOs (largest first) until their sum reaches `amount`, aggregating fragmented funds into
+    /// at most [`MAX_AGGREGATED_INPUTS`] inputs. Nothing is reserved if the amount cannot be reached
+    pub fn reserve_amount(&self, amount: u64) -> Option<Vec<Utxo>> {
+        let mut state = self.state.lock().unwrap();
+        let mut selected = vec![];
+        let mut total = 0u64;
+        for i in (0..state.available.len()).sorted_by_key(|&i| std::cmp::Reverse(state.available[i].1.amount)) {
+            if total >= amount || selected.len() == MAX_AGGREGATED_INPUTS {
+                break;
+            }
+            total += state.available[i].1.amount;
+            selected.push(i);
+        }
+        if total < amount {
+            return None;
+        }
+        let utxos = selected.iter().map(|&i| state.available[i].clone()).collect_vec();
+        // Remove from the back so that the remaining indices stay valid
+        for i in selected.into_iter().sorted_unstable().rev() {
+            state.available.remove(i);
+        }
+        state.reserved.extend(utxos.iter().map(|(op, _)| *op));
+        Some(utxos)
+    }
+
+    /// Returns a reserved UTXO which ended up unused back to the available set
+    pub fn release(&self, utxo: Utxo) {
+        let mut state = self.state.lock().unwrap();
+        if state.reserved.remove(&utxo.0) {
+            state.available.push_back(utxo);
+        }
+    }
+
+    /// Records a submitted tx: its inputs are marked as spent and its outputs paying to the managed address
+    /// become available for chaining further txs before this one is accepted
+    pub fn chain(&self, tx: &Transaction) {
+        let mut state = self.state.lock().unwrap();
+        for input in tx.inputs.iter() {
+            state.reserved.remove(&input.previous_outpoint);
+            state.spent.insert(input.previous_outpoint);
+        }
+        let tx_id = tx.id();
+        for (index, output) in tx.outputs.iter().enumerate().rev() {
+            if output.script_public_key == self.script_public_key {
+                let outpoint = TransactionOutpoint::new(tx_id, index as u32);
+                let entry = UtxoEntry::new(output.value, output.script_public_key.clone(), 0, false);
+                state.unconfirmed.insert(outpoint, entry.clone());
+                state.available.push_front((outpoint, entry));
+            }
+        }
+    }
+
+    /// Submits a tx built from reserved UTXOs. On success the tx is chained (see [`Self::chain`]), otherwise
+    /// the local state is reset from the node
+    pub async fn submit(&self, kaspad: &impl RpcApi, tx: &Transaction) -> RpcResult<TransactionId> {
+        match kaspad.submit_transaction(tx.into(), false).await {
+            Ok(tx_id) => {
+                self.chain(tx);
+                Ok(tx_id)
+            }
+            Err(err) => {
+                warn!("Submitting tx {} failed: {}. Refreshing UTXOs", tx.id(), err);
+                for input in tx.inputs.iter() {
+                    self.state.lock().unwrap().reserved.remove(&input.previous_outpoint);
+                }
+                if let Err(refresh_err) = self.reset(kaspad).await {
+                    warn!("UTXO refresh failed: {}", refresh_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Splits the largest available UTXO into `parts` equal outputs to the managed address, so that several
+    /// episodes can submit txs concurrently without waiting for each other's outputs. Returns the submitted split tx id
+    pub async fn split(&self, kaspad: &impl RpcApi, signer: Keypair, parts: u64, fee: u64) -> RpcResult<Option<TransactionId>> {
+        let utxo = {
+            let mut state = self.state.lock().unwrap();
+            let Some(index) = state.available.iter().position_max_by_key(|(_, entry)| entry.amount) else {
+                return Ok(None);
+            };
+            let utxo = state.available.remove(index).unwrap();
+            state.reserved.insert(utxo.0);
+            utxo
+        };
+        let Some(value) = utxo.1.amount.checked_sub(fee).map(|amount| amount / parts.max(1)).filter(|&value| value > 0) else {
+            self.release(utxo);
+            return Ok(None);
+        };
+        let input = TransactionInput { previous_outpoint: utxo.0, signature_script: vec![], sequence: 0, sig_op_count: 1 };
+        let outputs =
+            (0..parts.max(1)).map(|_| TransactionOutput { value, script_public_key: self.script_public_key.clone() }).collect_vec();
+        let mut unsigned_tx = Transaction::new_non_finalized(TX_VERSION, vec![input], outputs, 0, SUBNETWORK_ID_NATIVE, 0, vec![]);
+        unsigned_tx.finalize();
+        let signed_tx = sign(MutableTransaction::with_entries(unsigned_tx, vec![utxo.1]), signer).tx;
+        self.submit(kaspad, &signed_tx).await.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kaspa_addresses::{Prefix, Version};
+
+    #[test]
+    fn test_utxo_reserve_and_chain() {
+        let address = Address::new(Prefix::Testnet, Version::PubKey, &[1u8; 32]);
+        let manager = UtxoManager::new(address.clone());
+        let spk = pay_to_address_script(&address);
+        manager.state.lock().unwrap().available = (0..2u64)
+            .map(|i| (TransactionOutpoint::new(i.into(), 0), UtxoEntry::new(1000 * (i + 1), spk.clone(), 0, false)))
+            .collect();
+
+        let first = manager.reserve().unwrap();
+        assert_eq!(manager.reserve_at_least(1500).unwrap().1.amount, 2000);
+        assert!(manager.reserve().is_none());
+
+        let input = TransactionInput { previous_outpoint: first.0, signature_script: vec![], sequence: 0, sig_op_count: 1 };
+        let mut tx = Transaction::new_non_finalized(
+            TX_VERSION,
+            vec![input],
+            vec![TransactionOutput { value: 900, script_public_key: spk }],
+            0,
+            SUBNETWORK_ID_NATIVE,
+            0,
+            vec![],
+        );
+        tx.finalize();
+        manager.chain(&tx);
+
+        // The chained output is available while the spent outpoint is not
+        let chained = manager.reserve().unwrap();
+        assert_eq!(chained.0, TransactionOutpoint::new(tx.id(), 0));
+        assert!(manager.state.lock().unwrap().spent.contains(&first.0));
+
+        manager.release(chained.clone());
+        assert_eq!(manager.reserve(), Some(chained));
+    }
+
+    #[test]
+    fn test_utxo_reserve_amount() {
+        let address = Address::new(Prefix::Testnet, Version::PubKey, &[1u8; 32]);
+        let manager = UtxoManager::new(address.clone());
+        let spk = pay_to_address_script(&address);
+        manager.state.lock().unwrap().available = [300u64, 100, 500, 200]
+            .into_iter()
+            .enumerate()
+            .map(|(i, amount)| (TransactionOutpoint::new((i as u64).into(), 0), UtxoEntry::new(amount, spk.clone(), 0, false)))
+            .collect();
+
+        assert!(manager.reserve_amount(1200).is_none());
+        let utxos = manager.reserve_amount(700).unwrap();
+        assert_eq!(utxos.iter().map(|(_, entry)| entry.amount).collect_vec(), vec![500, 300]);
+        assert_eq!(manager.reserve_amount(300).unwrap().len(), 2);
+        assert!(manager.reserve().is_none());
+    }
+}
@@ -1,5 +1,30 @@
+//! Kaspa dapps infrastructure: an engine running episodes (the dapp state machines) over commands carried by Kaspa tx
+//! payloads, along with the tooling for generating such txs and for listening to their acceptance. The stable API is
+//! re-exported by [`prelude`].
+
+// Allows the derive macros, which refer to `::kdapp`, to be used within the crate
+extern crate self as kdapp;
+
+pub mod anchor;
+#[cfg(feature = "rpc")]
+pub mod cache;
+pub mod economics;
 pub mod engine;
 pub mod episode;
+#[cfg(feature = "rpc")]
 pub mod generator;
+pub mod oracle;
 pub mod pki;
+pub mod prelude;
+#[cfg(feature = "rpc")]
 pub mod proxy;
+pub mod replication;
+pub mod schema;
+pub mod scratch;
+#[cfg(feature = "service")]
+pub mod service;
+pub mod shadow;
+pub mod storage;
+pub mod testing;
+pub mod tracker;
+pub mod wallet;
@@ -0,0 +1,230 @@
+//! Oracle attestations, allowing episodes to consume external data (e.g., price feeds or event outcomes) with
+//! authenticity checks. An oracle signs [`Attestation`]s of the values of its feeds off-chain, and any participant can
+//! submit them to an episode via an [`OracleCommand`], which episodes embed in their command type. The episode keeps
+//! an [`OracleFeeds`] holding the oracles it trusts (usually fixed on initialization), which verifies submitted
+//! attestations and tracks the latest value of every feed.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use secp256k1::{Message, SecretKey};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::episode::PayloadMetadata;
+use crate::pki::{sign_message_with, to_message, verify_signature, PubKey, Sig, SigScheme};
+use crate::schema::{Fields, Schema, TypeSchema};
+
+/// Domain separation of the message signed by oracles
+const ATTESTATION_DOMAIN: &str = "kdapp/oracle-attestation";
+
+/// The time (in milliseconds) an attestation may be ahead of the accepting block, accounting for clock drift
+/// between the oracle and the network
+pub const FUTURE_TOLERANCE: u64 = 60_000;
+
+/// Identifies a feed of an oracle, e.g., `"KAS/USD"` or `"match-1234/winner"`
+pub type FeedId = String;
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize, TypeSchema)]
+pub struct Attestation {
+    pub oracle: PubKey,
+    pub feed_id: FeedId,
+    /// The observed value. Prices are fixed point numbers (at a precision defined by the feed), and event outcomes
+    /// are indices into the possible outcomes
+    pub value: i64,
+    /// The time of the observation, in milliseconds since the unix epoch (as block timestamps)
+    pub timestamp: u64,
+}
+
+impl Attestation {
+    /// Signs the attestation with the oracle key (using Schnorr)
+    pub fn sign(self, sk: &SecretKey) -> SignedAttestation {
+        let sig = sign_message_with(SigScheme::Schnorr, sk, &self.message());
+        SignedAttestation { attestation: self, sig }
+    }
+
+    fn message(&self) -> Message {
+        to_message(&(ATTESTATION_DOMAIN, self))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedAttestation {
+    pub attestation: Attestation,
+    pub sig: Sig,
+}
+
+impl SignedAttestation {
+    /// Verifies the signature of the oracle. Whether the oracle is trusted is up to the caller (see [`OracleFeeds`])
+    pub fn verify(&self) -> bool {
+        verify_signature(&self.attestation.oracle, &self.attestation.message(), &self.sig)
+    }
+}
+
+// Attestations are embedded in commands, so the signature (which extends to the end of its input) is length prefixed
+impl BorshSerialize for SignedAttestation {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.attestation.serialize(writer)?;
+        borsh::to_vec(&self.sig)?.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for SignedAttestation {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let attestation = Attestation::deserialize_reader(reader)?;
+        let sig = Sig::try_from_slice(&Vec::<u8>::deserialize_reader(reader)?)?;
+        Ok(Self { attestation, sig })
+    }
+}
+
+impl TypeSchema for SignedAttestation {
+    fn schema() -> Schema {
+        let fields = vec![("attestation".to_string(), Attestation::schema()), ("sig".to_string(), Vec::<u8>::schema())];
+        Schema::Struct { name: "SignedAttestation".to_string(), fields: Fields::Named(fields) }
+    }
+}
+
+/// Oracle commands which episodes embed in their command type, e.g., `enum Command { Oracle(OracleCommand), .. }`,
+/// and execute via [`OracleFeeds::execute`]
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize, TypeSchema)]
+pub enum OracleCommand {
+    SubmitAttestation(SignedAttestation),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum OracleError {
+    #[error("oracle {0} is not trusted.")]
+    UntrustedOracle(PubKey),
+
+    #[error("invalid attestation signature.")]
+    InvalidSignature,
+
+    #[error("attestation of {timestamp} is too old for a block of {accepting_time}.")]
+    Stale { timestamp: u64, accepting_time: u64 },
+
+    #[error("attestation of {timestamp} is ahead of a block of {accepting_time}.")]
+    Premature { timestamp: u64, accepting_time: u64 },
+
+    #[error("attestation does not supersede the latest value of feed {0}.")]
+    Superseded(FeedId),
+}
+
+/// Restores the previous value of a feed (see [`OracleFeeds::rollback`])
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct OracleRollback {
+    oracle: PubKey,
+    feed_id: FeedId,
+    prev: Option<Attestation>,
+}
+
+/// The oracles trusted by an episode, and the latest attested value of each of their feeds
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct OracleFeeds {
+    trusted: Vec<PubKey>,
+    /// The maximal age of an attestation relative to its accepting block, in milliseconds
+    max_age: u64,
+    latest: BTreeMap<(PubKey, FeedId), Attestation>,
+}
+
+impl OracleFeeds {
+    pub fn new(trusted: Vec<PubKey>, max_age: u64) -> Self {
+        Self { trusted, max_age, latest: BTreeMap::new() }
+    }
+
+    pub fn trusted(&self) -> &[PubKey] {
+        &self.trusted
+    }
+
+    /// The latest accepted attestation of the feed
+    pub fn latest(&self, oracle: &PubKey, feed_id: &str) -> Option<&Attestation> {
+        self.latest.get(&(*oracle, feed_id.to_string()))
+    }
+
+    /// Verifies that the attestation is signed by a trusted oracle, and is fresh relative to the accepting block
+    pub fn verify(&self, signed: &SignedAttestation, metadata: &PayloadMetadata) -> Result<(), OracleError> {
+        let attestation = &signed.attestation;
+        if !self.trusted.contains(&attestation.oracle) {
+            return Err(OracleError::UntrustedOracle(attestation.oracle));
+        }
+        if !signed.verify() {
+            return Err(OracleError::InvalidSignature);
+        }
+        let (timestamp, accepting_time) = (attestation.timestamp, metadata.accepting_time);
+        if timestamp.saturating_add(self.max_age) < accepting_time {
+            return Err(OracleError::Stale { timestamp, accepting_time });
+        }
+        if timestamp > accepting_time.saturating_add(FUTURE_TOLERANCE) {
+            return Err(OracleError::Premature { timestamp, accepting_time });
+        }
+        Ok(())
+    }
+
+    /// Verifies the attestation and records it as the latest value of its feed. Attestations must be strictly newer
+    /// than the latest value, so that old values cannot be replayed
+    pub fn submit(&mut self, signed: &SignedAttestation, metadata: &PayloadMetadata) -> Result<OracleRollback, OracleError> {
+        self.verify(signed, metadata)?;
+        let attestation = &signed.attestation;
+        let key = (attestation.oracle, attestation.feed_id.clone());
+        if self.latest.get(&key).is_some_and(|latest| latest.timestamp >= attestation.timestamp) {
+            return Err(OracleError::Superseded(attestation.feed_id.clone()));
+        }
+        let prev = self.latest.insert(key, attestation.clone());
+        Ok(OracleRollback { oracle: attestation.oracle, feed_id: attestation.feed_id.clone(), prev })
+    }
+
+    pub fn execute(&mut self, cmd: &OracleCommand, metadata: &PayloadMetadata) -> Result<OracleRollback, OracleError> {
+        match cmd {
+            OracleCommand::SubmitAttestation(signed) => self.submit(signed, metadata),
+        }
+    }
+
+    pub fn rollback(&mut self, rollback: OracleRollback) {
+        let key = (rollback.oracle, rollback.feed_id);
+        match rollback.prev {
+            Some(prev) => self.latest.insert(key, prev),
+            None => self.latest.remove(&key),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pki::generate_keypair;
+
+    fn metadata(accepting_time: u64) -> PayloadMetadata {
+        PayloadMetadata { accepting_time, ..PayloadMetadata::for_test(0) }
+    }
+
+    #[test]
+    fn test_oracle_feeds() {
+        let (sk, oracle) = generate_keypair();
+        let (rogue_sk, rogue) = generate_keypair();
+        let attest = |value, timestamp| Attestation { oracle, feed_id: "KAS/USD".to_string(), value, timestamp };
+        let mut feeds = OracleFeeds::new(vec![oracle], 10_000);
+
+        let first = OracleCommand::SubmitAttestation(attest(100, 50_000).sign(&sk));
+        let encoded = borsh::to_vec(&first).unwrap();
+        assert_eq!(borsh::from_slice::<OracleCommand>(&encoded).unwrap(), first);
+        let rollback = feeds.execute(&first, &metadata(55_000)).unwrap();
+        assert_eq!(feeds.latest(&oracle, "KAS/USD").unwrap().value, 100);
+
+        // Replays and older values are rejected, as are stale and premature ones
+        assert_eq!(feeds.execute(&first, &metadata(55_000)), Err(OracleError::Superseded("KAS/USD".to_string())));
+        assert!(matches!(feeds.submit(&attest(101, 50_000).sign(&sk), &metadata(70_000)), Err(OracleError::Stale { .. })));
+        assert!(matches!(feeds.submit(&attest(101, 200_000).sign(&sk), &metadata(70_000)), Err(OracleError::Premature { .. })));
+
+        let rogue_attestation = Attestation { oracle: rogue, ..attest(1, 52_000) };
+        assert_eq!(
+            feeds.submit(&rogue_attestation.clone().sign(&rogue_sk), &metadata(55_000)),
+            Err(OracleError::UntrustedOracle(rogue))
+        );
+        let forged = SignedAttestation { attestation: attest(1, 52_000), sig: rogue_attestation.sign(&rogue_sk).sig };
+        assert_eq!(feeds.submit(&forged, &metadata(55_000)), Err(OracleError::InvalidSignature));
+
+        let second = feeds.submit(&attest(102, 52_000).sign(&sk), &metadata(55_000)).unwrap();
+        assert_eq!(feeds.latest(&oracle, "KAS/USD").unwrap().value, 102);
+        feeds.rollback(second);
+        assert_eq!(feeds.latest(&oracle, "KAS/USD").unwrap().value, 100);
+        feeds.rollback(rollback);
+        assert_eq!(feeds.latest(&oracle, "KAS/USD"), None);
+    }
+}
@@ -1,12 +1,25 @@
 //! Public Key Infrastructure (PKI) methods and helpers.
+//!
+//! Commands can be signed with either ECDSA or Schnorr (BIP-340) signatures. Schnorr is the scheme used by Kaspa
+//! itself, so a single keypair and signature scheme can serve both the kaspa address and episode authorization.
+//! ECDSA is kept as the default for compatibility with previously signed commands.
 
 use borsh::{BorshDeserialize, BorshSerialize};
+use kaspa_addresses::{Address, Prefix, Version};
 use rand::rngs::OsRng;
-use secp256k1::ecdsa::Signature;
-use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::{ecdsa, schnorr};
+use secp256k1::{Keypair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
 use sha2::{Digest, Sha256};
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Marks Schnorr signatures in serialized form. DER encoded ECDSA signatures always start with 0x30
+const SCHNORR_SIG_MARKER: u8 = 0x01;
+
+/// Domain separation of the message signed by address bindings
+const ADDRESS_BINDING_DOMAIN: &str = "kdapp/address-binding";
+
+pub mod musig;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct PubKey(pub PublicKey);
 
 impl std::fmt::Debug for PubKey {
@@ -21,8 +34,35 @@ impl std::fmt::Display for PubKey {
     }
 }
 
+impl PubKey {
+    /// The kaspa (Schnorr pay-to-pubkey) address of this key
+    pub fn to_address(&self, prefix: Prefix) -> Address {
+        Address::new(prefix, Version::PubKey, &self.0.x_only_public_key().0.serialize())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SigScheme {
+    #[default]
+    Ecdsa,
+    Schnorr,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Sig(pub Signature);
+pub enum Sig {
+    Ecdsa(ecdsa::Signature),
+    Schnorr(schnorr::Signature),
+}
+
+impl Sig {
+    pub fn scheme(&self) -> SigScheme {
+        match self {
+            Sig::Ecdsa(_) => SigScheme::Ecdsa,
+            Sig::Schnorr(_) => SigScheme::Schnorr,
+        }
+    }
+}
+
 impl BorshSerialize for PubKey {
     fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
         writer.write_all(&self.0.serialize())
@@ -41,7 +81,14 @@ impl BorshDeserialize for PubKey {
 
 impl BorshSerialize for Sig {
     fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        writer.write_all(&self.0.serialize_der())
+        match self {
+            // Kept as plain DER for compatibility with previously signed commands
+            Sig::Ecdsa(sig) => writer.write_all(&sig.serialize_der()),
+            Sig::Schnorr(sig) => {
+                writer.write_all(&[SCHNORR_SIG_MARKER])?;
+                writer.write_all(sig.as_ref())
+            }
+        }
     }
 }
 
@@ -49,8 +96,99 @@ impl BorshDeserialize for Sig {
     fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
         let mut buf = Vec::new();
         reader.read_to_end(&mut buf)?;
-        let sig = Signature::from_der(&buf).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid signature"))?;
-        Ok(Sig(sig))
+        let invalid = |_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid signature");
+        match buf.split_first() {
+            Some((&SCHNORR_SIG_MARKER, sig)) => Ok(Sig::Schnorr(schnorr::Signature::from_slice(sig).map_err(invalid)?)),
+            _ => Ok(Sig::Ecdsa(ecdsa::Signature::from_der(&buf).map_err(invalid)?)),
+        }
+    }
+}
+
+/// A set of signatures by different keys over the same message. Since a serialized [`Sig`] extends to the end of
+/// its input, each signature is serialized with a length prefix
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MultiSig(pub Vec<(PubKey, Sig)>);
+
+impl BorshSerialize for MultiSig {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        (self.0.len() as u32).serialize(writer)?;
+        for (pubkey, sig) in self.0.iter() {
+            pubkey.serialize(writer)?;
+            borsh::to_vec(sig)?.serialize(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl BorshDeserialize for MultiSig {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let len = u32::deserialize_reader(reader)?;
+        let mut sigs = Vec::new();
+        for _ in 0..len {
+            let pubkey = PubKey::deserialize_reader(reader)?;
+            let sig: Sig = borsh::from_slice(&Vec::<u8>::deserialize_reader(reader)?)?;
+            sigs.push((pubkey, sig));
+        }
+        Ok(MultiSig(sigs))
+    }
+}
+
+/// A proof that the holder of the episode key `pubkey` also controls the kaspa (Schnorr pay-to-pubkey) `address`. Both
+/// keys sign the same canonical message binding the two together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressBinding {
+    pub address: Address,
+    pub pubkey: PubKey,
+    pub address_sig: schnorr::Signature,
+    pub pubkey_sig: Sig,
+}
+
+impl AddressBinding {
+    /// Creates the binding of the kaspa address of `funding` (under `prefix`) to the episode key `pk`
+    pub fn new(funding: &Keypair, prefix: Prefix, sk: &SecretKey, pk: PubKey) -> Self {
+        let address = Address::new(prefix, Version::PubKey, &funding.x_only_public_key().0.serialize());
+        let msg = Self::message(&address, &pk);
+        let address_sig = Secp256k1::signing_only().sign_schnorr(&msg, funding);
+        Self { address, pubkey: pk, address_sig, pubkey_sig: sign_message(sk, &msg) }
+    }
+
+    fn message(address: &Address, pubkey: &PubKey) -> Message {
+        to_message(&(ADDRESS_BINDING_DOMAIN, address, pubkey))
+    }
+
+    /// Verifies both signatures. Only pay-to-pubkey addresses can be bound
+    pub fn verify(&self) -> bool {
+        if self.address.version != Version::PubKey {
+            return false;
+        }
+        let Ok(address_key) = XOnlyPublicKey::from_slice(&self.address.payload) else {
+            return false;
+        };
+        let msg = Self::message(&self.address, &self.pubkey);
+        Secp256k1::verification_only().verify_schnorr(&self.address_sig, &msg, &address_key).is_ok()
+            && verify_signature(&self.pubkey, &msg, &self.pubkey_sig)
+    }
+}
+
+impl BorshSerialize for AddressBinding {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.address.serialize(writer)?;
+        self.pubkey.serialize(writer)?;
+        writer.write_all(self.address_sig.as_ref())?;
+        // Extends to the end of the input, hence serialized last
+        self.pubkey_sig.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for AddressBinding {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let address = Address::deserialize_reader(reader)?;
+        let pubkey = PubKey::deserialize_reader(reader)?;
+        let mut buf = [0u8; 64];
+        reader.read_exact(&mut buf)?;
+        let address_sig = schnorr::Signature::from_slice(&buf)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid signature"))?;
+        Ok(Self { address, pubkey, address_sig, pubkey_sig: Sig::deserialize_reader(reader)? })
     }
 }
 
@@ -71,13 +209,63 @@ pub fn to_message<T: BorshSerialize>(object: &T) -> Message {
     Message::from_digest_slice(&hash).expect("hash must be 32 bytes")
 }
 
-/// Sign a message using a `SecretKey`
+/// Sign a message using a `SecretKey` (with ECDSA)
 pub fn sign_message(secret_key: &SecretKey, message: &Message) -> Sig {
+    sign_message_with(SigScheme::Ecdsa, secret_key, message)
+}
+
+/// Sign a message using a `SecretKey` with the given signature scheme
+pub fn sign_message_with(scheme: SigScheme, secret_key: &SecretKey, message: &Message) -> Sig {
     let secp = Secp256k1::signing_only();
-    Sig(secp.sign_ecdsa(message, secret_key))
+    match scheme {
+        SigScheme::Ecdsa => Sig::Ecdsa(secp.sign_ecdsa(message, secret_key)),
+        SigScheme::Schnorr => Sig::Schnorr(secp.sign_schnorr(message, &Keypair::from_secret_key(&secp, secret_key))),
+    }
 }
 
+/// Verifies a signature of either scheme. Schnorr signatures are verified against the x-only form of the public key
 pub fn verify_signature(public_key: &PubKey, message: &Message, signature: &Sig) -> bool {
     let secp = Secp256k1::verification_only();
-    secp.verify_ecdsa(message, &signature.0, &public_key.0).is_ok()
+    match signature {
+        Sig::Ecdsa(sig) => secp.verify_ecdsa(message, sig, &public_key.0).is_ok(),
+        Sig::Schnorr(sig) => secp.verify_schnorr(sig, message, &public_key.0.x_only_public_key().0).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sig_schemes() {
+        let (sk, pk) = generate_keypair();
+        let (_, other_pk) = generate_keypair();
+        let msg = to_message(&"command");
+        for scheme in [SigScheme::Ecdsa, SigScheme::Schnorr] {
+            let sig = sign_message_with(scheme, &sk, &msg);
+            let decoded: Sig = borsh::from_slice(&borsh::to_vec(&sig).unwrap()).unwrap();
+            assert_eq!(decoded, sig);
+            assert_eq!(decoded.scheme(), scheme);
+            assert!(verify_signature(&pk, &msg, &decoded));
+            assert!(!verify_signature(&other_pk, &msg, &decoded));
+            assert!(!verify_signature(&pk, &to_message(&"other"), &decoded));
+        }
+    }
+
+    #[test]
+    fn test_address_binding() {
+        let funding = Keypair::new(secp256k1::SECP256K1, &mut OsRng);
+        let (sk, pk) = generate_keypair();
+        let (_, other_pk) = generate_keypair();
+        let binding = AddressBinding::new(&funding, Prefix::Testnet, &sk, pk);
+        assert!(binding.verify());
+        let decoded: AddressBinding = borsh::from_slice(&borsh::to_vec(&binding).unwrap()).unwrap();
+        assert_eq!(decoded, binding);
+
+        // Neither key can be swapped
+        assert!(!AddressBinding { pubkey: other_pk, ..binding.clone() }.verify());
+        let other_funding = Keypair::new(secp256k1::SECP256K1, &mut OsRng);
+        let other_address = Address::new(Prefix::Testnet, Version::PubKey, &other_funding.x_only_public_key().0.serialize());
+        assert!(!AddressBinding { address: other_address, ..binding }.verify());
+    }
 }
@@ -0,0 +1,281 @@
+//! MuSig2 key and signature aggregation (following BIP-327, without key tweaking). A set of signers aggregates their
+//! keys into a single key, and after exchanging public nonces each signer produces a partial signature. The partial
+//! signatures aggregate into a single BIP-340 Schnorr signature which verifies against the aggregated key, so an
+//! n-signer command carries a single 64 bytes signature.
+//!
+//! Signing is a two round protocol:
+//! 1. every signer calls [`generate_nonce`] and shares the public nonce;
+//! 2. every signer calls [`partial_sign`] with the [aggregated](aggregate_nonces) public nonces and shares the partial
+//!    signature, which are then combined with [`aggregate_partial_sigs`].
+//!
+//! As in BIP-327, the aggregated key depends on the order of the signers, so all parties must agree on the key order
+//! (e.g., the order in which signers are listed by the command).
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use rand::rngs::OsRng;
+use secp256k1::{schnorr, Message, PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use super::{PubKey, Sig};
+
+/// The secret part of a signing nonce. Reusing it for two signatures leaks the secret key, hence it is consumed on signing
+pub struct SecNonce([SecretKey; 2]);
+
+/// The public part of a signing nonce, shared with the other signers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubNonce([PublicKey; 2]);
+
+/// A partial signature produced by a single signer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialSig(SecretKey);
+
+impl BorshSerialize for PubNonce {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.0[0].serialize())?;
+        writer.write_all(&self.0[1].serialize())
+    }
+}
+
+impl BorshDeserialize for PubNonce {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let (PubKey(r1), PubKey(r2)) = (PubKey::deserialize_reader(reader)?, PubKey::deserialize_reader(reader)?);
+        Ok(PubNonce([r1, r2]))
+    }
+}
+
+impl BorshSerialize for PartialSig {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.0.secret_bytes())
+    }
+}
+
+impl BorshDeserialize for PartialSig {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut buf = [0u8; 32];
+        reader.read_exact(&mut buf)?;
+        let s = SecretKey::from_slice(&buf)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid partial signature"))?;
+        Ok(PartialSig(s))
+    }
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    for chunk in data {
+        hasher.update(chunk);
+    }
+    hasher.finalize().into()
+}
+
+/// Hashes into a scalar. Returns `None` for hashes exceeding the group order, which happens with negligible probability
+fn hash_to_scalar(tag: &str, data: &[&[u8]]) -> Option<Scalar> {
+    Scalar::from_be_bytes(tagged_hash(tag, data)).ok()
+}
+
+/// The aggregated key along with the aggregation coefficient of each signer
+struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    aggregated: PublicKey,
+}
+
+impl KeyAggContext {
+    fn new(signers: &[PubKey]) -> Option<Self> {
+        let keys: Vec<PublicKey> = signers.iter().map(|pk| pk.0).collect();
+        let serialized: Vec<[u8; 33]> = keys.iter().map(PublicKey::serialize).collect();
+        let list_hash = tagged_hash("KeyAgg list", &serialized.iter().map(|key| key.as_slice()).collect::<Vec<_>>());
+        // The first key which differs from the first key of the list has a coefficient of 1
+        let second = serialized.iter().find(|&key| Some(key) != serialized.first());
+        let coefficients: Vec<Scalar> = serialized
+            .iter()
+            .map(|key| if Some(key) == second { Some(Scalar::ONE) } else { hash_to_scalar("KeyAgg coefficient", &[&list_hash, key]) })
+            .collect::<Option<_>>()?;
+        let secp = Secp256k1::verification_only();
+        let weighted: Vec<PublicKey> =
+            keys.iter().zip(coefficients.iter()).map(|(key, a)| key.mul_tweak(&secp, a).ok()).collect::<Option<_>>()?;
+        let aggregated = PublicKey::combine_keys(&weighted.iter().collect::<Vec<_>>()).ok()?;
+        Some(Self { keys, coefficients, aggregated })
+    }
+
+    fn coefficient(&self, key: &PublicKey) -> Option<Scalar> {
+        self.keys.iter().position(|k| k == key).map(|i| self.coefficients[i])
+    }
+
+    fn has_even_y(&self) -> bool {
+        has_even_y(&self.aggregated)
+    }
+}
+
+fn has_even_y(key: &PublicKey) -> bool {
+    key.serialize()[0] == 0x02
+}
+
+/// The per-signature values shared by all signers
+struct SessionContext {
+    /// The nonce coefficient `b`
+    nonce_coefficient: Scalar,
+    /// The final nonce `R`
+    nonce: PublicKey,
+    /// The BIP-340 challenge `e`
+    challenge: Scalar,
+}
+
+impl SessionContext {
+    fn new(key_agg: &KeyAggContext, agg_nonce: &PubNonce, message: &Message) -> Option<Self> {
+        let secp = Secp256k1::verification_only();
+        let agg_key_x = key_agg.aggregated.x_only_public_key().0.serialize();
+        let [r1, r2] = agg_nonce.0;
+        let nonce_coefficient = hash_to_scalar("MuSig/noncecoef", &[&r1.serialize(), &r2.serialize(), &agg_key_x, message.as_ref()])?;
+        let nonce = r1.combine(&r2.mul_tweak(&secp, &nonce_coefficient).ok()?).ok()?;
+        let nonce_x = nonce.x_only_public_key().0.serialize();
+        let challenge = hash_to_scalar("BIP0340/challenge", &[&nonce_x, &agg_key_x, message.as_ref()])?;
+        Some(Self { nonce_coefficient, nonce, challenge })
+    }
+}
+
+/// Aggregates the keys of `signers` into the key against which their aggregated signatures verify. Returns `None` if
+/// `signers` is empty or (with negligible probability) if aggregation fails
+pub fn aggregate_keys(signers: &[PubKey]) -> Option<PubKey> {
+    KeyAggContext::new(signers).map(|ctx| PubKey(ctx.aggregated))
+}
+
+/// Generates a fresh signing nonce. Must be used for a single signature only
+pub fn generate_nonce() -> (SecNonce, PubNonce) {
+    let secp = Secp256k1::new();
+    let (k1, k2) = (SecretKey::new(&mut OsRng), SecretKey::new(&mut OsRng));
+    (SecNonce([k1, k2]), PubNonce([PublicKey::from_secret_key(&secp, &k1), PublicKey::from_secret_key(&secp, &k2)]))
+}
+
+/// Aggregates the public nonces of all signers
+pub fn aggregate_nonces(nonces: &[PubNonce]) -> Option<PubNonce> {
+    let r1: Vec<&PublicKey> = nonces.iter().map(|nonce| &nonce.0[0]).collect();
+    let r2: Vec<&PublicKey> = nonces.iter().map(|nonce| &nonce.0[1]).collect();
+    Some(PubNonce([PublicKey::combine_keys(&r1).ok()?, PublicKey::combine_keys(&r2).ok()?]))
+}
+
+/// Produces the partial signature of `secret_key` over `message` on behalf of `signers`. Returns `None` if the key of
+/// `secret_key` is not one of `signers`
+pub fn partial_sign(
+    secret_key: &SecretKey,
+    sec_nonce: SecNonce,
+    signers: &[PubKey],
+    agg_nonce: &PubNonce,
+    message: &Message,
+) -> Option<PartialSig> {
+    let secp = Secp256k1::signing_only();
+    let key_agg = KeyAggContext::new(signers)?;
+    let session = SessionContext::new(&key_agg, agg_nonce, message)?;
+    let coefficient = key_agg.coefficient(&PublicKey::from_secret_key(&secp, secret_key))?;
+
+    // The aggregated key and the final nonce are used in their even y form (as BIP-340 requires), so the secrets
+    // are negated accordingly
+    let [mut k1, mut k2] = sec_nonce.0;
+    if !has_even_y(&session.nonce) {
+        (k1, k2) = (k1.negate(), k2.negate());
+    }
+    let d = if key_agg.has_even_y() { *secret_key } else { secret_key.negate() };
+
+    // s = k1 + b * k2 + e * a * d
+    let nonce_part = k2.mul_tweak(&session.nonce_coefficient).ok()?.add_tweak(&Scalar::from(k1)).ok()?;
+    let key_part = d.mul_tweak(&coefficient).ok()?.mul_tweak(&session.challenge).ok()?;
+    Some(PartialSig(nonce_part.add_tweak(&Scalar::from(key_part)).ok()?))
+}
+
+/// Combines the partial signatures of all signers into a Schnorr signature verifying against the
+/// [aggregated key](aggregate_keys) of `signers`
+pub fn aggregate_partial_sigs(signers: &[PubKey], agg_nonce: &PubNonce, message: &Message, partials: &[PartialSig]) -> Option<Sig> {
+    let key_agg = KeyAggContext::new(signers)?;
+    let session = SessionContext::new(&key_agg, agg_nonce, message)?;
+    let (first, rest) = partials.split_first()?;
+    let s = rest.iter().try_fold(first.0, |s, partial| s.add_tweak(&Scalar::from(partial.0)).ok())?;
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&session.nonce.x_only_public_key().0.serialize());
+    bytes[32..].copy_from_slice(&s.secret_bytes());
+    schnorr::Signature::from_slice(&bytes).ok().map(Sig::Schnorr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pki::{generate_keypair, to_message, verify_signature};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_musig2() {
+        let keys: Vec<_> = (0..5).map(|_| generate_keypair()).collect();
+        let signers: Vec<PubKey> = keys.iter().map(|&(_, pk)| pk).collect();
+        let msg = to_message(&"command");
+
+        let agg_key = aggregate_keys(&signers).unwrap();
+        let reversed: Vec<PubKey> = signers.iter().rev().copied().collect();
+        assert_ne!(aggregate_keys(&reversed), Some(agg_key));
+
+        // Round 1: nonce exchange (nonces are shared in serialized form)
+        let (sec_nonces, pub_nonces): (Vec<_>, Vec<_>) = keys.iter().map(|_| generate_nonce()).unzip();
+        let pub_nonces: Vec<PubNonce> =
+            pub_nonces.iter().map(|nonce| borsh::from_slice(&borsh::to_vec(nonce).unwrap()).unwrap()).collect();
+        let agg_nonce = aggregate_nonces(&pub_nonces).unwrap();
+
+        // Round 2: partial signatures
+        let partials: Vec<PartialSig> = keys
+            .iter()
+            .zip(sec_nonces)
+            .map(|((sk, _), sec_nonce)| partial_sign(sk, sec_nonce, &signers, &agg_nonce, &msg).unwrap())
+            .collect();
+        let sig = aggregate_partial_sigs(&signers, &agg_nonce, &msg, &partials).unwrap();
+        assert!(verify_signature(&agg_key, &msg, &sig));
+        assert!(!verify_signature(&agg_key, &to_message(&"other"), &sig));
+
+        // A missing partial signature does not verify
+        let partial = aggregate_partial_sigs(&signers, &agg_nonce, &msg, &partials[1..]).unwrap();
+        assert!(!verify_signature(&agg_key, &msg, &partial));
+        // Neither does a signature against a different signer set
+        assert!(!verify_signature(&aggregate_keys(&signers[1..]).unwrap(), &msg, &sig));
+
+        let (outsider, _) = generate_keypair();
+        assert!(partial_sign(&outsider, generate_nonce().0, &signers, &agg_nonce, &msg).is_none());
+    }
+
+    #[test]
+    fn test_key_agg_vectors() {
+        // The key aggregation vectors of BIP-327
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .map(|key| PubKey(PublicKey::from_str(key).unwrap()));
+        let vectors: [(&[usize], &str); 4] = [
+            (&[0, 1, 2], "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"),
+            (&[2, 1, 0], "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"),
+            (&[0, 0, 0], "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"),
+            (&[0, 0, 1, 1], "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E"),
+        ];
+        for (indices, expected) in vectors {
+            let signers: Vec<PubKey> = indices.iter().map(|&i| keys[i]).collect();
+            let agg_key = aggregate_keys(&signers).unwrap();
+            assert_eq!(agg_key.0.x_only_public_key().0.to_string(), expected.to_lowercase());
+        }
+    }
+
+    #[test]
+    fn test_musig2_duplicate_signers() {
+        // Signing follows BIP-327 for any key list, including one with a duplicate key whose signer signs twice
+        let ((sk1, pk1), (sk2, pk2)) = (generate_keypair(), generate_keypair());
+        let (keys, signers) = ([sk1, sk1, sk2], vec![pk1, pk1, pk2]);
+        let msg = to_message(&"command");
+        let (sec_nonces, pub_nonces): (Vec<_>, Vec<_>) = keys.iter().map(|_| generate_nonce()).unzip();
+        let agg_nonce = aggregate_nonces(&pub_nonces).unwrap();
+        let partials: Vec<PartialSig> = keys
+            .iter()
+            .zip(sec_nonces)
+            .map(|(sk, sec_nonce)| partial_sign(sk, sec_nonce, &signers, &agg_nonce, &msg).unwrap())
+            .collect();
+        let Sig::Schnorr(sig) = aggregate_partial_sigs(&signers, &agg_nonce, &msg, &partials).unwrap() else { unreachable!() };
+        let agg_key = aggregate_keys(&signers).unwrap().0.x_only_public_key().0;
+        assert!(Secp256k1::verification_only().verify_schnorr(&sig, &msg, &agg_key).is_ok());
+    }
+}
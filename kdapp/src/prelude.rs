@@ -0,0 +1,89 @@
+//! The stable API surface of kdapp. Downstream episodes and apps are encouraged to import from here, e.g.,
+//! `use kdapp::prelude::*;`. Breaking changes to the items re-exported below follow semver, and removals are preceded
+//! by a release in which the item is `#[deprecated]`. Other public items of the crate modules may change more freely,
+//! and items hidden from the docs are internal. The generator and proxy items require the (default) `rpc` feature.
+
+pub use crate::engine::{command_message, DefaultEventHandler, Engine, EngineMsg, EpisodeMessage};
+pub use crate::episode::{
+    Episode, EpisodeCommand, EpisodeError, EpisodeEventHandler, EpisodeId, EpisodeProjection, MultisigPolicy, PayloadMetadata,
+};
+#[cfg(feature = "rpc")]
+pub use crate::generator::{
+    derive_pattern_from_prefix, FeePolicy, PatternType, PrefixType, SubmitOutcome, TransactionGenerator, UtxoManager,
+};
+pub use crate::pki::{generate_keypair, sign_message, to_message, verify_signature, PubKey, Sig};
+#[cfg(feature = "rpc")]
+pub use crate::proxy::{connect_client, run_listener, EngineMap};
+pub use crate::schema::TypeSchema;
+pub use crate::tracker::EpisodeTracker;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Message, SecretKey};
+    use std::sync::mpsc::Receiver;
+
+    // Implementing only the required items guards against new required trait items
+    #[derive(Clone, Debug)]
+    struct Noop;
+
+    impl Episode for Noop {
+        type Command = ();
+        type CommandRollback = ();
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Noop
+        }
+
+        fn execute(
+            &mut self,
+            _cmd: &(),
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<(), EpisodeError<std::fmt::Error>> {
+            Ok(())
+        }
+
+        fn rollback(&mut self, _rollback: ()) -> bool {
+            true
+        }
+    }
+
+    /// Fails to compile if the signatures of the stable API change
+    #[test]
+    fn test_api_stability() {
+        let _: fn(Receiver<EngineMsg>) -> Engine<Noop> = Engine::new;
+        let _: fn(&mut Engine<Noop>, Vec<DefaultEventHandler>) = Engine::start;
+        let _: fn(EpisodeId, u64, (), SecretKey, PubKey) -> EpisodeMessage<Noop> = EpisodeMessage::new_signed_command;
+        let _: fn(EpisodeId, u64, &u64) -> Message = command_message;
+        let _: fn() -> EpisodeTracker<Noop> = EpisodeTracker::new;
+        let _: fn() -> (SecretKey, PubKey) = generate_keypair;
+        let _: fn(&u64) -> Message = to_message;
+        let _: fn(&SecretKey, &Message) -> Sig = sign_message;
+        let _: fn(&PubKey, &Message, &Sig) -> bool = verify_signature;
+    }
+
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn test_rpc_api_stability() {
+        use kaspa_addresses::Address;
+        use kaspa_consensus_core::network::NetworkId;
+        use kaspa_wrpc_client::{error::Error, KaspaRpcClient};
+        use secp256k1::Keypair;
+        use std::future::Future;
+        use std::sync::{atomic::AtomicBool, Arc};
+
+        let _: fn(Keypair, PatternType, PrefixType) -> TransactionGenerator = TransactionGenerator::new;
+        let _: fn(PrefixType) -> PatternType = derive_pattern_from_prefix;
+        let _: fn(Address) -> UtxoManager = UtxoManager::new;
+
+        fn listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signal: Arc<AtomicBool>) -> impl Future<Output = ()> {
+            run_listener(kaspad, engines, exit_signal)
+        }
+        fn client(network_id: NetworkId, rpc_url: Option<String>) -> impl Future<Output = Result<KaspaRpcClient, Error>> {
+            connect_client(network_id, rpc_url)
+        }
+        let _ = (listener, client);
+    }
+}
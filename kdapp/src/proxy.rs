@@ -1,4 +1,4 @@
-//! Contains methods for creating a Kaspa wrpc client as well as listener logic for following
+//! Contains methods for creating a Kaspa wrpc (or grpc) client as well as listener logic for following
 //! accepted txs by id pattern and prefix and sending them to corresponding engines.
 
 use kaspa_consensus_core::{network::NetworkId, Hash};
@@ -9,20 +9,25 @@ use kaspa_wrpc_client::error::Error;
 use kaspa_wrpc_client::prelude::*;
 use kaspa_wrpc_client::{KaspaRpcClient, WrpcEncoding};
 
+#[cfg(feature = "grpc")]
+use kaspa_grpc_client::GrpcClient;
+
 use log::{debug, info, warn};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     mpsc::Sender,
-    Arc,
+    Arc, Mutex,
 };
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::{sleep_until, Instant};
 
-use crate::generator::{PatternType, PrefixType};
+use crate::generator::{derive_pattern_from_prefix, PatternType, PrefixType};
 use crate::{
     engine::EngineMsg as Msg,
+    episode::TxOutput,
     generator::{check_pattern, Payload},
 };
 
@@ -36,6 +41,78 @@ fn connect_options() -> ConnectOptions {
     }
 }
 
+/// A Kaspa node RPC client usable by the listener. Implemented for the wRPC client and, with the `grpc`
+/// feature enabled, for the gRPC client (which avoids the Borsh-over-websocket hop for co-located nodes).
+pub trait NodeClient {
+    /// The RPC API through which the node is queried
+    type Api: RpcApi;
+
+    fn api(&self) -> &Self::Api;
+
+    /// The URL of the connected node, if known
+    fn node_url(&self) -> Option<String>;
+
+    /// Whether the client currently holds an active connection
+    fn is_node_connected(&self) -> bool;
+}
+
+impl NodeClient for KaspaRpcClient {
+    type Api = Self;
+
+    fn api(&self) -> &Self {
+        self
+    }
+
+    fn node_url(&self) -> Option<String> {
+        self.url()
+    }
+
+    fn is_node_connected(&self) -> bool {
+        self.is_connected()
+    }
+}
+
+/// A gRPC client along with the URL it is connected to (see [`connect_grpc_client`])
+#[cfg(feature = "grpc")]
+pub struct GrpcNodeClient {
+    client: GrpcClient,
+    url: String,
+}
+
+#[cfg(feature = "grpc")]
+impl GrpcNodeClient {
+    pub fn client(&self) -> &GrpcClient {
+        &self.client
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl NodeClient for GrpcNodeClient {
+    type Api = GrpcClient;
+
+    fn api(&self) -> &GrpcClient {
+        &self.client
+    }
+
+    fn node_url(&self) -> Option<String> {
+        Some(self.url.clone())
+    }
+
+    fn is_node_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+}
+
+/// Returns whether the URL should be connected to via [`connect_grpc_client`] rather than [`connect_client`]
+#[cfg(feature = "grpc")]
+pub fn is_grpc_url(url: &str) -> bool {
+    url.starts_with("grpc://")
+}
+
 // Copied from https://github.com/supertypo/simply-kaspa-indexer/blob/main/kaspad/src/pool/manager.rs
 pub async fn connect_client(network_id: NetworkId, rpc_url: Option<String>) -> Result<KaspaRpcClient, Error> {
     let url = if let Some(url) = &rpc_url { url } else { &Resolver::default().get_url(WrpcEncoding::Borsh, network_id).await? };
@@ -47,6 +124,25 @@ pub async fn connect_client(network_id: NetworkId, rpc_url: Option<String>) -> R
         e
     })?;
 
+    verify_node(&client, network_id, url).await?;
+    Ok(client)
+}
+
+/// Connects to a (usually local) Kaspa node over gRPC, e.g., `grpc://localhost:16110`
+#[cfg(feature = "grpc")]
+pub async fn connect_grpc_client(network_id: NetworkId, rpc_url: String) -> Result<GrpcNodeClient, Error> {
+    debug!("Connecting to Kaspad {}", rpc_url);
+    let client = GrpcClient::connect(rpc_url.clone()).await.map_err(|e| {
+        warn!("Kaspad connection failed: {e}");
+        Error::Custom(e.to_string())
+    })?;
+
+    verify_node(&client, network_id, &rpc_url).await?;
+    Ok(GrpcNodeClient { client, url: rpc_url })
+}
+
+/// Verifies the node is on the expected network and is synced
+async fn verify_node(client: &impl RpcApi, network_id: NetworkId, url: &str) -> Result<(), Error> {
     let server_info = client.get_server_info().await?;
     let connected_network = format!(
         "{}{}",
@@ -64,40 +160,174 @@ pub async fn connect_client(network_id: NetworkId, rpc_url: Option<String>) -> R
         warn!("{err_msg}");
         Err(Error::Custom(err_msg))
     } else {
-        Ok(client)
+        Ok(())
     }
 }
 
+/// The DAA depth after which an accepting block is considered final (i.e., irreversible). Corresponds to the
+/// Kaspa finality duration of 12 hours at 10 BPS.
+pub const FINALITY_DEPTH: u64 = 432000;
+
+/// The DAA depths at which engines are notified of increasing confirmation of previously accepted blocks (prior to
+/// finality). A DAA depth is the DAA score elapsed since the accepting block rather than a count of blocks, so at 10 BPS
+/// these correspond to roughly 0.1, 0.6 and 10 seconds
+pub const CONFIRMATION_DAA_DEPTHS: [u64; 3] = [1, 6, 100];
+
 pub type EngineMap = HashMap<PrefixType, (PatternType, Sender<Msg>)>;
 
-pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signal: Arc<AtomicBool>) {
-    let info = kaspad.get_block_dag_info().await.unwrap();
-    let mut sink = info.sink;
+/// Builds an engine map entry whose pattern is derived from the prefix, thus guaranteed to match
+/// generators created with [`TransactionGenerator::from_prefix`](crate::generator::TransactionGenerator::from_prefix)
+pub fn engine_entry(prefix: PrefixType, sender: Sender<Msg>) -> (PrefixType, (PatternType, Sender<Msg>)) {
+    (prefix, (derive_pattern_from_prefix(prefix), sender))
+}
+
+/// A shared and cloneable routing table of engines, allowing to register and unregister engines while the listener is running
+/// (e.g., for starting to serve a new episode type without restarting the listener).
+#[derive(Clone, Default)]
+pub struct EngineRegistry(Arc<Mutex<EngineMap>>);
+
+impl EngineRegistry {
+    pub fn new(engines: EngineMap) -> Self {
+        Self(Arc::new(Mutex::new(engines)))
+    }
+
+    /// Registers an engine route. Returns the previous route registered for this prefix, if any
+    pub fn register(&self, prefix: PrefixType, pattern: PatternType, sender: Sender<Msg>) -> Option<(PatternType, Sender<Msg>)> {
+        self.0.lock().unwrap().insert(prefix, (pattern, sender))
+    }
+
+    /// Unregisters the engine route for this prefix. The returned sender can be used for signaling exit to the engine
+    pub fn unregister(&self, prefix: PrefixType) -> Option<(PatternType, Sender<Msg>)> {
+        self.0.lock().unwrap().remove(&prefix)
+    }
+
+    pub fn contains(&self, prefix: PrefixType) -> bool {
+        self.0.lock().unwrap().contains_key(&prefix)
+    }
+
+    /// Returns a point-in-time copy of the routing table
+    pub fn snapshot(&self) -> EngineMap {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl From<EngineMap> for EngineRegistry {
+    fn from(engines: EngineMap) -> Self {
+        Self::new(engines)
+    }
+}
+
+/// A lightweight snapshot of the listener sync progress. Published through a `watch` channel
+/// so that peers (e.g., HTTP servers) can report chain sync health without polling the node themselves.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerStatus {
+    /// The URL of the connected node (if known)
+    pub node_url: Option<String>,
+    /// Whether the underlying RPC client reports an active connection
+    pub connected: bool,
+    /// The last processed sink, i.e., the point from which the next VSPC query starts
+    pub sink: Option<Hash>,
+    /// The most recent accepting chain block processed by the listener
+    pub last_accepted_block: Option<Hash>,
+    /// The DAA score of the last accepting block which carried episode txs
+    pub last_accepted_daa: Option<u64>,
+    /// The time of the last status update
+    pub last_update: Option<Instant>,
+    /// Chain blocks per second as measured over the last polling interval
+    pub blocks_per_sec: f64,
+    /// Total number of txs which were matched and forwarded to engines
+    pub txs_matched: u64,
+    /// Total number of chain reorgs (i.e., polling rounds with removed chain blocks) observed
+    pub reorgs_seen: u64,
+}
+
+pub async fn run_listener(kaspad: impl NodeClient, engines: EngineMap, exit_signal: Arc<AtomicBool>) {
+    let (status, _) = watch::channel(ListenerStatus::default());
+    run_listener_with_status(kaspad, engines, exit_signal, status).await
+}
+
+/// Same as [`run_listener`] but additionally publishes a [`ListenerStatus`] through the provided `watch` sender
+/// after every polling round.
+pub async fn run_listener_with_status(
+    kaspad: impl NodeClient,
+    engines: EngineMap,
+    exit_signal: Arc<AtomicBool>,
+    status: watch::Sender<ListenerStatus>,
+) {
+    run_listener_from(kaspad, engines.into(), exit_signal, status, None).await
+}
+
+/// Same as [`run_listener_with_status`] but routes txs through an [`EngineRegistry`] which can be updated while the
+/// listener is running, and starts following the chain from `start_sink` (if provided) rather than from the current node sink.
+/// The latter is useful for resuming without gaps, e.g., when promoting a standby engine (see [`crate::replication`]).
+pub async fn run_listener_from(
+    kaspad: impl NodeClient,
+    registry: EngineRegistry,
+    exit_signal: Arc<AtomicBool>,
+    status: watch::Sender<ListenerStatus>,
+    start_sink: Option<Hash>,
+) {
+    let mut sink = match start_sink {
+        Some(sink) => sink,
+        None => kaspad.api().get_block_dag_info().await.unwrap().sink,
+    };
     let mut now = Instant::now();
+    // Accepting blocks which were reported to engines and are awaiting further confirmation. The i'th queue holds blocks awaiting
+    // the i'th confirmation depth (where the last one is the finality depth). Each queue is ordered by DAA score
+    let depths: Vec<u64> = CONFIRMATION_DAA_DEPTHS.into_iter().chain(std::iter::once(FINALITY_DEPTH)).collect();
+    let mut pending_confirmation: Vec<VecDeque<(u64, Hash, Vec<PrefixType>)>> = vec![VecDeque::new(); depths.len()];
     info!("Sink: {}", sink);
+    status.send_modify(|s| {
+        s.node_url = kaspad.node_url();
+        s.connected = kaspad.is_node_connected();
+        s.sink = Some(sink);
+        s.last_update = Some(now);
+    });
     loop {
         if exit_signal.load(Ordering::Relaxed) {
             info!("Exiting...");
             break;
         }
+        let prev = now;
         sleep_until(now + Duration::from_secs(1)).await;
         now = Instant::now();
 
-        let vcb = kaspad.get_virtual_chain_from_block(sink, true).await.unwrap();
+        // Take the current routing table, so that engines registered meanwhile are served starting from this round
+        let mut engines = registry.snapshot();
+
+        let vcb = kaspad.api().get_virtual_chain_from_block(sink, true).await.unwrap();
 
         debug!("vspc: {}, {}", vcb.removed_chain_block_hashes.len(), vcb.accepted_transaction_ids.len());
 
+        let num_chain_blocks = vcb.accepted_transaction_ids.len();
+        let reorged = !vcb.removed_chain_block_hashes.is_empty();
+        status.send_modify(|s| {
+            s.connected = kaspad.is_node_connected();
+            s.last_update = Some(now);
+            s.blocks_per_sec = num_chain_blocks as f64 / now.duration_since(prev).as_secs_f64();
+            if reorged {
+                s.reorgs_seen += 1;
+            }
+        });
+
         if let Some(new_sink) = vcb.accepted_transaction_ids.last().map(|ncb| ncb.accepting_block_hash) {
             sink = new_sink;
+            status.send_modify(|s| {
+                s.sink = Some(sink);
+                s.last_accepted_block = Some(sink);
+            });
         } else {
             // No new added chain blocks. This means no removed chain blocks as well so we can continue
             continue;
         }
 
+        // The prefixes of the engines notified of the new sink
+        let mut sink_notified = vec![];
+
         for rcb in vcb.removed_chain_block_hashes {
-            for (_, sender) in engines.values() {
-                let msg = Msg::BlkReverted { accepting_hash: rcb };
-                sender.send(msg).unwrap();
+            pending_confirmation.iter_mut().for_each(|queue| queue.retain(|(_, hash, _)| *hash != rcb));
+            for prefix in engines.keys().copied().collect::<Vec<_>>() {
+                send_or_drop(&mut engines, prefix, Msg::BlkReverted { accepting_hash: rcb });
             }
         }
 
@@ -114,15 +344,15 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
                 .filter(|&id| engines.values().any(|(pattern, _)| check_pattern(id, pattern)))
                 .collect();
 
-            // Track the required payloads
-            let mut required_payloads: HashMap<Hash, Option<Vec<u8>>> = required_txs.iter().map(|&id| (id, None)).collect();
+            // Track the required payloads along with the tx outputs and the ids of the txs spent by the tx inputs
+            let mut required_payloads: HashMap<Hash, Option<(Vec<u8>, _, _)>> = required_txs.iter().map(|&id| (id, None)).collect();
             let mut required_num = required_payloads.len();
 
             if required_num == 0 {
                 continue;
             }
 
-            let accepting_block = kaspad.get_block(accepting_hash, false).await.unwrap(); // no need for txs of this block itself
+            let accepting_block = kaspad.api().get_block(accepting_hash, false).await.unwrap(); // no need for txs of this block itself
             let verbose = accepting_block.verbose_data.unwrap();
             assert_eq!(verbose.selected_parent_hash, verbose.merge_set_blues_hashes[0]);
             debug!(
@@ -134,11 +364,20 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
 
             // Iterate over merged blocks until finding all accepted and required txs (the mergeset is guaranteed to contain these txs)
             'outer: for merged_hash in verbose.merge_set_blues_hashes.into_iter().chain(verbose.merge_set_reds_hashes) {
-                let merged_block = kaspad.get_block(merged_hash, true).await.unwrap();
+                let merged_block = kaspad.api().get_block(merged_hash, true).await.unwrap();
                 for tx in merged_block.transactions.into_iter().skip(1) {
                     if let Some(required_payload) = required_payloads.get_mut(&tx.verbose_data.unwrap().transaction_id) {
                         if required_payload.is_none() {
-                            required_payload.replace(tx.payload);
+                            let outputs: Vec<_> = tx
+                                .outputs
+                                .into_iter()
+                                .map(|output| TxOutput {
+                                    address: output.verbose_data.map(|verbose| verbose.script_public_key_address),
+                                    amount: output.value,
+                                })
+                                .collect();
+                            let spent_tx_ids = tx.inputs.iter().map(|input| input.previous_outpoint.transaction_id).collect();
+                            required_payload.replace((tx.payload, outputs, spent_tx_ids));
                             required_num -= 1;
                             if required_num == 0 {
                                 break 'outer;
@@ -151,6 +390,8 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
             // info!("Tx payloads: {:?}", required_payloads);
 
             let mut consumed_txs = 0;
+            let mut notified_prefixes = vec![];
+            let mut closed_prefixes = vec![];
             // Iterate over all engines and look for id pattern + prefix
             for (&prefix, (pattern, sender)) in engines.iter() {
                 // Collect and strip payloads in the correct order (as maintained by required_txs)
@@ -164,10 +405,10 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
                         match required_payloads.entry(id) {
                             Entry::Occupied(entry) => {
                                 // The prefix is unique per engine, so once we find a match we can consume the entry
-                                if Payload::check_header(entry.get().as_ref().unwrap(), prefix) {
-                                    let payload = entry.remove().unwrap();
+                                if Payload::check_header(&entry.get().as_ref().unwrap().0, prefix) {
+                                    let (payload, outputs, spent_tx_ids) = entry.remove().unwrap();
                                     consumed_txs += 1;
-                                    return Some((id, Payload::strip_header(payload)));
+                                    return Some((id, Payload::strip_header(payload), outputs, spent_tx_ids));
                                 }
                             }
                             Entry::Vacant(_) => {}
@@ -175,27 +416,106 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
                         None
                     })
                     .collect();
-                for (tx_id, _payload) in associated_txs.iter() {
+                for (tx_id, ..) in associated_txs.iter() {
                     info!("received episode tx: {}", tx_id);
                 }
                 if !associated_txs.is_empty() {
+                    let num_matched = associated_txs.len() as u64;
+                    status.send_modify(|s| {
+                        s.txs_matched += num_matched;
+                        s.last_accepted_daa = Some(accepting_block.header.daa_score);
+                    });
                     let msg = Msg::BlkAccepted {
                         accepting_hash,
                         accepting_daa: accepting_block.header.daa_score,
                         accepting_time: accepting_block.header.timestamp,
                         associated_txs,
                     };
-                    sender.send(msg).unwrap();
+                    match sender.send(msg) {
+                        Ok(()) => notified_prefixes.push(prefix),
+                        Err(_) => closed_prefixes.push(prefix),
+                    }
                 }
                 if consumed_txs == required_txs.len() {
                     // No need to check additional engines
                     break;
                 }
             }
+            for prefix in closed_prefixes {
+                drop_route(&mut engines, prefix);
+            }
+            if accepting_hash == sink {
+                sink_notified = notified_prefixes.clone();
+            }
+            if !notified_prefixes.is_empty() {
+                pending_confirmation[0].push_back((accepting_block.header.daa_score, accepting_hash, notified_prefixes));
+            }
+        }
+
+        // Engines not notified of the new sink are sent an empty acceptance of it, so that DAA driven logic (episode ticks
+        // and expiry, chunk timeouts and anchoring) progresses while no episode txs are accepted. It is confirmed, finalized
+        // and reverted like any other accepting block
+        let mut idle_prefixes: Vec<PrefixType> = engines.keys().filter(|prefix| !sink_notified.contains(prefix)).copied().collect();
+        if !idle_prefixes.is_empty() {
+            let header = kaspad.api().get_block(sink, false).await.unwrap().header;
+            idle_prefixes.retain(|&prefix| {
+                let msg = Msg::BlkAccepted {
+                    accepting_hash: sink,
+                    accepting_daa: header.daa_score,
+                    accepting_time: header.timestamp,
+                    associated_txs: vec![],
+                };
+                send_or_drop(&mut engines, prefix, msg)
+            });
+            match pending_confirmation[0].back_mut() {
+                Some((_, hash, prefixes)) if *hash == sink => prefixes.extend(idle_prefixes),
+                _ => pending_confirmation[0].push_back((header.daa_score, sink, idle_prefixes)),
+            }
+        }
+
+        // Notify engines of accepting blocks which passed a confirmation depth or the finality depth
+        if pending_confirmation.iter().any(|queue| !queue.is_empty()) {
+            let virtual_daa = kaspad.api().get_block_dag_info().await.unwrap().virtual_daa_score;
+            for (i, &depth) in depths.iter().enumerate() {
+                while pending_confirmation[i].front().is_some_and(|(daa, _, _)| daa + depth <= virtual_daa) {
+                    let (daa, accepting_hash, mut prefixes) = pending_confirmation[i].pop_front().unwrap();
+                    let msg = if i + 1 < depths.len() {
+                        Msg::BlkConfirmed { accepting_hash, daa_depth: virtual_daa - daa }
+                    } else {
+                        Msg::BlkFinalized { accepting_hash }
+                    };
+                    // Engines which were unregistered meanwhile are skipped
+                    prefixes.retain(|&prefix| engines.contains_key(&prefix) && send_or_drop(&mut engines, prefix, msg.clone()));
+                    if i + 1 < depths.len() {
+                        pending_confirmation[i + 1].push_back((daa, accepting_hash, prefixes));
+                    }
+                }
+            }
+        }
+    }
+
+    for (prefix, (_, sender)) in registry.snapshot() {
+        if sender.send(Msg::Exit).is_err() {
+            debug!("Engine {:#010x} already exited", prefix);
         }
     }
+}
 
-    for (_, sender) in engines.values() {
-        sender.send(Msg::Exit).unwrap();
+/// Sends `msg` to the engine routed by `prefix`, dropping the route if the engine stopped receiving (see [`drop_route`]).
+/// Returns whether the message was sent
+fn send_or_drop(engines: &mut EngineMap, prefix: PrefixType, msg: Msg) -> bool {
+    let sent = engines.get(&prefix).is_some_and(|(_, sender)| sender.send(msg).is_ok());
+    if !sent {
+        drop_route(engines, prefix);
     }
+    sent
+}
+
+/// Removes the route of an engine which stopped receiving from the routing table of the current round, so that the
+/// listener keeps serving the remaining engines. This is usually an engine which exited following its unregistration
+/// after the round took its snapshot. The registry itself is left as is, since the prefix might have been registered
+/// anew meanwhile, hence an engine which exited while still registered is skipped (and reported) every round
+fn drop_route(engines: &mut EngineMap, prefix: PrefixType) {
+    warn!("Engine {:#010x} stopped receiving, skipping its route", prefix);
+    engines.remove(&prefix);
 }
@@ -0,0 +1,331 @@
+//! Warm standby support. A primary tees the engine message stream it receives from the proxy to any number of
+//! TCP-connected standbys, which feed it into their own engines and thus stay only moments behind. Once the primary
+//! goes away, a standby can be promoted by starting a listener from the last replicated accepting block
+//! (see [`crate::proxy::run_listener_from`]).
+//!
+//! A joining standby first receives the episode snapshots persisted by the primary (see [`SnapshotHandler`]), which it
+//! stores so that it can serve episode states right away, and then the message history retained by the primary before
+//! following the live stream. The primary retains the messages logged within the episode lifetime preceding the last
+//! finalized block, since older messages can neither be reverted nor affect episodes which the engine still runs.
+//!
+//! Each standby is served by a writer thread of its own through a bounded queue, so that a slow or stalled standby
+//! cannot hold back the primary. Standbys which fall behind or time out are disconnected, and may reconnect to catch up.
+//!
+//! [`SnapshotHandler`]: crate::storage::SnapshotHandler
+
+use borsh::BorshDeserialize;
+use kaspa_consensus_core::Hash;
+use log::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::engine::{EngineMsg, EPISODE_LIFETIME, SAMPLE_REMOVAL_TIME};
+use crate::storage::{Storage, SNAPSHOTS_TREE};
+
+/// The maximal length of a frame, bounding the allocation made for a frame announced by the peer
+const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// The DAA span of the retained message history preceding the last finalized block. Episodes created before it are
+/// removed by the engine, allowing for the delay of its periodic removal
+const LOG_RETENTION: u64 = EPISODE_LIFETIME + SAMPLE_REMOVAL_TIME;
+
+/// The maximal number of frames queued for a standby, beyond which it is considered to have fallen behind
+const STANDBY_QUEUE_LEN: usize = 10_000;
+
+/// The time a write to a standby may block before the standby is considered stalled
+const STANDBY_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Frame = Arc<Vec<u8>>;
+
+/// Frames are encoded as a 4 byte little-endian length followed by the borsh encoded content. A connection starts with
+/// a frame holding the number of snapshots, followed by a frame per snapshot entry and then by `EngineMsg` frames
+fn write_frame(stream: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    if frame.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("frame of {} bytes exceeds the maximal length", frame.len())));
+    }
+    stream.write_all(&(frame.len() as u32).to_le_bytes())?;
+    stream.write_all(frame)
+}
+
+fn read_frame<T: BorshDeserialize>(stream: &mut impl Read) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("frame of {} bytes exceeds the maximal length", len)));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame)?;
+    borsh::from_slice(&frame)
+}
+
+#[derive(Default)]
+struct Replicas {
+    /// The retained frames, used for bringing up new standbys. Each frame is logged along with the DAA score of the
+    /// last accepted block at the time
+    log: VecDeque<(u64, Frame)>,
+    /// The DAA scores of the accepted blocks which were not finalized yet
+    unfinalized: HashMap<Hash, u64>,
+    last_accepted_daa: u64,
+    /// The frame queues of the connected standbys, each drained by the writer thread of the standby
+    standbys: Vec<SyncSender<Frame>>,
+    /// Set once the primary stops replicating
+    closed: bool,
+}
+
+impl Replicas {
+    /// Logs the frame of the message, and truncates the log once a block is finalized
+    fn log(&mut self, msg: &EngineMsg, frame: Frame) {
+        match msg {
+            EngineMsg::BlkAccepted { accepting_hash, accepting_daa, .. } => {
+                self.unfinalized.insert(*accepting_hash, *accepting_daa);
+                self.last_accepted_daa = self.last_accepted_daa.max(*accepting_daa);
+            }
+            EngineMsg::BlkReverted { accepting_hash } => {
+                self.unfinalized.remove(accepting_hash);
+            }
+            EngineMsg::BlkFinalized { accepting_hash } => {
+                if let Some(finalized_daa) = self.unfinalized.remove(accepting_hash) {
+                    let horizon = finalized_daa.saturating_sub(LOG_RETENTION);
+                    while self.log.front().is_some_and(|&(daa, _)| daa < horizon) {
+                        self.log.pop_front();
+                    }
+                    self.unfinalized.retain(|_, &mut daa| daa >= horizon);
+                }
+            }
+            EngineMsg::BlkConfirmed { .. } | EngineMsg::Exit => {}
+        }
+        self.log.push_back((self.last_accepted_daa, frame));
+    }
+}
+
+/// Runs the primary side of the replication. Messages received from `upstream` (usually fed by the proxy listener)
+/// are forwarded to the `local` engine and replicated to all connected standbys, along with the episode snapshots
+/// persisted to `snapshots` (if provided). Blocks until an `EngineMsg::Exit` is received or the upstream channel is closed.
+pub fn run_primary(
+    addr: impl ToSocketAddrs,
+    upstream: Receiver<EngineMsg>,
+    local: Sender<EngineMsg>,
+    snapshots: Option<Arc<dyn Storage>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Replication primary listening on {}", listener.local_addr()?);
+    serve_primary(listener, upstream, local, snapshots)
+}
+
+fn serve_primary(
+    listener: TcpListener,
+    upstream: Receiver<EngineMsg>,
+    local: Sender<EngineMsg>,
+    snapshots: Option<Arc<dyn Storage>>,
+) -> io::Result<()> {
+    let replicas = Arc::new(Mutex::new(Replicas::default()));
+
+    let acceptor_replicas = replicas.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Replication accept failed: {}", err);
+                    continue;
+                }
+            };
+            let (queue, frames) = sync_channel(STANDBY_QUEUE_LEN);
+            // Registering the queue along with copying the log guarantees the standby sees no gaps or duplicates, while
+            // the copy is sent by the writer thread without holding the lock
+            let catch_up: Vec<Frame> = {
+                let mut replicas = acceptor_replicas.lock().unwrap();
+                if replicas.closed {
+                    break;
+                }
+                replicas.standbys.push(queue);
+                replicas.log.iter().map(|(_, frame)| frame.clone()).collect()
+            };
+            let snapshots = snapshots.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(err) = serve_standby(stream, snapshots, catch_up, frames) {
+                    warn!("Standby {:?} dropped: {}", peer, err);
+                }
+            });
+        }
+    });
+
+    while let Ok(msg) = upstream.recv() {
+        let exit = matches!(msg, EngineMsg::Exit);
+        if !exit {
+            let frame = Arc::new(borsh::to_vec(&msg)?);
+            let mut replicas = replicas.lock().unwrap();
+            replicas.standbys.retain(|queue| match queue.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Standby fell {} messages behind. Dropping.", STANDBY_QUEUE_LEN);
+                    false
+                }
+                // The writer thread already reported the failure
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+            replicas.log(&msg, frame);
+        }
+        if local.send(msg).is_err() || exit {
+            break;
+        }
+    }
+    // Dropping the queues lets the writer threads flush them and close the connections
+    let mut replicas = replicas.lock().unwrap();
+    replicas.closed = true;
+    replicas.standbys.clear();
+    Ok(())
+}
+
+/// Sends the snapshots and the catch-up frames to a standby, followed by the frames queued for it. Returns once the queue
+/// is dropped or a write fails or times out, closing the connection
+fn serve_standby(
+    mut stream: TcpStream,
+    snapshots: Option<Arc<dyn Storage>>,
+    catch_up: Vec<Frame>,
+    frames: Receiver<Frame>,
+) -> io::Result<()> {
+    stream.set_write_timeout(Some(STANDBY_WRITE_TIMEOUT))?;
+    let snapshots = match snapshots {
+        Some(storage) => storage.entries(SNAPSHOTS_TREE)?,
+        None => vec![],
+    };
+    write_frame(&mut stream, &borsh::to_vec(&(snapshots.len() as u64))?)?;
+    for entry in snapshots.iter() {
+        write_frame(&mut stream, &borsh::to_vec(entry)?)?;
+    }
+    for frame in catch_up.iter() {
+        write_frame(&mut stream, frame)?;
+    }
+    info!("Standby {:?} connected ({} snapshots and {} messages replayed)", stream.peer_addr().ok(), snapshots.len(), catch_up.len());
+    drop(catch_up);
+    for frame in frames {
+        write_frame(&mut stream, &frame)?;
+    }
+    Ok(())
+}
+
+/// Runs the standby side of the replication. Connects to the primary at `addr`, stores the episode snapshots it persisted
+/// to `snapshots` (if provided) and forwards all replicated messages to the `local` engine. Returns once the primary goes
+/// away, yielding the last replicated accepting block hash which should be used as the starting point when promoting this
+/// standby to a primary.
+pub fn run_standby(
+    addr: impl ToSocketAddrs,
+    local: Sender<EngineMsg>,
+    snapshots: Option<Arc<dyn Storage>>,
+) -> io::Result<Option<Hash>> {
+    let mut stream = TcpStream::connect(addr)?;
+    info!("Connected to replication primary {}", stream.peer_addr()?);
+    let num_snapshots: u64 = read_frame(&mut stream)?;
+    for _ in 0..num_snapshots {
+        let (key, value): (Vec<u8>, Vec<u8>) = read_frame(&mut stream)?;
+        if let Some(storage) = snapshots.as_ref() {
+            storage.put(SNAPSHOTS_TREE, &key, &value)?;
+        }
+    }
+    let mut last_accepted = None;
+    loop {
+        let msg = match read_frame(&mut stream) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Replication primary lost: {}", err);
+                return Ok(last_accepted);
+            }
+        };
+        if let EngineMsg::BlkAccepted { accepting_hash, .. } = msg {
+            last_accepted = Some(accepting_hash);
+        }
+        if local.send(msg).is_err() {
+            // The local engine has exited
+            return Ok(last_accepted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_standby_replication() {
+        let accepted = |block: u64, payload_len: usize| EngineMsg::BlkAccepted {
+            accepting_hash: block.into(),
+            accepting_daa: block,
+            accepting_time: 0,
+            associated_txs: vec![(block.into(), vec![0; payload_len], vec![], vec![])],
+        };
+        let storage = Arc::new(MemoryStorage::new());
+        storage.put(SNAPSHOTS_TREE, &[1], &[2]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (upstream, upstream_receiver) = channel();
+        let (local_sender, local) = channel();
+        let primary = thread::spawn(move || serve_primary(listener, upstream_receiver, local_sender, Some(storage)));
+
+        // Messages preceding the connection of a standby are caught up with
+        upstream.send(accepted(1, 0)).unwrap();
+        local.recv().unwrap();
+        let standby_storage = Arc::new(MemoryStorage::new());
+        let (standby_sender, standby_local) = channel();
+        let standby = {
+            let storage: Arc<dyn Storage> = standby_storage.clone();
+            thread::spawn(move || run_standby(addr, standby_sender, Some(storage)))
+        };
+
+        // A standby which never reads does not hold back the primary, even once its socket buffers are full
+        let _stalled = TcpStream::connect(addr).unwrap();
+        for block in 2..=200 {
+            upstream.send(accepted(block, 128 << 10)).unwrap();
+            assert!(local.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+        for block in 1..=200u64 {
+            let Ok(EngineMsg::BlkAccepted { accepting_hash, .. }) = standby_local.recv_timeout(Duration::from_secs(5)) else {
+                panic!("standby missed block {}", block);
+            };
+            assert_eq!(accepting_hash, block.into());
+        }
+        assert_eq!(standby_storage.get(SNAPSHOTS_TREE, &[1]).unwrap(), Some(vec![2]));
+
+        // Once the primary goes away, the standby yields the block to resume from
+        drop(upstream);
+        primary.join().unwrap().unwrap();
+        assert_eq!(standby.join().unwrap().unwrap(), Some(200u64.into()));
+    }
+
+    #[test]
+    fn test_log_truncation() {
+        let mut replicas = Replicas::default();
+        let mut log = |msg: EngineMsg| replicas.log(&msg, Arc::new(borsh::to_vec(&msg).unwrap()));
+        let accepted = |daa: u64| EngineMsg::BlkAccepted {
+            accepting_hash: daa.into(),
+            accepting_daa: daa,
+            accepting_time: 0,
+            associated_txs: vec![],
+        };
+        for daa in [1, 2, LOG_RETENTION + 1, LOG_RETENTION + 2] {
+            log(accepted(daa));
+        }
+        log(EngineMsg::BlkFinalized { accepting_hash: 1u64.into() });
+        log(EngineMsg::BlkFinalized { accepting_hash: (LOG_RETENTION + 2).into() });
+        let retained: Vec<u64> = replicas.log.iter().map(|&(daa, _)| daa).collect();
+        assert_eq!(retained, vec![2, LOG_RETENTION + 1, LOG_RETENTION + 2, LOG_RETENTION + 2, LOG_RETENTION + 2]);
+
+        // Frames exceeding the maximal length are refused on both ends
+        let mut stream = vec![];
+        assert!(write_frame(&mut stream, &vec![0; MAX_FRAME_LEN + 1]).is_err());
+        let frame = borsh::to_vec(&EngineMsg::Exit).unwrap();
+        write_frame(&mut stream, &frame).unwrap();
+        assert!(matches!(read_frame(&mut stream.as_slice()), Ok(EngineMsg::Exit)));
+        let oversized = (MAX_FRAME_LEN as u32 + 1).to_le_bytes();
+        assert_eq!(read_frame::<EngineMsg>(&mut oversized.as_slice()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
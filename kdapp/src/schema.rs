@@ -0,0 +1,200 @@
+//! Machine-readable descriptions of the Borsh layout of episode payloads, allowing non-Rust clients (e.g., browser JS or
+//! Python bots) to construct and decode episode messages without hand-porting the layout. Types describe themselves
+//! through [`TypeSchema`], which is usually derived, and [`episode_schema`] renders the schema of an episode as JSON.
+//!
+//! The layout follows Borsh: struct fields are serialized in order, enums are prefixed by a `u8` variant index,
+//! sequences and strings by a `u32` length, and options by a `u8` tag. Layouts which Borsh does not define are described
+//! by the following primitives:
+//! - `pubkey`: a 33-byte compressed secp256k1 public key;
+//! - `hash`: 32 bytes;
+//! - `sig`: either a DER encoded ECDSA signature, or a `0x01` marker followed by a 64-byte Schnorr signature. A `sig`
+//!   extends to the end of its input, hence it is always the last field of a message;
+//! - `address`: a kaspa address in its `kaspa-addresses` Borsh encoding.
+
+use crate::episode::EpisodeProjection;
+use crate::pki::{AddressBinding, MultiSig, PubKey, Sig};
+use kaspa_addresses::Address;
+use kaspa_consensus_core::Hash;
+
+/// Derive for [`TypeSchema`] (see [`kdapp_macros::TypeSchema`])
+pub use kdapp_macros::TypeSchema;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Schema {
+    Primitive(&'static str),
+    Option(Box<Schema>),
+    Seq(Box<Schema>),
+    Array(Box<Schema>, usize),
+    Tuple(Vec<Schema>),
+    Struct { name: String, fields: Fields },
+    Enum { name: String, variants: Vec<(String, Fields)> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fields {
+    Named(Vec<(String, Schema)>),
+    Unnamed(Vec<Schema>),
+    Unit,
+}
+
+impl Schema {
+    /// Renders the schema as JSON. Primitives are rendered as their name, and composite types as objects keyed by
+    /// their kind, e.g., `{"seq":"u8"}` or `{"enum":"Name","variants":[{"name":"A","fields":[]}]}`
+    pub fn to_json(&self) -> String {
+        match self {
+            Schema::Primitive(name) => format!("\"{}\"", name),
+            Schema::Option(inner) => format!("{{\"option\":{}}}", inner.to_json()),
+            Schema::Seq(inner) => format!("{{\"seq\":{}}}", inner.to_json()),
+            Schema::Array(inner, len) => format!("{{\"array\":{},\"len\":{}}}", inner.to_json(), len),
+            Schema::Tuple(elems) => format!("{{\"tuple\":[{}]}}", join(elems.iter().map(Schema::to_json))),
+            Schema::Struct { name, fields } => format!("{{\"struct\":\"{}\",\"fields\":{}}}", name, fields.to_json()),
+            Schema::Enum { name, variants } => {
+                let variants =
+                    variants.iter().map(|(name, fields)| format!("{{\"name\":\"{}\",\"fields\":{}}}", name, fields.to_json()));
+                format!("{{\"enum\":\"{}\",\"variants\":[{}]}}", name, join(variants))
+            }
+        }
+    }
+}
+
+impl Fields {
+    /// Renders the fields as a JSON array of `{"name":..,"type":..}` objects for named fields, or of bare types otherwise
+    pub fn to_json(&self) -> String {
+        match self {
+            Fields::Named(fields) => {
+                format!(
+                    "[{}]",
+                    join(fields.iter().map(|(name, schema)| format!("{{\"name\":\"{}\",\"type\":{}}}", name, schema.to_json())))
+                )
+            }
+            Fields::Unnamed(fields) => format!("[{}]", join(fields.iter().map(Schema::to_json))),
+            Fields::Unit => "[]".to_string(),
+        }
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(",")
+}
+
+/// A type which can describe its Borsh layout
+pub trait TypeSchema {
+    fn schema() -> Schema;
+}
+
+macro_rules! primitive_schema {
+    ($($ty:ty => $name:literal),* $(,)?) => {
+        $(
+            impl TypeSchema for $ty {
+                fn schema() -> Schema {
+                    Schema::Primitive($name)
+                }
+            }
+        )*
+    };
+}
+
+primitive_schema! {
+    u8 => "u8", u16 => "u16", u32 => "u32", u64 => "u64", u128 => "u128",
+    i8 => "i8", i16 => "i16", i32 => "i32", i64 => "i64", i128 => "i128",
+    // Borsh encodes usize and isize as 64 bit integers
+    usize => "u64", isize => "i64",
+    f32 => "f32", f64 => "f64", bool => "bool", String => "string",
+    PubKey => "pubkey", Hash => "hash", Sig => "sig", Address => "address",
+}
+
+impl<T: TypeSchema> TypeSchema for Option<T> {
+    fn schema() -> Schema {
+        Schema::Option(Box::new(T::schema()))
+    }
+}
+
+impl<T: TypeSchema> TypeSchema for Vec<T> {
+    fn schema() -> Schema {
+        Schema::Seq(Box::new(T::schema()))
+    }
+}
+
+impl<T: TypeSchema, const N: usize> TypeSchema for [T; N] {
+    fn schema() -> Schema {
+        Schema::Array(Box::new(T::schema()), N)
+    }
+}
+
+macro_rules! tuple_schema {
+    ($($name:ident),*) => {
+        impl<$($name: TypeSchema),*> TypeSchema for ($($name,)*) {
+            fn schema() -> Schema {
+                Schema::Tuple(vec![$($name::schema()),*])
+            }
+        }
+    };
+}
+
+tuple_schema!();
+tuple_schema!(A);
+tuple_schema!(A, B);
+tuple_schema!(A, B, C);
+tuple_schema!(A, B, C, D);
+
+// Each signature of a multisig is serialized with a length prefix
+impl TypeSchema for MultiSig {
+    fn schema() -> Schema {
+        Schema::Seq(Box::new(Schema::Tuple(vec![PubKey::schema(), Vec::<u8>::schema()])))
+    }
+}
+
+impl TypeSchema for AddressBinding {
+    fn schema() -> Schema {
+        let fields = vec![
+            ("address".to_string(), Address::schema()),
+            ("pubkey".to_string(), PubKey::schema()),
+            ("address_sig".to_string(), <[u8; 64]>::schema()),
+            ("pubkey_sig".to_string(), Sig::schema()),
+        ];
+        Schema::Struct { name: "AddressBinding".to_string(), fields: Fields::Named(fields) }
+    }
+}
+
+/// Renders the schema of the episode as a JSON object holding the schemas of its `message` (the
+/// [`EpisodeMessage`](crate::engine::EpisodeMessage) carried by tx payloads), its `command` and its `public` projection
+pub fn episode_schema<G>() -> String
+where
+    G: EpisodeProjection,
+    G::Command: TypeSchema,
+    G::Public: TypeSchema,
+{
+    format!(
+        "{{\"message\":{},\"command\":{},\"public\":{}}}",
+        crate::engine::EpisodeMessage::<G>::schema().to_json(),
+        G::Command::schema().to_json(),
+        G::Public::schema().to_json()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(TypeSchema)]
+    #[allow(dead_code)]
+    enum Command {
+        Move { row: u8, col: u8 },
+        Say(String, Option<PubKey>),
+        Resign,
+    }
+
+    #[test]
+    fn test_schema_json() {
+        assert_eq!(
+            Command::schema().to_json(),
+            concat!(
+                r#"{"enum":"Command","variants":["#,
+                r#"{"name":"Move","fields":[{"name":"row","type":"u8"},{"name":"col","type":"u8"}]},"#,
+                r#"{"name":"Say","fields":["string",{"option":"pubkey"}]},"#,
+                r#"{"name":"Resign","fields":[]}]}"#
+            )
+        );
+        assert_eq!(<([u8; 2], Vec<bool>)>::schema().to_json(), r#"{"tuple":[{"array":"u8","len":2},{"seq":"bool"}]}"#);
+    }
+}
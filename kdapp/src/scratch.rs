@@ -0,0 +1,125 @@
+//! Engine-managed key-value scratch storage. An episode holding a [`ScratchStore`] (and exposing it through
+//! [`Episode::scratch_store`](crate::episode::Episode::scratch_store)) can keep small values in it from within
+//! `execute`, while the engine journals every mutation made during a command and undoes them when the command is
+//! rejected or rolled back. Episode rollback data then only needs to cover state kept outside of the store.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::collections::BTreeMap;
+
+/// The previous values of all keys mutated by a single command, in mutation order (`None` for keys which were absent)
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ScratchRollback(Vec<(Vec<u8>, Option<Vec<u8>>)>);
+
+impl ScratchRollback {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default, BorshSerialize, BorshDeserialize)]
+pub struct ScratchStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Mutations of the currently executing command. Transient, hence not serialized
+    #[borsh(skip)]
+    journal: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl ScratchStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value stored under `key`, or `None` if the key is absent or its value does not deserialize as `V`
+    pub fn get<K: BorshSerialize, V: BorshDeserialize>(&self, key: &K) -> Option<V> {
+        self.entries.get(&borsh::to_vec(key).unwrap()).and_then(|value| borsh::from_slice(value).ok())
+    }
+
+    pub fn contains<K: BorshSerialize>(&self, key: &K) -> bool {
+        self.entries.contains_key(&borsh::to_vec(key).unwrap())
+    }
+
+    /// Stores `value` under `key`, returning the raw previous value if any
+    pub fn put<K: BorshSerialize, V: BorshSerialize>(&mut self, key: &K, value: &V) -> Option<Vec<u8>> {
+        let key = borsh::to_vec(key).unwrap();
+        let previous = self.entries.insert(key.clone(), borsh::to_vec(value).unwrap());
+        self.journal.push((key, previous.clone()));
+        previous
+    }
+
+    /// Removes `key`, returning its raw value if it was present
+    pub fn remove<K: BorshSerialize>(&mut self, key: &K) -> Option<Vec<u8>> {
+        let key = borsh::to_vec(key).unwrap();
+        let previous = self.entries.remove(&key);
+        if previous.is_some() {
+            self.journal.push((key, previous.clone()));
+        }
+        previous
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Starts journaling the mutations of a new command
+    pub(crate) fn begin(&mut self) {
+        self.journal.clear();
+    }
+
+    /// Ends journaling and returns the rollback data of the command mutations
+    pub(crate) fn commit(&mut self) -> ScratchRollback {
+        ScratchRollback(std::mem::take(&mut self.journal))
+    }
+
+    /// Undoes the mutations of a rolled back command
+    pub(crate) fn restore(&mut self, rollback: ScratchRollback) {
+        // Mutations are undone in reverse order so that each key ends up with the value it had before the first one
+        for (key, previous) in rollback.0.into_iter().rev() {
+            match previous {
+                Some(value) => self.entries.insert(key, value),
+                None => self.entries.remove(&key),
+            };
+        }
+    }
+
+    /// Undoes the mutations of a rejected command
+    pub(crate) fn discard(&mut self) {
+        let rollback = self.commit();
+        self.restore(rollback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scratch_restore() {
+        let mut store = ScratchStore::new();
+        store.put(&"a", &1u32);
+        store.begin();
+        store.commit();
+
+        store.begin();
+        store.put(&"a", &2u32);
+        store.put(&"a", &3u32);
+        store.put(&"b", &"value".to_string());
+        store.remove(&"c");
+        let rollback = store.commit();
+        assert_eq!(store.get::<_, u32>(&"a"), Some(3));
+        assert_eq!(store.get::<_, String>(&"b").as_deref(), Some("value"));
+
+        store.restore(rollback);
+        assert_eq!(store.get::<_, u32>(&"a"), Some(1));
+        assert!(!store.contains(&"b"));
+
+        store.begin();
+        store.remove(&"a");
+        store.discard();
+        assert_eq!(store.get::<_, u32>(&"a"), Some(1));
+        assert_eq!(store.len(), 1);
+    }
+}
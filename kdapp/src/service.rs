@@ -0,0 +1,341 @@
+//! A gRPC service exposing the episodes run by an engine (see `proto/service.proto`), giving every application a
+//! uniform machine API for reading episode state, following episode events and submitting commands. A
+//! [`ServiceHandler`] is passed to the engine and records the [projections](EpisodeProjection) of the episodes, while
+//! [`serve`] runs the server over a clone of it. States and commands are Borsh encoded, with layouts described by the
+//! [episode schema](crate::schema::episode_schema).
+//!
+//! Submitted commands are paid for by the service host (see [`CommandSubmitter`]), so a service accepting commands
+//! should only be exposed to trusted clients.
+
+use kaspa_consensus_core::Hash;
+use kaspa_wrpc_client::KaspaRpcClient;
+use log::info;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::engine::EpisodeMessage;
+use crate::episode::{Episode, EpisodeEventHandler, EpisodeId, EpisodeProjection, PayloadMetadata};
+use crate::generator::{RetryPolicy, SubmitOutcome, TransactionGenerator, UtxoManager, MIN_OUTPUT_AMOUNT};
+use crate::pki::PubKey;
+
+pub mod proto {
+    tonic::include_proto!("kdapp.service");
+}
+
+use proto::episode_service_server::{EpisodeService, EpisodeServiceServer};
+use proto::{
+    EpisodeEvent, EpisodeState, EventKind, GetEpisodeStateRequest, StreamEpisodeEventsRequest, SubmitCommandRequest,
+    SubmitCommandResponse,
+};
+
+/// The number of events buffered per stream. A client lagging further behind has its stream closed with `DATA_LOSS`,
+/// after which it should re-read the episode state and resubscribe
+const EVENTS_CAPACITY: usize = 1024;
+
+struct Shared {
+    states: Mutex<HashMap<EpisodeId, Vec<u8>>>,
+    events: broadcast::Sender<EpisodeEvent>,
+}
+
+/// An event handler recording the latest projection of every episode and broadcasting the episode events to the
+/// service clients
+pub struct ServiceHandler<G> {
+    shared: Arc<Shared>,
+    _phantom: PhantomData<fn(&G)>,
+}
+
+impl<G> Clone for ServiceHandler<G> {
+    fn clone(&self) -> Self {
+        Self { shared: self.shared.clone(), _phantom: PhantomData }
+    }
+}
+
+impl<G: EpisodeProjection> Default for ServiceHandler<G> {
+    fn default() -> Self {
+        let shared = Shared { states: Default::default(), events: broadcast::channel(EVENTS_CAPACITY).0 };
+        Self { shared: Arc::new(shared), _phantom: PhantomData }
+    }
+}
+
+impl<G: EpisodeProjection> ServiceHandler<G> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The Borsh encoded projection of the episode, or `None` if it is unknown or expired
+    pub fn state(&self, episode_id: EpisodeId) -> Option<Vec<u8>> {
+        self.shared.states.lock().unwrap().get(&episode_id).cloned()
+    }
+
+    fn publish(&self, episode_id: EpisodeId, episode: &G, kind: EventKind, command: Vec<u8>, daa: u64) {
+        let state = borsh::to_vec(&episode.project()).unwrap();
+        self.shared.states.lock().unwrap().insert(episode_id, state.clone());
+        self.send(EpisodeEvent { episode_id, kind: kind.into(), state, command, daa, ..Default::default() });
+    }
+
+    fn send(&self, event: EpisodeEvent) {
+        // Sending fails only if no client is streaming
+        let _ = self.shared.events.send(event);
+    }
+}
+
+impl<G: EpisodeProjection> EpisodeEventHandler<G> for ServiceHandler<G> {
+    fn on_initialize(&self, episode_id: EpisodeId, episode: &G) {
+        self.publish(episode_id, episode, EventKind::Initialize, vec![], 0);
+    }
+
+    fn on_command(
+        &self,
+        episode_id: EpisodeId,
+        episode: &G,
+        cmd: &<G as Episode>::Command,
+        _authorization: Option<PubKey>,
+        metadata: &PayloadMetadata,
+    ) {
+        self.publish(episode_id, episode, EventKind::Command, borsh::to_vec(cmd).unwrap(), metadata.accepting_daa);
+    }
+
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &G) {
+        self.publish(episode_id, episode, EventKind::Rollback, vec![], 0);
+    }
+
+    fn on_key_rotation(&self, episode_id: EpisodeId, episode: &G, _old: PubKey, _new: PubKey) {
+        self.publish(episode_id, episode, EventKind::KeyRotation, vec![], 0);
+    }
+
+    fn on_daa_tick(&self, episode_id: EpisodeId, episode: &G, daa: u64) {
+        self.publish(episode_id, episode, EventKind::DaaTick, vec![], daa);
+    }
+
+    fn on_expiry(&self, episode_id: EpisodeId) {
+        self.shared.states.lock().unwrap().remove(&episode_id);
+        self.send(EpisodeEvent { episode_id, kind: EventKind::Expiry.into(), ..Default::default() });
+    }
+
+    fn on_rejection(&self, episode_id: EpisodeId, tx_id: Hash, error: &str) {
+        // The state is left unchanged, so the event carries the latest one (if the episode exists)
+        let state = self.state(episode_id).unwrap_or_default();
+        let (tx_id, error) = (tx_id.to_string(), error.to_string());
+        self.send(EpisodeEvent { episode_id, kind: EventKind::Rejection.into(), state, tx_id, error, ..Default::default() });
+    }
+}
+
+/// Builds and submits the command txs of the service clients, funded by the UTXOs of the host. The UTXO manager is
+/// expected to be refreshed by the host, and can be shared with its other flows
+pub struct CommandSubmitter {
+    kaspad: KaspaRpcClient,
+    generator: TransactionGenerator,
+    utxos: Arc<UtxoManager>,
+    retry_policy: RetryPolicy,
+}
+
+impl CommandSubmitter {
+    pub fn new(kaspad: KaspaRpcClient, generator: TransactionGenerator, utxos: Arc<UtxoManager>) -> Self {
+        Self { kaspad, generator, utxos, retry_policy: Default::default() }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    async fn submit<G: Episode>(&self, msg: &EpisodeMessage<G>) -> Result<String, Status> {
+        // Command txs pay back to the funding address
+        let recipient = self.utxos.address();
+        let fee = self
+            .generator
+            .command_fee(&self.kaspad, recipient, msg)
+            .await
+            .map_err(|err| Status::unavailable(format!("estimating the fee failed: {}", err)))?;
+        // Prefer a single UTXO, and fall back to aggregating fragmented funds (at the fee of the additional inputs)
+        let (utxos, fee) = match self.utxos.reserve_at_least(fee.saturating_add(MIN_OUTPUT_AMOUNT)) {
+            Some(utxo) => (vec![utxo], fee),
+            None => self
+                .generator
+                .reserve_aggregated(&self.kaspad, &self.utxos, recipient, msg, fee)
+                .await
+                .ok_or_else(|| Status::failed_precondition("the service has insufficient funds"))?,
+        };
+        match self.generator.submit_utxos_with_retry(&self.kaspad, &self.utxos, utxos, recipient, msg, fee, self.retry_policy).await {
+            SubmitOutcome::GaveUp { attempts, error } => {
+                Err(Status::unavailable(format!("submission failed after {} attempts: {}", attempts, error)))
+            }
+            outcome => Ok(outcome.tx_id().unwrap().to_string()),
+        }
+    }
+}
+
+struct EpisodeServer<G> {
+    handler: ServiceHandler<G>,
+    submitter: Option<CommandSubmitter>,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<EpisodeEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<G> EpisodeService for EpisodeServer<G>
+where
+    G: EpisodeProjection + 'static,
+    G::Command: Send + Sync,
+{
+    async fn get_episode_state(&self, request: Request<GetEpisodeStateRequest>) -> Result<Response<EpisodeState>, Status> {
+        let episode_id = request.into_inner().episode_id;
+        let state = self.handler.state(episode_id).ok_or_else(|| Status::not_found(format!("unknown episode {}", episode_id)))?;
+        Ok(Response::new(EpisodeState { episode_id, state }))
+    }
+
+    type StreamEpisodeEventsStream = EventStream;
+
+    async fn stream_episode_events(
+        &self,
+        request: Request<StreamEpisodeEventsRequest>,
+    ) -> Result<Response<Self::StreamEpisodeEventsStream>, Status> {
+        let filter = request.into_inner().episode_id;
+        let events = BroadcastStream::new(self.handler.shared.events.subscribe()).filter_map(move |event| match event {
+            Ok(event) if filter.is_none_or(|episode_id| episode_id == event.episode_id) => Some(Ok(event)),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Status::data_loss(format!("missed {} events", missed)))),
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn submit_command(&self, request: Request<SubmitCommandRequest>) -> Result<Response<SubmitCommandResponse>, Status> {
+        let submitter = self.submitter.as_ref().ok_or_else(|| Status::unimplemented("command submission is disabled"))?;
+        let msg: EpisodeMessage<G> = borsh::from_slice(&request.into_inner().message)
+            .map_err(|err| Status::invalid_argument(format!("invalid episode message: {}", err)))?;
+        let tx_id = submitter.submit(&msg).await?;
+        info!("Submitted command of episode {} on behalf of a client: {}", msg.episode_id(), tx_id);
+        Ok(Response::new(SubmitCommandResponse { tx_id }))
+    }
+}
+
+/// Serves the episodes recorded by `handler` on `addr` until `exit_signal` is set. Command submission is disabled
+/// unless a `submitter` is provided
+pub async fn serve<G>(
+    addr: SocketAddr,
+    handler: ServiceHandler<G>,
+    submitter: Option<CommandSubmitter>,
+    exit_signal: Arc<AtomicBool>,
+) -> Result<(), tonic::transport::Error>
+where
+    G: EpisodeProjection + 'static,
+    G::Command: Send + Sync,
+{
+    info!("Serving episodes on {}", addr);
+    let shutdown = async move {
+        while !exit_signal.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+    Server::builder()
+        .add_service(EpisodeServiceServer::new(EpisodeServer { handler, submitter }))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::episode::EpisodeError;
+    use proto::episode_service_client::EpisodeServiceClient;
+
+    #[derive(Debug)]
+    struct Tally {
+        total: u64,
+    }
+
+    impl Episode for Tally {
+        type Command = u64;
+        type CommandRollback = u64;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { total: 0 }
+        }
+
+        fn execute(
+            &mut self,
+            cmd: &u64,
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<u64, EpisodeError<std::fmt::Error>> {
+            self.total += cmd;
+            Ok(*cmd)
+        }
+
+        fn rollback(&mut self, cmd: u64) -> bool {
+            self.total -= cmd;
+            true
+        }
+    }
+
+    impl EpisodeProjection for Tally {
+        type Public = u64;
+
+        fn project(&self) -> u64 {
+            self.total
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let handler = ServiceHandler::<Tally>::new();
+        let exit_signal = Arc::new(AtomicBool::new(false));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = tokio::spawn(serve(addr, handler.clone(), None, exit_signal.clone()));
+
+        let mut client = loop {
+            match EpisodeServiceClient::connect(format!("http://{}", addr)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        let mut events = client.stream_episode_events(StreamEpisodeEventsRequest { episode_id: Some(1) }).await.unwrap().into_inner();
+        let status = client.get_episode_state(GetEpisodeStateRequest { episode_id: 1 }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let metadata = PayloadMetadata::for_test(7);
+        let mut episode = Tally { total: 0 };
+        handler.on_initialize(2, &episode);
+        handler.on_initialize(1, &episode);
+        episode.total = 3;
+        handler.on_command(1, &episode, &3, None, &metadata);
+
+        let state = client.get_episode_state(GetEpisodeStateRequest { episode_id: 1 }).await.unwrap().into_inner();
+        assert_eq!(state.state, borsh::to_vec(&3u64).unwrap());
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!((event.episode_id, event.kind()), (1, EventKind::Initialize));
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!((event.kind(), event.command, event.daa), (EventKind::Command, borsh::to_vec(&3u64).unwrap(), 7));
+        handler.on_rejection(1, 5u64.into(), "invalid signature");
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.state, event.error.as_str()),
+            (EventKind::Rejection, borsh::to_vec(&3u64).unwrap(), "invalid signature")
+        );
+
+        // Expired episodes are no longer served
+        handler.on_expiry(1);
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!((event.episode_id, event.kind()), (1, EventKind::Expiry));
+        let status = client.get_episode_state(GetEpisodeStateRequest { episode_id: 1 }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let status = client.submit_command(SubmitCommandRequest { message: vec![] }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+        // The server shuts down gracefully, awaiting open connections
+        drop((client, events));
+        exit_signal.store(true, Ordering::Relaxed);
+        server.await.unwrap().unwrap();
+    }
+}
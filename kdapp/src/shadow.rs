@@ -0,0 +1,283 @@
+//! Shadow execution for de-risking episode logic upgrades. The current episode implementation and its upgraded
+//! version run in separate engines over the same engine feed, and the digests of their states are compared after
+//! every event. Divergences are reported as they are found, allowing operators to verify an upgrade against live
+//! chain data before cutting over.
+
+use crate::engine::{Engine, EngineMsg};
+use crate::episode::{Episode, EpisodeEventHandler, EpisodeId, EpisodeProjection, PayloadMetadata};
+use crate::pki::PubKey;
+use borsh::BorshSerialize;
+use kaspa_consensus_core::Hash;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+/// A function mapping episode state into a digest. The digests of both implementations must agree for equivalent states,
+/// so the upgraded implementation should digest a representation which is shared with the current one
+pub type DigestFn<G> = fn(&G) -> Hash;
+
+/// Digests the Borsh serialization of a value. Useful for building [`DigestFn`]s over a shared state representation
+pub fn borsh_digest<T: BorshSerialize>(value: &T) -> Hash {
+    Hash::from_slice(&Sha256::digest(borsh::to_vec(value).unwrap()))
+}
+
+/// Digests the [projection](EpisodeProjection) of an episode. A natural [`DigestFn`] when both implementations
+/// share the projection type
+pub fn projection_digest<G: EpisodeProjection>(episode: &G) -> Hash {
+    borsh_digest(&episode.project())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowEventKind {
+    Initialize,
+    Command { tx_id: Hash },
+    Rollback,
+    KeyRotation { old: PubKey, new: PubKey },
+    DaaTick { daa: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShadowEvent {
+    pub kind: ShadowEventKind,
+    pub digest: Hash,
+}
+
+/// An event which does not match between the two implementations. A missing event indicates that one
+/// implementation rejected (or did not receive) a command which the other accepted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub episode_id: EpisodeId,
+    /// The index of the event within the episode event sequence
+    pub index: usize,
+    pub current: Option<ShadowEvent>,
+    pub upgraded: Option<ShadowEvent>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShadowReport {
+    pub events_compared: u64,
+    pub divergences: Vec<Divergence>,
+}
+
+impl ShadowReport {
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty()
+    }
+
+    fn record(&mut self, divergence: Divergence) {
+        warn!(
+            "Episode {}: shadow divergence at event {}: current {:?}, upgraded {:?}",
+            divergence.episode_id, divergence.index, divergence.current, divergence.upgraded
+        );
+        self.divergences.push(divergence);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Current,
+    Upgraded,
+}
+
+struct DigestHandler<G: Episode> {
+    side: Side,
+    digest: DigestFn<G>,
+    sender: Sender<(Side, EpisodeId, ShadowEvent)>,
+}
+
+impl<G: Episode> DigestHandler<G> {
+    fn report(&self, episode_id: EpisodeId, kind: ShadowEventKind, episode: &G) {
+        let _ = self.sender.send((self.side, episode_id, ShadowEvent { kind, digest: (self.digest)(episode) }));
+    }
+}
+
+impl<G: Episode> EpisodeEventHandler<G> for DigestHandler<G> {
+    fn on_initialize(&self, episode_id: EpisodeId, episode: &G) {
+        self.report(episode_id, ShadowEventKind::Initialize, episode);
+    }
+
+    fn on_command(
+        &self,
+        episode_id: EpisodeId,
+        episode: &G,
+        _cmd: &<G as Episode>::Command,
+        _authorization: Option<PubKey>,
+        metadata: &PayloadMetadata,
+    ) {
+        self.report(episode_id, ShadowEventKind::Command { tx_id: metadata.tx_id }, episode);
+    }
+
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &G) {
+        self.report(episode_id, ShadowEventKind::Rollback, episode);
+    }
+
+    fn on_key_rotation(&self, episode_id: EpisodeId, episode: &G, old: PubKey, new: PubKey) {
+        self.report(episode_id, ShadowEventKind::KeyRotation { old, new }, episode);
+    }
+
+    fn on_daa_tick(&self, episode_id: EpisodeId, episode: &G, daa: u64) {
+        self.report(episode_id, ShadowEventKind::DaaTick { daa }, episode);
+    }
+}
+
+/// Runs the `Current` and `Upgraded` episode implementations over the engine feed of `receiver` until it is closed or
+/// an exit message is received, and returns the report of all compared events. Events are compared per episode in the
+/// order of their occurrence, and each divergence is also logged as soon as it is found.
+pub fn run_shadow<Current, Upgraded>(
+    receiver: Receiver<EngineMsg>,
+    current_digest: DigestFn<Current>,
+    upgraded_digest: DigestFn<Upgraded>,
+) -> ShadowReport
+where
+    Current: Episode + Send + 'static,
+    Upgraded: Episode + Send + 'static,
+{
+    let (event_sender, event_receiver) = channel();
+    let (current_sender, current_receiver) = channel();
+    let (upgraded_sender, upgraded_receiver) = channel();
+
+    let current_handler = DigestHandler { side: Side::Current, digest: current_digest, sender: event_sender.clone() };
+    let upgraded_handler = DigestHandler { side: Side::Upgraded, digest: upgraded_digest, sender: event_sender };
+    let current_engine = thread::spawn(move || Engine::<Current, _>::new(current_receiver).start(vec![current_handler]));
+    let upgraded_engine = thread::spawn(move || Engine::<Upgraded, _>::new(upgraded_receiver).start(vec![upgraded_handler]));
+    let comparator = thread::spawn(move || compare_events(event_receiver));
+
+    while let Ok(msg) = receiver.recv() {
+        let exit = matches!(msg, EngineMsg::Exit);
+        let _ = current_sender.send(msg.clone());
+        let _ = upgraded_sender.send(msg);
+        if exit {
+            break;
+        }
+    }
+    drop((current_sender, upgraded_sender));
+    current_engine.join().unwrap();
+    upgraded_engine.join().unwrap();
+    // Both handlers were dropped along with their engines, so the comparator receives all events and then terminates
+    comparator.join().unwrap()
+}
+
+fn compare_events(event_receiver: Receiver<(Side, EpisodeId, ShadowEvent)>) -> ShadowReport {
+    // Per episode: the number of events compared so far, and the events of each side pending comparison
+    let mut episodes: HashMap<EpisodeId, (usize, VecDeque<ShadowEvent>, VecDeque<ShadowEvent>)> = HashMap::new();
+    let mut report = ShadowReport::default();
+
+    for (side, episode_id, event) in event_receiver {
+        let (index, current, upgraded) = episodes.entry(episode_id).or_default();
+        match side {
+            Side::Current => current.push_back(event),
+            Side::Upgraded => upgraded.push_back(event),
+        }
+        while !current.is_empty() && !upgraded.is_empty() {
+            let (current_event, upgraded_event) = (current.pop_front(), upgraded.pop_front());
+            if current_event != upgraded_event {
+                report.record(Divergence { episode_id, index: *index, current: current_event, upgraded: upgraded_event });
+            }
+            report.events_compared += 1;
+            *index += 1;
+        }
+    }
+
+    // Events left unmatched once both engines exited
+    for (episode_id, (index, current, upgraded)) in episodes {
+        let (current, upgraded) = (current.into_iter().map(Some), upgraded.into_iter().map(Some));
+        let pending = current
+            .chain(std::iter::repeat(None))
+            .zip(upgraded.chain(std::iter::repeat(None)))
+            .take_while(|pair| pair != &(None, None));
+        for (offset, (current, upgraded)) in pending.enumerate() {
+            report.record(Divergence { episode_id, index: index + offset, current, upgraded });
+        }
+    }
+    info!("Shadow execution compared {} events with {} divergences", report.events_compared, report.divergences.len());
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EpisodeMessage;
+    use crate::episode::EpisodeError;
+
+    /// Sums unsigned commands, where the upgraded implementation rejects commands exceeding `MAX_CMD`
+    #[derive(Debug)]
+    struct Sum<const MAX_CMD: u64> {
+        total: u64,
+    }
+
+    impl<const MAX_CMD: u64> Episode for Sum<MAX_CMD> {
+        type Command = u64;
+        type CommandRollback = u64;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { total: 0 }
+        }
+
+        fn execute(
+            &mut self,
+            cmd: &u64,
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<u64, EpisodeError<std::fmt::Error>> {
+            if *cmd > MAX_CMD {
+                return Err(EpisodeError::InvalidCommand(std::fmt::Error));
+            }
+            self.total += cmd;
+            Ok(*cmd)
+        }
+
+        fn rollback(&mut self, cmd: u64) -> bool {
+            self.total -= cmd;
+            true
+        }
+    }
+
+    type Current = Sum<{ u64::MAX }>;
+    type Upgraded = Sum<5>;
+
+    /// Feeds the episode creation followed by the commands in consecutive blocks, where the i'th message is carried by tx `i`
+    fn feed(cmds: &[u64]) -> Receiver<EngineMsg> {
+        let (sender, receiver) = channel();
+        let new_episode = EpisodeMessage::<Current>::NewEpisode { episode_id: 1, participants: vec![] };
+        let cmds = cmds.iter().map(|&cmd| EpisodeMessage::<Current>::UnsignedCommand { episode_id: 1, cmd });
+        for (i, msg) in std::iter::once(new_episode).chain(cmds).enumerate() {
+            let i = i as u64;
+            let associated_txs = vec![(i.into(), borsh::to_vec(&msg).unwrap(), vec![], vec![])];
+            sender
+                .send(EngineMsg::BlkAccepted { accepting_hash: (100 + i).into(), accepting_daa: i, accepting_time: 0, associated_txs })
+                .unwrap();
+        }
+        sender.send(EngineMsg::Exit).unwrap();
+        receiver
+    }
+
+    #[test]
+    fn test_shadow_divergence() {
+        let digest = |sum: &Current| borsh_digest(&sum.total);
+        let upgraded_digest = |sum: &Upgraded| borsh_digest(&sum.total);
+
+        // The implementations agree on commands which both accept
+        let report = run_shadow::<Current, Upgraded>(feed(&[1, 2, 3]), digest, upgraded_digest);
+        assert!(report.is_clean());
+        assert_eq!(report.events_compared, 4);
+
+        // The command carried by tx 3 is rejected by the upgraded implementation only, so from there on its events are
+        // compared against those of the following command, and its last event is missing
+        let report = run_shadow::<Current, Upgraded>(feed(&[1, 2, 9, 3]), digest, upgraded_digest);
+        assert_eq!(report.events_compared, 4);
+        let command = |tx_id: u64, total: u64| ShadowEvent {
+            kind: ShadowEventKind::Command { tx_id: tx_id.into() },
+            digest: borsh_digest(&total),
+        };
+        assert_eq!(
+            report.divergences,
+            vec![
+                Divergence { episode_id: 1, index: 3, current: Some(command(3, 12)), upgraded: Some(command(4, 6)) },
+                Divergence { episode_id: 1, index: 4, current: Some(command(4, 15)), upgraded: None },
+            ]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Persistent storage for episode snapshots, indexes and application data. Backends implement the [`Storage`] trait,
+//! a minimal ordered key-value store partitioned into named trees, and are shared via `Arc<dyn Storage>`. A
+//! [`Collection`] is a typed view over a single tree, and a [`SnapshotHandler`] persists the latest state of every
+//! episode run by an engine, e.g., for serving it to peers across restarts (the engine itself always rebuilds episode
+//! state from the chain).
+//!
+//! Two backends are provided: [`MemoryStorage`] for tests and ephemeral peers, and [`FileStorage`] which keeps a file per
+//! entry. Embedded databases (e.g., sled or RocksDB) can be plugged in by implementing [`Storage`].
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::episode::{Episode, EpisodeEventHandler, EpisodeId, PayloadMetadata};
+use crate::pki::PubKey;
+
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    /// Removes the entry, if any
+    fn remove(&self, tree: &str, key: &[u8]) -> io::Result<()>;
+
+    /// All entries of the tree, ordered by key
+    fn entries(&self, tree: &str) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: Mutex<HashMap<String, Tree>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.trees.lock().unwrap().get(tree).and_then(|entries| entries.get(key).cloned()))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.trees.lock().unwrap().entry(tree.to_string()).or_default().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> io::Result<()> {
+        if let Some(entries) = self.trees.lock().unwrap().get_mut(tree) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn entries(&self, tree: &str) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees.get(tree).map(|entries| entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect()).unwrap_or_default())
+    }
+}
+
+/// Stores each tree in a directory under the root, holding a file per entry named by its hex encoded key. Entries are
+/// written to a temporary file which then replaces the entry, so an entry is never observed partially written
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn tree_dir(&self, tree: &str) -> io::Result<PathBuf> {
+        // Tree names map to directories, so they are restricted to avoid escaping the root
+        if tree.is_empty() || !tree.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid tree name '{}'", tree)));
+        }
+        Ok(self.root.join(tree))
+    }
+
+    fn entry_name(key: &[u8]) -> String {
+        format!("{}.entry", faster_hex::hex_string(key))
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.tree_dir(tree)?.join(Self::entry_name(key))) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let dir = self.tree_dir(tree)?;
+        fs::create_dir_all(&dir)?;
+        let name = Self::entry_name(key);
+        let tmp = dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, value)?;
+        fs::rename(tmp, dir.join(name))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> io::Result<()> {
+        match fs::remove_file(self.tree_dir(tree)?.join(Self::entry_name(key))) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn entries(&self, tree: &str) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let dir = self.tree_dir(tree)?;
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut entries = vec![];
+        for dir_entry in read_dir {
+            let path = dir_entry?.path();
+            // Skips leftover temporary files of interrupted writes
+            let Some(name) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".entry")) else {
+                continue;
+            };
+            let mut key = vec![0u8; name.len() / 2];
+            if faster_hex::hex_decode(name.as_bytes(), &mut key).is_err() {
+                continue;
+            }
+            entries.push((key, fs::read(&path)?));
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+/// A typed view over a storage tree, with Borsh encoded keys and values. Note that entries are ordered by their encoded
+/// keys, which for integers is little-endian
+pub struct Collection<K, V> {
+    storage: Arc<dyn Storage>,
+    tree: String,
+    _phantom: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V> Clone for Collection<K, V> {
+    fn clone(&self) -> Self {
+        Self { storage: self.storage.clone(), tree: self.tree.clone(), _phantom: PhantomData }
+    }
+}
+
+impl<K: BorshSerialize + BorshDeserialize, V: BorshSerialize + BorshDeserialize> Collection<K, V> {
+    pub fn new(storage: Arc<dyn Storage>, tree: &str) -> Self {
+        Self { storage, tree: tree.to_string(), _phantom: PhantomData }
+    }
+
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        self.storage.get(&self.tree, &borsh::to_vec(key)?)?.map(|value| borsh::from_slice(&value)).transpose()
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> io::Result<()> {
+        self.storage.put(&self.tree, &borsh::to_vec(key)?, &borsh::to_vec(value)?)
+    }
+
+    pub fn remove(&self, key: &K) -> io::Result<()> {
+        self.storage.remove(&self.tree, &borsh::to_vec(key)?)
+    }
+
+    pub fn entries(&self) -> io::Result<Vec<(K, V)>> {
+        self.storage
+            .entries(&self.tree)?
+            .into_iter()
+            .map(|(key, value)| Ok((borsh::from_slice(&key)?, borsh::from_slice(&value)?)))
+            .collect()
+    }
+}
+
+/// The storage tree holding episode snapshots
+pub const SNAPSHOTS_TREE: &str = "episode_snapshots";
+
+/// An event handler persisting the state of an episode following every engine event which affects it
+pub struct SnapshotHandler<G> {
+    snapshots: Collection<EpisodeId, G>,
+}
+
+impl<G: Episode + BorshSerialize + BorshDeserialize> SnapshotHandler<G> {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { snapshots: Collection::new(storage, SNAPSHOTS_TREE) }
+    }
+
+    /// The snapshots persisted so far, which is the latest state of each episode
+    pub fn snapshots(&self) -> Collection<EpisodeId, G> {
+        self.snapshots.clone()
+    }
+
+    fn persist(&self, episode_id: EpisodeId, episode: &G) {
+        if let Err(err) = self.snapshots.put(&episode_id, episode) {
+            warn!("Episode {}: failed persisting snapshot: {}", episode_id, err);
+        }
+    }
+}
+
+impl<G: Episode + BorshSerialize + BorshDeserialize> EpisodeEventHandler<G> for SnapshotHandler<G> {
+    fn on_initialize(&self, episode_id: EpisodeId, episode: &G) {
+        self.persist(episode_id, episode);
+    }
+
+    fn on_command(
+        &self,
+        episode_id: EpisodeId,
+        episode: &G,
+        _cmd: &<G as Episode>::Command,
+        _authorization: Option<PubKey>,
+        _metadata: &PayloadMetadata,
+    ) {
+        self.persist(episode_id, episode);
+    }
+
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &G) {
+        self.persist(episode_id, episode);
+    }
+
+    fn on_key_rotation(&self, episode_id: EpisodeId, episode: &G, _old: PubKey, _new: PubKey) {
+        self.persist(episode_id, episode);
+    }
+
+    fn on_daa_tick(&self, episode_id: EpisodeId, episode: &G, _daa: u64) {
+        self.persist(episode_id, episode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_storage(storage: Arc<dyn Storage>) {
+        let collection = Collection::<u8, String>::new(storage.clone(), "names");
+        assert_eq!(collection.get(&1).unwrap(), None);
+        collection.put(&2, &"b".to_string()).unwrap();
+        collection.put(&1, &"a".to_string()).unwrap();
+        collection.put(&2, &"c".to_string()).unwrap();
+        assert_eq!(collection.get(&2).unwrap(), Some("c".to_string()));
+        assert_eq!(collection.entries().unwrap(), vec![(1, "a".to_string()), (2, "c".to_string())]);
+
+        collection.remove(&1).unwrap();
+        collection.remove(&3).unwrap();
+        assert_eq!(collection.entries().unwrap(), vec![(2, "c".to_string())]);
+        // Trees are independent
+        assert!(storage.entries("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_storage_backends() {
+        check_storage(Arc::new(MemoryStorage::new()));
+
+        let root = std::env::temp_dir().join(format!("kdapp-storage-{}", rand::random::<u64>()));
+        let storage = FileStorage::open(&root).unwrap();
+        assert!(storage.put("../escape", &[1], &[1]).is_err());
+        check_storage(Arc::new(storage));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! A simulation harness for testing episodes against DAG reorgs. A [`Simulation`] drives an engine with virtual
+//! blocks carrying submitted episode messages, and can revert or reorg the most recent blocks, like the proxy listener
+//! does when the virtual chain changes, or finalize them. [`assert_reorg_consistency`] checks an episode implementation
+//! by injecting reorgs of every depth up to a bound at every point of a message sequence, and asserting that the states
+//! following each revert and each reapply match those of a straight-line execution.
+
+use kaspa_consensus_core::Hash;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Sender};
+
+use crate::engine::{DefaultEventHandler, Engine, EngineMsg, EpisodeMessage};
+use crate::episode::{Episode, EpisodeEventHandler, EpisodeId};
+use crate::shadow::DigestFn;
+
+/// The DAA score advanced by each simulated block
+pub const SIM_BLOCK_DAA: u64 = 10;
+
+/// A tx carrying an episode message, along with the ids of the txs it spends
+#[derive(Clone)]
+struct SimTx {
+    id: Hash,
+    payload: Vec<u8>,
+    spent_tx_ids: Vec<Hash>,
+}
+
+struct SimBlock {
+    hash: Hash,
+    daa: u64,
+    txs: Vec<SimTx>,
+}
+
+pub struct Simulation<G: Episode, H: EpisodeEventHandler<G> + Clone = DefaultEventHandler> {
+    engine: Engine<G, H>,
+    handlers: Vec<H>,
+    sender: Sender<EngineMsg>,
+    /// The blocks of the virtual chain, in acceptance order
+    chain: Vec<SimBlock>,
+    /// The number of blocks at the start of the chain which were finalized
+    finalized: usize,
+    pending: Vec<SimTx>,
+    next_id: u64,
+}
+
+impl<G: Episode> Default for Simulation<G> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<G: Episode> Simulation<G> {
+    pub fn new() -> Self {
+        Self::with_handlers(vec![])
+    }
+}
+
+impl<G: Episode, H: EpisodeEventHandler<G> + Clone> Simulation<G, H> {
+    /// Creates a simulation whose engine reports to `handlers`
+    pub fn with_handlers(handlers: Vec<H>) -> Self {
+        let (sender, receiver) = channel();
+        Self { engine: Engine::new(receiver), handlers, sender, chain: vec![], finalized: 0, pending: vec![], next_id: 1 }
+    }
+
+    /// Queues the message for the next block and returns the id of the tx carrying it
+    pub fn submit(&mut self, msg: &EpisodeMessage<G>) -> Hash {
+        self.submit_spending(msg, vec![])
+    }
+
+    /// Same as [`Self::submit`], but the tx spends outputs of the txs `spent_tx_ids`, e.g., the tx of the previous chunk
+    /// of a chunked message (see [`EpisodeMessage::into_chunks`])
+    pub fn submit_spending(&mut self, msg: &EpisodeMessage<G>, spent_tx_ids: Vec<Hash>) -> Hash {
+        let id = self.next_hash();
+        self.pending.push(SimTx { id, payload: borsh::to_vec(msg).unwrap(), spent_tx_ids });
+        id
+    }
+
+    /// Accepts a block carrying all queued messages and returns its hash
+    pub fn advance(&mut self) -> Hash {
+        let txs = std::mem::take(&mut self.pending);
+        let daa = self.chain.last().map_or(0, |block| block.daa) + SIM_BLOCK_DAA;
+        self.accept(daa, txs)
+    }
+
+    /// Finalizes all blocks of the chain which were not finalized yet
+    pub fn finalize(&mut self) {
+        let finalized = self.chain[self.finalized..].iter().map(|block| EngineMsg::BlkFinalized { accepting_hash: block.hash });
+        let msgs: Vec<_> = finalized.collect();
+        self.finalized = self.chain.len();
+        self.run(msgs);
+    }
+
+    /// Reverts the last `depth` blocks. Their messages are dropped
+    pub fn revert(&mut self, depth: usize) {
+        self.revert_blocks(depth);
+    }
+
+    /// Reverts the last `depth` blocks and accepts their txs again in new blocks (at the same DAA scores), as when the
+    /// reverted txs are accepted by the new virtual chain
+    pub fn reorg(&mut self, depth: usize) {
+        let reverted = self.revert_blocks(depth);
+        self.reapply(reverted);
+    }
+
+    pub fn episode(&self, episode_id: EpisodeId) -> Option<&G> {
+        self.engine.episodes.get(&episode_id).map(|wrapper| &wrapper.episode)
+    }
+
+    /// The digests of all live episodes
+    pub fn digests(&self, digest: DigestFn<G>) -> BTreeMap<EpisodeId, Hash> {
+        self.engine.episodes.iter().map(|(&episode_id, wrapper)| (episode_id, digest(&wrapper.episode))).collect()
+    }
+
+    fn revert_blocks(&mut self, depth: usize) -> Vec<SimBlock> {
+        let split = self.chain.len().checked_sub(depth).expect("reverting beyond the first block");
+        let reverted = self.chain.split_off(split);
+        // Finalized blocks are never reverted by the listener, and the engine ignores such reverts
+        self.finalized = self.finalized.min(self.chain.len());
+        self.run(reverted.iter().rev().map(|block| EngineMsg::BlkReverted { accepting_hash: block.hash }));
+        reverted
+    }
+
+    fn reapply(&mut self, blocks: Vec<SimBlock>) {
+        for block in blocks {
+            self.accept(block.daa, block.txs);
+        }
+    }
+
+    fn accept(&mut self, daa: u64, txs: Vec<SimTx>) -> Hash {
+        let hash = self.next_hash();
+        let associated_txs = txs.iter().cloned().map(|tx| (tx.id, tx.payload, vec![], tx.spent_tx_ids)).collect();
+        self.run([EngineMsg::BlkAccepted { accepting_hash: hash, accepting_daa: daa, accepting_time: daa, associated_txs }]);
+        self.chain.push(SimBlock { hash, daa, txs });
+        hash
+    }
+
+    fn next_hash(&mut self) -> Hash {
+        self.next_id += 1;
+        self.next_id.into()
+    }
+
+    /// Runs the engine over the messages. The engine returns once it reaches the trailing exit message
+    fn run(&mut self, msgs: impl IntoIterator<Item = EngineMsg>) {
+        for msg in msgs.into_iter().chain([EngineMsg::Exit]) {
+            self.sender.send(msg).unwrap();
+        }
+        self.engine.start(self.handlers.clone());
+    }
+}
+
+/// Executes `blocks` (each a list of messages accepted together) straight-line, and then once per height and reorg
+/// depth (up to `max_depth`), reverting that many blocks at that height before reapplying them. Panics if the states,
+/// compared through `digest`, differ from the straight-line execution after a revert, after the reapply, or at the end.
+pub fn assert_reorg_consistency<G: Episode>(blocks: &[Vec<EpisodeMessage<G>>], max_depth: usize, digest: DigestFn<G>) {
+    let mut straight = Simulation::<G>::new();
+    // The digests following each height, starting from the empty state
+    let mut expected = vec![straight.digests(digest)];
+    for block in blocks {
+        for msg in block {
+            straight.submit(msg);
+        }
+        straight.advance();
+        expected.push(straight.digests(digest));
+    }
+
+    for height in 1..=blocks.len() {
+        for depth in 1..=max_depth.min(height) {
+            let mut sim = Simulation::<G>::new();
+            for block in &blocks[..height] {
+                for msg in block {
+                    sim.submit(msg);
+                }
+                sim.advance();
+            }
+            let context = format!("reorg of depth {} at height {}", depth, height);
+            let reverted = sim.revert_blocks(depth);
+            assert_eq!(sim.digests(digest), expected[height - depth], "state diverged after reverting the {}", context);
+            sim.reapply(reverted);
+            assert_eq!(sim.digests(digest), expected[height], "state diverged after reapplying the {}", context);
+            for block in &blocks[height..] {
+                for msg in block {
+                    sim.submit(msg);
+                }
+                sim.advance();
+            }
+            assert_eq!(sim.digests(digest), expected[blocks.len()], "final state diverged following the {}", context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::episode::{EpisodeError, PayloadMetadata};
+    use crate::pki::PubKey;
+    use crate::shadow::borsh_digest;
+
+    /// Sums unsigned commands. Its rollback is broken unless `restore` is set
+    #[derive(Debug)]
+    struct Sum {
+        total: u64,
+        restore: bool,
+    }
+
+    impl Episode for Sum {
+        type Command = u64;
+        type CommandRollback = u64;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { total: 0, restore: participants.is_empty() }
+        }
+
+        fn execute(
+            &mut self,
+            cmd: &u64,
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<u64, EpisodeError<std::fmt::Error>> {
+            self.total += cmd;
+            Ok(*cmd)
+        }
+
+        fn rollback(&mut self, cmd: u64) -> bool {
+            if self.restore {
+                self.total -= cmd;
+            }
+            true
+        }
+    }
+
+    fn blocks(participants: Vec<PubKey>) -> Vec<Vec<EpisodeMessage<Sum>>> {
+        let cmd = |cmd| EpisodeMessage::UnsignedCommand { episode_id: 1, cmd };
+        vec![vec![EpisodeMessage::NewEpisode { episode_id: 1, participants }], vec![cmd(1), cmd(2)], vec![], vec![cmd(3)]]
+    }
+
+    #[test]
+    fn test_reorg_consistency() {
+        assert_reorg_consistency(&blocks(vec![]), 3, |sum| borsh_digest(&sum.total));
+
+        let mut sim = Simulation::new();
+        blocks(vec![]).iter().for_each(|block| {
+            for msg in block {
+                sim.submit(msg);
+            }
+            sim.advance();
+        });
+        sim.revert(1);
+        assert_eq!(sim.episode(1).unwrap().total, 3);
+        sim.revert(3);
+        assert!(sim.episode(1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "state diverged after reverting the reorg of depth 1 at height 2")]
+    fn test_reorg_consistency_broken_rollback() {
+        let (_, pk) = crate::pki::generate_keypair();
+        assert_reorg_consistency(&blocks(vec![pk]), 3, |sum| borsh_digest(&sum.total));
+    }
+}
@@ -0,0 +1,173 @@
+//! Tracks the latest state of episodes as reported by the engine, allowing participants to await conditions over
+//! episode state (e.g., "it's my turn") without polling. The tracker is an [`EpisodeEventHandler`] which is passed
+//! to the engine, while clones of it are used by the participant flows for awaiting. A tracker holds either the full
+//! episode state, or its [projection](EpisodeProjection) when created with [`EpisodeTracker::projected`].
+
+use crate::episode::{Episode, EpisodeEventHandler, EpisodeId, EpisodeProjection, PayloadMetadata};
+use crate::pki::PubKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Tracks views of type `S` of the episodes, which is the episode state itself by default
+pub struct EpisodeTracker<G: Episode, S: Clone = G> {
+    episodes: Arc<Mutex<HashMap<EpisodeId, watch::Sender<Option<S>>>>>,
+    view: fn(&G) -> S,
+}
+
+impl<G: Episode, S: Clone> Clone for EpisodeTracker<G, S> {
+    fn clone(&self) -> Self {
+        Self { episodes: self.episodes.clone(), view: self.view }
+    }
+}
+
+impl<G: Episode + Clone> Default for EpisodeTracker<G> {
+    fn default() -> Self {
+        Self { episodes: Default::default(), view: G::clone }
+    }
+}
+
+impl<G: Episode + Clone> EpisodeTracker<G> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<G: EpisodeProjection> EpisodeTracker<G, G::Public> {
+    /// Creates a tracker of the episode projections, which can be exposed without leaking private episode state
+    pub fn projected() -> Self {
+        Self { episodes: Default::default(), view: G::project }
+    }
+}
+
+impl<G: Episode, S: Clone> EpisodeTracker<G, S> {
+    /// Returns the latest known state of the episode
+    pub fn current(&self, episode_id: EpisodeId) -> Option<S> {
+        self.episodes.lock().unwrap().get(&episode_id).and_then(|sender| sender.borrow().clone())
+    }
+
+    /// Subscribes to state changes of the episode. The episode does not need to exist yet, in which case the
+    /// receiver holds `None` until it is initialized
+    pub fn subscribe(&self, episode_id: EpisodeId) -> watch::Receiver<Option<S>> {
+        self.episodes.lock().unwrap().entry(episode_id).or_insert_with(|| watch::Sender::new(None)).subscribe()
+    }
+
+    /// Resolves with the first `Some` value returned by `f` over the episode state, evaluating it on the current
+    /// state and on every following change
+    pub async fn await_episode_field<T>(&self, episode_id: EpisodeId, mut f: impl FnMut(&S) -> Option<T>) -> T {
+        let mut receiver = self.subscribe(episode_id);
+        let mut field = None;
+        // The tracker holds the sender, so the receiver cannot observe a closed channel
+        receiver
+            .wait_for(|state| {
+                field = state.as_ref().and_then(&mut f);
+                field.is_some()
+            })
+            .await
+            .expect("tracker holds the sender");
+        field.unwrap()
+    }
+
+    /// Resolves with the episode state once `predicate` holds over it
+    pub async fn await_episode(&self, episode_id: EpisodeId, mut predicate: impl FnMut(&S) -> bool) -> S {
+        self.await_episode_field(episode_id, |state| predicate(state).then(|| state.clone())).await
+    }
+
+    fn publish(&self, episode_id: EpisodeId, episode: &G) {
+        let mut episodes = self.episodes.lock().unwrap();
+        let sender = episodes.entry(episode_id).or_insert_with(|| watch::Sender::new(None));
+        sender.send_replace(Some((self.view)(episode)));
+    }
+}
+
+impl<G: Episode, S: Clone> EpisodeEventHandler<G> for EpisodeTracker<G, S> {
+    fn on_initialize(&self, episode_id: EpisodeId, episode: &G) {
+        self.publish(episode_id, episode);
+    }
+
+    fn on_command(
+        &self,
+        episode_id: EpisodeId,
+        episode: &G,
+        _cmd: &<G as Episode>::Command,
+        _authorization: Option<PubKey>,
+        _metadata: &PayloadMetadata,
+    ) {
+        self.publish(episode_id, episode);
+    }
+
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &G) {
+        self.publish(episode_id, episode);
+    }
+
+    fn on_key_rotation(&self, episode_id: EpisodeId, episode: &G, _old: PubKey, _new: PubKey) {
+        self.publish(episode_id, episode);
+    }
+
+    fn on_daa_tick(&self, episode_id: EpisodeId, episode: &G, _daa: u64) {
+        self.publish(episode_id, episode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EpisodeMessage;
+    use crate::episode::EpisodeError;
+    use crate::testing::Simulation;
+
+    /// Sums unsigned commands
+    #[derive(Clone, Debug)]
+    struct Sum {
+        total: u64,
+    }
+
+    impl Episode for Sum {
+        type Command = u64;
+        type CommandRollback = u64;
+        type CommandError = std::fmt::Error;
+
+        fn initialize(_participants: Vec<PubKey>, _metadata: &PayloadMetadata) -> Self {
+            Self { total: 0 }
+        }
+
+        fn execute(
+            &mut self,
+            cmd: &u64,
+            _auth: Option<PubKey>,
+            _metadata: &PayloadMetadata,
+        ) -> Result<u64, EpisodeError<std::fmt::Error>> {
+            self.total += cmd;
+            Ok(*cmd)
+        }
+
+        fn rollback(&mut self, cmd: u64) -> bool {
+            self.total -= cmd;
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker() {
+        let tracker = EpisodeTracker::<Sum>::new();
+        let mut sim = Simulation::with_handlers(vec![tracker.clone()]);
+
+        // Await the command before the episode even exists
+        let waiter = tokio::spawn({
+            let tracker = tracker.clone();
+            async move { tracker.await_episode_field(1, |sum| (sum.total > 0).then_some(sum.total)).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(tracker.current(1).is_none());
+
+        sim.submit(&EpisodeMessage::NewEpisode { episode_id: 1, participants: vec![] });
+        sim.advance();
+        assert_eq!(tracker.current(1).unwrap().total, 0);
+        sim.submit(&EpisodeMessage::UnsignedCommand { episode_id: 1, cmd: 5 });
+        sim.advance();
+        assert_eq!(waiter.await.unwrap(), 5);
+
+        sim.revert(1);
+        assert_eq!(tracker.await_episode(1, |sum| sum.total == 0).await.total, 0);
+    }
+}
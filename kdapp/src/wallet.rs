@@ -0,0 +1,233 @@
+//! Mnemonic (BIP-39) backed wallets with hierarchical key derivation (BIP-32, via `kaspa_bip32`). A single phrase backs
+//! up both the Kaspa funding keys, which are derived over the standard Kaspa path (so the same funds are visible in
+//! regular Kaspa wallets), and the episode identity keys, which are derived over a separate hardened branch.
+//!
+//! Peers which do not use mnemonics can keep a keyfile per role (e.g., `organizer` or `participant`) in a
+//! [`WalletDir`].
+
+use bip39::Mnemonic;
+use kaspa_addresses::{Address, Prefix, Version};
+use kaspa_bip32::{ChildNumber, ExtendedPrivateKey};
+#[cfg(feature = "rpc")]
+use kaspa_rpc_core::{api::rpc::RpcApi, RpcResult};
+use secp256k1::{Keypair, SecretKey, SECP256K1};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WalletError {
+    #[error("failed accessing {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("keyfile {0} does not contain a valid hex private key")]
+    InvalidKeyfile(PathBuf),
+
+    #[error("invalid wallet role '{0}'")]
+    InvalidRole(String),
+
+    #[error("invalid mnemonic: {0}")]
+    InvalidMnemonic(String),
+
+    #[error("invalid derivation path '{0}'")]
+    InvalidDerivationPath(String),
+
+    #[error("key derivation produced an invalid key")]
+    InvalidDerivation,
+}
+
+/// The registered BIP-44 coin type of Kaspa
+pub const KASPA_COIN_TYPE: u32 = 111111;
+
+/// The branch under the account path used for episode identity keys. Kaspa wallets use branches 0 and 1 for receive
+/// and change addresses respectively
+pub const IDENTITY_BRANCH: u32 = 2;
+
+const HARDENED: u32 = 0x8000_0000;
+
+/// Parses a derivation path such as `m/44'/111111'/0'/0/0` into child indexes (hardened indexes may be marked with `'` or `h`)
+pub fn parse_derivation_path(path: &str) -> Result<Vec<u32>, WalletError> {
+    let invalid = || WalletError::InvalidDerivationPath(path.to_owned());
+    let mut parts = path.trim().split('/');
+    if parts.next() != Some("m") {
+        return Err(invalid());
+    }
+    parts
+        .map(|part| {
+            let (index, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(index) => (index, true),
+                None => (part, false),
+            };
+            let index: u32 = index.parse().ok().filter(|index| index & HARDENED == 0).ok_or_else(invalid)?;
+            Ok(if hardened { index | HARDENED } else { index })
+        })
+        .collect()
+}
+
+/// Generates a new random mnemonic of `word_count` words (12, 15, 18, 21 or 24)
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic, WalletError> {
+    Mnemonic::generate(word_count).map_err(|err| WalletError::InvalidMnemonic(err.to_string()))
+}
+
+/// A wallet deriving all of its keys from the seed of a single mnemonic
+pub struct HdWallet {
+    master: ExtendedPrivateKey<SecretKey>,
+}
+
+impl HdWallet {
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str) -> Result<Self, WalletError> {
+        Self::from_seed(&mnemonic.to_seed(passphrase))
+    }
+
+    fn from_seed(seed: &[u8]) -> Result<Self, WalletError> {
+        Ok(Self { master: ExtendedPrivateKey::new(seed).map_err(|_| WalletError::InvalidDerivation)? })
+    }
+
+    /// Restores the wallet from a mnemonic phrase, optionally protected by a BIP-39 passphrase
+    pub fn from_phrase(phrase: &str, passphrase: &str) -> Result<Self, WalletError> {
+        let mnemonic = Mnemonic::parse(phrase.trim()).map_err(|err| WalletError::InvalidMnemonic(err.to_string()))?;
+        Self::from_mnemonic(&mnemonic, passphrase)
+    }
+
+    /// Derives the keypair at the given derivation path
+    pub fn derive(&self, path: &str) -> Result<Keypair, WalletError> {
+        let key = parse_derivation_path(path)?.into_iter().try_fold(self.master.clone(), |key, index| {
+            let child = ChildNumber::new(index & !HARDENED, index & HARDENED != 0).map_err(|_| WalletError::InvalidDerivation)?;
+            // A derived key is invalid with negligible probability, in which case BIP-32 proceeds to the next index
+            key.derive_child(child).map_err(|_| WalletError::InvalidDerivation)
+        })?;
+        Ok(Keypair::from_secret_key(SECP256K1, key.private_key()))
+    }
+
+    /// The Kaspa funding keypair at `m/44'/111111'/<account>'/0/<index>`
+    pub fn funding_keypair(&self, account: u32, index: u32) -> Result<Keypair, WalletError> {
+        self.derive(&format!("m/44'/{}'/{}'/0/{}", KASPA_COIN_TYPE, account, index))
+    }
+
+    /// The episode identity keypair at `m/44'/111111'/<account>'/2'/<index>'`. The branch is hardened so that
+    /// identity keys cannot be related to the funding keys of the account
+    pub fn identity_keypair(&self, account: u32, index: u32) -> Result<Keypair, WalletError> {
+        self.derive(&format!("m/44'/{}'/{}'/{}'/{}'", KASPA_COIN_TYPE, account, IDENTITY_BRANCH, index))
+    }
+}
+
+/// Parses a hex encoded private key into a keypair
+pub fn parse_private_key(private_key_hex: &str) -> Option<Keypair> {
+    let mut private_key_bytes = [0u8; 32];
+    faster_hex::hex_decode(private_key_hex.as_bytes(), &mut private_key_bytes).ok()?;
+    Keypair::from_seckey_slice(SECP256K1, &private_key_bytes).ok()
+}
+
+/// Reads a hex encoded private key from the file at `path`
+pub fn read_keyfile(path: &Path) -> Result<Keypair, WalletError> {
+    let contents = fs::read_to_string(path).map_err(|err| WalletError::Io(path.to_owned(), err))?;
+    parse_private_key(contents.trim()).ok_or_else(|| WalletError::InvalidKeyfile(path.to_owned()))
+}
+
+/// The Kaspa (Schnorr pay-to-pubkey) address of the keypair
+pub fn keypair_address(keypair: &Keypair, prefix: Prefix) -> Address {
+    Address::new(prefix, Version::PubKey, &keypair.x_only_public_key().0.serialize())
+}
+
+/// The balance of `address` in sompi. Requires the node to run with the UTXO index
+#[cfg(feature = "rpc")]
+pub async fn address_balance(kaspad: &impl RpcApi, address: &Address) -> RpcResult<u64> {
+    kaspad.get_balance_by_address(address.clone()).await
+}
+
+/// A directory holding a keyfile per role, named `<role>.key`. Keyfiles hold a hex encoded private key, and are
+/// interchangeable with the keyfiles passed via `--keyfile`
+pub struct WalletDir {
+    dir: PathBuf,
+}
+
+impl WalletDir {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn keyfile_path(&self, role: &str) -> Result<PathBuf, WalletError> {
+        // Roles map to file names, so they are restricted to avoid escaping the directory
+        if role.is_empty() || !role.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(WalletError::InvalidRole(role.to_owned()));
+        }
+        Ok(self.dir.join(format!("{}.key", role)))
+    }
+
+    /// Loads the keypair of `role`, if its keyfile exists
+    pub fn load(&self, role: &str) -> Result<Option<Keypair>, WalletError> {
+        let path = self.keyfile_path(role)?;
+        match read_keyfile(&path) {
+            Err(WalletError::Io(_, err)) if err.kind() == ErrorKind::NotFound => Ok(None),
+            res => res.map(Some),
+        }
+    }
+
+    /// Loads the keypair of `role`, or generates and stores a new one if it has no keyfile yet. Returns whether the
+    /// keypair was created
+    pub fn load_or_create(&self, role: &str) -> Result<(Keypair, bool), WalletError> {
+        if let Some(keypair) = self.load(role)? {
+            return Ok((keypair, false));
+        }
+        let path = self.keyfile_path(role)?;
+        let io_error = |err| WalletError::Io(path.clone(), err);
+        fs::create_dir_all(&self.dir).map_err(io_error)?;
+        let keypair = Keypair::new(SECP256K1, &mut rand::thread_rng());
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Keyfiles are only readable by their owner
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path).map_err(io_error)?;
+        file.write_all(keypair.display_secret().to_string().as_bytes()).map_err(io_error)?;
+        Ok((keypair, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hd_wallet() {
+        // BIP-32 test vector 1
+        let wallet = HdWallet::from_seed(&(0u8..16).collect::<Vec<_>>()).unwrap();
+        let key = wallet.derive("m").unwrap();
+        assert_eq!(key.display_secret().to_string(), "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35");
+        let key = wallet.derive("m/0'").unwrap();
+        assert_eq!(key.display_secret().to_string(), "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea");
+        let key = wallet.derive("m/0h/1").unwrap();
+        assert_eq!(key.display_secret().to_string(), "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368");
+
+        assert!(parse_derivation_path("44'/0").is_err());
+        assert!(parse_derivation_path("m/2147483648").is_err());
+
+        // Both roles are restored from the phrase and do not collide
+        let mnemonic = generate_mnemonic(24).unwrap();
+        let wallet = HdWallet::from_mnemonic(&mnemonic, "").unwrap();
+        let restored = HdWallet::from_phrase(&mnemonic.to_string(), "").unwrap();
+        assert_eq!(wallet.funding_keypair(0, 0).unwrap(), restored.funding_keypair(0, 0).unwrap());
+        assert_eq!(wallet.identity_keypair(0, 0).unwrap(), restored.identity_keypair(0, 0).unwrap());
+        assert_ne!(wallet.funding_keypair(0, 0).unwrap(), wallet.identity_keypair(0, 0).unwrap());
+        assert_ne!(
+            wallet.funding_keypair(0, 0).unwrap(),
+            HdWallet::from_mnemonic(&mnemonic, "other").unwrap().funding_keypair(0, 0).unwrap()
+        );
+        assert!(HdWallet::from_phrase("not a valid phrase", "").is_err());
+    }
+
+    #[test]
+    fn test_wallet_dir() {
+        let dir = std::env::temp_dir().join(format!("kdapp-wallet-{}", rand::random::<u64>()));
+        let wallets = WalletDir::new(&dir);
+        assert!(wallets.load("organizer").unwrap().is_none());
+        let (organizer, created) = wallets.load_or_create("organizer").unwrap();
+        assert!(created);
+        assert_eq!(wallets.load_or_create("organizer").unwrap(), (organizer, false));
+        assert_eq!(read_keyfile(&dir.join("organizer.key")).unwrap(), organizer);
+        assert_ne!(wallets.load_or_create("participant").unwrap().0, organizer);
+        assert!(wallets.keyfile_path("../organizer").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}